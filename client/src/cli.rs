@@ -0,0 +1,129 @@
+//! Command line flags and subcommands.
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::protocol::Padding;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "An end-to-end encrypted chat client")]
+pub struct Cli {
+    /// Profile under $XDG_CONFIG_HOME/chat to take settings and keys from
+    #[arg(short, long, global = true, default_value = "default")]
+    pub profile: String,
+
+    /// Log line format: full, compact, pretty or json [env: LOG_FORMAT]
+    #[arg(long, global = true, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Write logs to daily files in this directory instead of the
+    /// terminal [env: LOG_DIR]
+    #[arg(long, global = true, value_name = "DIR")]
+    pub log_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// How log lines are laid out.
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+/// What history is exported to.
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Every message with its id, to import again
+    #[default]
+    Json,
+    /// One line per message
+    Text,
+    /// A web page
+    Html,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Chat interactively (the default)
+    Connect(Connect),
+    /// Send one message and exit, for scripts
+    Send {
+        #[command(flatten)]
+        connect: Connect,
+        /// Seconds to wait for a peer to exchange keys with
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+        /// The message; read from stdin when omitted
+        message: Option<String>,
+    },
+    /// Generate the profile's identity key
+    Keygen,
+    /// Print the fingerprint of the profile's identity key
+    Fingerprint,
+    /// Write the history of a room to a file
+    ExportHistory {
+        /// The room; the profile's, or lobby, when omitted
+        #[arg(long)]
+        room: Option<String>,
+        #[arg(long, value_enum, default_value_t)]
+        format: ExportFormat,
+        /// Encrypt it with a passphrase [env: ARCHIVE_PASSPHRASE]
+        #[arg(long)]
+        encrypt: bool,
+        /// Where to write it; stdout when omitted
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Add the messages of a JSON export, encrypted or not, to the history
+    /// of its room. Messages already there are skipped
+    ImportHistory {
+        /// The export
+        file: PathBuf,
+    },
+}
+
+/// Where to connect and as whom. Flags override the environment, which
+/// overrides the profile.
+#[derive(Args, Debug, Default)]
+pub struct Connect {
+    /// Address of the server [env: ADDRESS]
+    #[arg(short, long, value_name = "ADDR")]
+    pub server: Option<SocketAddr>,
+    /// Username to chat as; asked for when not set anywhere
+    #[arg(short, long)]
+    pub user: Option<String>,
+    /// Room to join [default: lobby]
+    #[arg(short, long)]
+    pub room: Option<String>,
+    /// Use TLS and trust the public web PKI [env: TLS]
+    #[arg(long)]
+    pub tls: bool,
+    /// Use TLS and trust the CA(s) in this PEM file [env: TLS_CA]
+    #[arg(long, value_name = "FILE")]
+    pub tls_ca: Option<PathBuf>,
+    /// Use TLS and trust only the certificate with this SHA-256 fingerprint [env: TLS_PIN]
+    #[arg(long, value_name = "SHA256")]
+    pub tls_pin: Option<String>,
+    /// Name to check the server's certificate against [env: TLS_SERVER_NAME]
+    #[arg(long, value_name = "NAME")]
+    pub tls_server_name: Option<String>,
+    /// Pad messages to hide their length: none, power-of-two or
+    /// block:<bytes> [env: PADDING] [default: power-of-two]
+    #[arg(long, value_name = "POLICY")]
+    pub padding: Option<Padding>,
+    /// Neither show nor keep history, for this run
+    #[arg(long)]
+    pub no_history: bool,
+    /// Do not tell the peer when you are typing
+    #[arg(long)]
+    pub no_typing: bool,
+    /// Do not tell the peer which of its messages you got and read
+    #[arg(long)]
+    pub no_receipts: bool,
+}
@@ -0,0 +1,189 @@
+//! History exported from a profile, to keep or to import on another
+//! device.
+//!
+//! JSON exports hold every message as sent, ids included, and are the
+//! ones that import again; plain text and HTML are for reading. An export
+//! can be sealed with a passphrase, in which case it starts with `MAGIC`.
+
+use std::{collections::HashSet, fmt::Write};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::ExportFormat;
+use crate::message::Message;
+use crate::secret::Plaintext;
+
+/// What a sealed export starts with.
+pub const MAGIC: &[u8] = b"chat-archive\0";
+/// The version of the JSON export's layout.
+const VERSION: u32 = 1;
+
+/// The history of one room as exported to JSON.
+#[derive(Serialize, Deserialize)]
+pub struct Archive {
+    version: u32,
+    pub room: String,
+    exported: DateTime<Utc>,
+    pub messages: Vec<Message>,
+}
+
+impl Archive {
+    pub fn new(room: &str, messages: Vec<Message>) -> Archive {
+        Archive {
+            version: VERSION,
+            room: room.to_owned(),
+            exported: Utc::now(),
+            messages,
+        }
+    }
+
+    /// Read a JSON export.
+    pub fn parse(json: &Plaintext) -> Result<Archive, String> {
+        let archive: Archive = serde_json::from_slice(json.expose())
+            .map_err(|err| format!("not a JSON export: {}", err))?;
+        if archive.version != VERSION {
+            return Err(format!("unknown export version {}", archive.version));
+        }
+        Ok(archive)
+    }
+
+    /// The export in `format`.
+    pub fn export(&self, format: ExportFormat) -> Plaintext {
+        match format {
+            ExportFormat::Json => {
+                let mut json = serde_json::to_string_pretty(self).expect("messages serialize");
+                json.push('\n');
+                Plaintext::from(json)
+            }
+            ExportFormat::Text => {
+                let mut text = String::new();
+                for message in &self.messages {
+                    let _ = writeln!(text, "[{}] {}", utc(message), message.describe());
+                }
+                Plaintext::from(text)
+            }
+            ExportFormat::Html => Plaintext::from(self.html()),
+        }
+    }
+
+    /// The messages of the archive that `known` does not hold yet, in order.
+    /// Ids are kept on export, so a message imported twice is only kept
+    /// once.
+    pub fn new_messages(self, known: &[Message]) -> Vec<Message> {
+        let mut seen: HashSet<_> = known.iter().map(Message::id).collect();
+        self.messages
+            .into_iter()
+            .filter(|message| seen.insert(message.id()))
+            .collect()
+    }
+
+    fn html(&self) -> String {
+        let room = escape(&self.room);
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>#{room}</title>\n</head>\n<body>\n<h1>#{room}</h1>\n<ol>\n"
+        );
+        for message in &self.messages {
+            let _ = writeln!(
+                html,
+                "<li id=\"{}\"><time datetime=\"{}\">{}</time> {}</li>",
+                message.id(),
+                message.timestamp().to_rfc3339(),
+                utc(message),
+                escape(&message.describe())
+            );
+        }
+        html.push_str("</ol>\n</body>\n</html>\n");
+        html
+    }
+}
+
+/// When `message` was sent, to the second, in UTC.
+fn utc(message: &Message) -> String {
+    message
+        .timestamp()
+        .format("%Y-%m-%d %H:%M:%S UTC")
+        .to_string()
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Kind, MessageId};
+    use chrono::TimeZone;
+
+    fn messages() -> Vec<Message> {
+        let time = Utc.with_ymd_and_hms(2024, 3, 1, 12, 30, 5).unwrap();
+        vec![
+            Message::from_parts(
+                "01ARZ3NDEKTSV4RRFFQ69G5FAV".parse().unwrap(),
+                "alice",
+                time,
+                Kind::Text("<b>hi</b> & bye".into()),
+                None,
+            ),
+            Message::from_parts(
+                MessageId::new(),
+                "bob",
+                time,
+                Kind::Action("waves".into()),
+                None,
+            ),
+        ]
+    }
+
+    #[test]
+    fn exports() {
+        let archive = Archive::new("lobby", messages());
+
+        let text = archive.export(ExportFormat::Text);
+        let text = std::str::from_utf8(text.expose()).unwrap();
+        assert_eq!(
+            text.lines().next().unwrap(),
+            "[2024-03-01 12:30:05 UTC] alice: <b>hi</b> & bye"
+        );
+        assert_eq!(text.lines().count(), 2);
+
+        let html = archive.export(ExportFormat::Html);
+        let html = std::str::from_utf8(html.expose()).unwrap();
+        assert!(html.contains(
+            "<li id=\"01ARZ3NDEKTSV4RRFFQ69G5FAV\">\
+             <time datetime=\"2024-03-01T12:30:05+00:00\">2024-03-01 12:30:05 UTC</time> \
+             alice: &lt;b&gt;hi&lt;/b&gt; &amp; bye</li>"
+        ));
+        assert!(html.contains("<title>#lobby</title>"));
+    }
+
+    #[test]
+    fn imports_merging_duplicates() {
+        let messages = messages();
+        let json = Archive::new("lobby", messages.clone()).export(ExportFormat::Json);
+        let archive = Archive::parse(&json).unwrap();
+        assert_eq!(archive.room, "lobby");
+        assert!(archive.messages == messages);
+
+        // The first message is already there, and the second is in the
+        // archive twice.
+        let mut archive = archive;
+        archive.messages.push(messages[1].clone());
+        assert!(archive.new_messages(&messages[..1]) == messages[1..]);
+
+        assert!(Archive::parse(&Plaintext::from("[]".to_owned())).is_err());
+    }
+}
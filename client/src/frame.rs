@@ -0,0 +1,169 @@
+//! The wire format spoken with the server.
+//!
+//! Every frame is prefixed with its length as a big endian `u32` (handled by
+//! `LengthDelimitedCodec`), followed by a one byte kind and the payload.
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+const DATA: u8 = 0;
+const NOTICE: u8 = 1;
+const SHUTDOWN: u8 = 2;
+const HELLO: u8 = 3;
+const ERROR: u8 = 4;
+const COMMAND: u8 = 5;
+const CHALLENGE: u8 = 6;
+
+/// A frame exchanged with the server.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame {
+    /// An end-to-end encrypted payload for the other peers.
+    Data(Bytes),
+    /// Human readable text from the server.
+    Notice(String),
+    /// The server is going away.
+    Shutdown(String),
+    /// Sent first, naming the account and the room to join. Both are at
+    /// most 255 bytes. The proof is our identity key and its signature
+    /// over the challenge and both names, see `Identity::prove`; without
+    /// one we join as a guest.
+    Hello {
+        account: String,
+        room: String,
+        proof: Option<Bytes>,
+    },
+    /// The server refused something, for example because the client is
+    /// sending too fast.
+    Error { code: u8, message: String },
+    /// A moderation command for the server, such as `kick alice`.
+    Command(String),
+    /// Sent empty to ask for something to sign with our identity key, and
+    /// the server's answer.
+    Challenge(Bytes),
+}
+
+impl Frame {
+    /// Parse a frame whose length prefix has already been stripped.
+    pub fn parse(mut frame: Bytes) -> Option<Frame> {
+        if frame.is_empty() {
+            return None;
+        }
+        let kind = frame[0];
+        let payload = frame.split_off(1);
+        let text = || String::from_utf8_lossy(&payload).into_owned();
+
+        match kind {
+            DATA => Some(Frame::Data(payload)),
+            NOTICE => Some(Frame::Notice(text())),
+            SHUTDOWN => Some(Frame::Shutdown(text())),
+            COMMAND => Some(Frame::Command(text())),
+            CHALLENGE => Some(Frame::Challenge(payload)),
+            HELLO => {
+                let (account, rest) = prefixed(&payload)?;
+                let (room, proof) = prefixed(rest)?;
+                Some(Frame::Hello {
+                    account: String::from_utf8(account.to_vec()).ok()?,
+                    room: String::from_utf8(room.to_vec()).ok()?,
+                    proof: (!proof.is_empty()).then(|| payload.slice_ref(proof)),
+                })
+            }
+            ERROR => {
+                let (code, message) = payload.split_first()?;
+                Some(Frame::Error {
+                    code: *code,
+                    message: String::from_utf8_lossy(message).into_owned(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Encode the frame, without the length prefix.
+    pub fn encode(&self) -> Bytes {
+        let mut frame = BytesMut::new();
+        match self {
+            Frame::Data(payload) => {
+                frame.put_u8(DATA);
+                frame.put_slice(payload);
+            }
+            Frame::Notice(text) => {
+                frame.put_u8(NOTICE);
+                frame.put_slice(text.as_bytes());
+            }
+            Frame::Shutdown(text) => {
+                frame.put_u8(SHUTDOWN);
+                frame.put_slice(text.as_bytes());
+            }
+            Frame::Hello {
+                account,
+                room,
+                proof,
+            } => {
+                frame.put_u8(HELLO);
+                frame.put_u8(account.len() as u8);
+                frame.put_slice(account.as_bytes());
+                frame.put_u8(room.len() as u8);
+                frame.put_slice(room.as_bytes());
+                if let Some(proof) = proof {
+                    frame.put_slice(proof);
+                }
+            }
+            Frame::Error { code, message } => {
+                frame.put_u8(ERROR);
+                frame.put_u8(*code);
+                frame.put_slice(message.as_bytes());
+            }
+            Frame::Command(text) => {
+                frame.put_u8(COMMAND);
+                frame.put_slice(text.as_bytes());
+            }
+            Frame::Challenge(challenge) => {
+                frame.put_u8(CHALLENGE);
+                frame.put_slice(challenge);
+            }
+        }
+        frame.freeze()
+    }
+}
+
+/// Split a field preceded by its one byte length off `bytes`.
+fn prefixed(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (len, rest) = bytes.split_first()?;
+    if rest.len() < *len as usize {
+        return None;
+    }
+    Some(rest.split_at(*len as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for frame in [
+            Frame::Data(Bytes::from_static(b"\x00\x01ciphertext")),
+            Frame::Notice("hello".into()),
+            Frame::Shutdown("bye".into()),
+            Frame::Hello {
+                account: "alice".into(),
+                room: "lobby".into(),
+                proof: None,
+            },
+            Frame::Hello {
+                account: "alice".into(),
+                room: "lobby".into(),
+                proof: Some(Bytes::from_static(&[7; 97])),
+            },
+            Frame::Challenge(Bytes::from_static(&[1; 32])),
+            Frame::Error {
+                code: 1,
+                message: "slow down".into(),
+            },
+            Frame::Command("mute bob 10m".into()),
+        ] {
+            assert_eq!(Frame::parse(frame.encode()), Some(frame));
+        }
+        assert_eq!(Frame::parse(Bytes::new()), None);
+        assert_eq!(Frame::parse(Bytes::from_static(&[200])), None);
+    }
+}
@@ -1,20 +1,67 @@
 use bytes::Bytes;
 use futures::{future, SinkExt, StreamExt};
-use std::net::SocketAddr;
-use tokio::net::TcpStream;
-use tokio_util::codec::{BytesCodec, FramedRead, FramedWrite};
+use std::{
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::mpsc,
+};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 
 use aes_gcm::{
-    aead::{Aead, AeadCore, KeyInit},
+    aead::{Aead, AeadCore},
     Aes256Gcm, Nonce,
 };
-use p256::{ecdh::EphemeralSecret, EncodedPoint, PublicKey};
+use p256::{ecdh::EphemeralSecret, EncodedPoint};
 use rand_core::OsRng;
+use rustyline::{
+    error::ReadlineError, Cmd, ConditionalEventHandler, DefaultEditor, Event, EventContext,
+    EventHandler, RepeatCount,
+};
 
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace, warn};
 
-use crate::message::Message;
+use crate::frame::Frame;
+use crate::history::{RoomHistory, Scrollback};
+use crate::identity::{announce_anonymously, fingerprint, parse_announcement, Identity};
+use crate::message::{Kind, Message, MessageId};
+use crate::presence::{Presence, TICK};
+use crate::protocol::{Padding, Session};
+use crate::search::{Hit, Index, Query};
+use crate::secret::{Plaintext, SessionKey};
+use crate::tls::Tls;
+use crate::transfer::{milestone, Incoming, Outgoing, Progress, Transfers};
+
+/// Input commands that are handed to the server as moderation commands,
+/// without the leading `:`.
+const MODERATION: &[&str] = &["kick", "ban", "unban", "mute", "unmute", "op", "deop"];
+
+/// Shorthand for the read half of the connection, plain TCP or TLS.
+type Stream = FramedRead<Box<dyn AsyncRead + Send + Unpin>, LengthDelimitedCodec>;
+
+/// Shorthand for the write half of the connection, plain TCP or TLS.
+type Sink = FramedWrite<Box<dyn AsyncWrite + Send + Unpin>, LengthDelimitedCodec>;
+
+/// What this client brings to every key exchange, and tells the peer once
+/// it is done.
+#[derive(Default)]
+pub struct SessionOptions {
+    /// Signs our ephemeral keys, if the profile has one.
+    pub identity: Option<Identity>,
+    /// The padding to ask the peer for.
+    pub padding: Padding,
+    /// Whether to tell the peer when we are typing.
+    pub typing: bool,
+    /// Whether to tell the peer which of its messages we got and read.
+    pub receipts: bool,
+}
 
 #[derive(Debug)]
 pub enum MyError {
@@ -22,32 +69,145 @@ pub enum MyError {
     Quit,
 }
 
-pub async fn handle_connection(addr: &SocketAddr) -> Result<(), MyError> {
+/// Connect to the server, plain TCP or TLS.
+async fn connect(addr: &SocketAddr, tls: Option<Tls>) -> Result<(Stream, Sink), MyError> {
     let tcp_stream = TcpStream::connect(addr).await.map_err(MyError::Io)?;
-    let (r, w) = tcp_stream.into_split();
-    let mut stream = FramedRead::new(r, BytesCodec::new());
-    let mut sink = FramedWrite::new(w, BytesCodec::new());
+    let (r, w): (
+        Box<dyn AsyncRead + Send + Unpin>,
+        Box<dyn AsyncWrite + Send + Unpin>,
+    ) = match tls {
+        Some(tls) => {
+            let tls_stream = tls.connect(tcp_stream).await.map_err(MyError::Io)?;
+            let (r, w) = tokio::io::split(tls_stream);
+            (Box::new(r), Box::new(w))
+        }
+        None => {
+            let (r, w) = tcp_stream.into_split();
+            (Box::new(r), Box::new(w))
+        }
+    };
+    Ok((
+        FramedRead::new(r, LengthDelimitedCodec::new()),
+        FramedWrite::new(w, LengthDelimitedCodec::new()),
+    ))
+}
 
-    println!("Enter your username:");
-    let mut buff = String::new();
-    std::io::stdin()
-        .read_line(&mut buff)
-        .expect("reading from stdin failed");
+/// How many messages are shown from the history on joining, and by
+/// `:more` without a count.
+const SCROLLBACK: usize = 20;
+/// How many of the latest search results are listed.
+const RESULTS: usize = 20;
+/// How many messages are shown on either side of a search result.
+const CONTEXT: usize = 5;
+/// The pause between the chunks of a file, to stay under the server's
+/// default limits of 10 messages and 256 KiB a second.
+const CHUNK_INTERVAL: Duration = Duration::from_millis(150);
+
+/// Chat interactively until the user quits or the server goes away. The
+/// username is asked for when it is `None`. Messages are kept in `history`
+/// if there is one, and the latest of them shown first. Files are saved to
+/// `downloads`.
+pub async fn handle_connection(
+    addr: &SocketAddr,
+    tls: Option<Tls>,
+    username: Option<String>,
+    room: &str,
+    options: SessionOptions,
+    history: Option<RoomHistory>,
+    downloads: PathBuf,
+) -> Result<(), MyError> {
+    let (mut stream, mut sink) = connect(addr, tls).await?;
+
+    let buff = match username {
+        Some(username) => username,
+        None => {
+            println!("Enter your username:");
+            let mut buff = String::new();
+            std::io::stdin()
+                .read_line(&mut buff)
+                .expect("reading from stdin failed");
+            buff
+        }
+    };
+    hello(
+        &mut stream,
+        &mut sink,
+        buff.trim(),
+        room,
+        options.identity.as_ref(),
+    )
+    .await?;
+
+    let mut scrollback = Scrollback::default();
+    if let Some(history) = &history {
+        show_earlier(history, &mut scrollback, SCROLLBACK);
+    }
 
     // FIX: keys are being sent even if another client hasn't connected
-    let shared_key = key_exchange(&mut stream, &mut sink).await;
-    let cipher1 = Aes256Gcm::new_from_slice(&shared_key).unwrap();
-    let cipher2 = cipher1.clone();
+    let (session_key, session) = key_exchange(&mut stream, &mut sink, &options).await?;
+    let cipher1 = session_key.cipher();
+    let cipher2 = session_key.cipher();
+    drop(session_key);
 
+    let chat = Chat {
+        sink: Arc::new(tokio::sync::Mutex::new(sink)),
+        cipher: cipher1,
+        session,
+        username: buff.trim().into(),
+        transfers: Arc::new(std::sync::Mutex::new(Transfers::new(downloads))),
+        presence: Arc::new(std::sync::Mutex::new(Presence::new(
+            options.typing,
+            options.receipts,
+        ))),
+    };
+    let mut editor = DefaultEditor::new().map_err(readline_error)?;
+    editor.bind_sequence(
+        Event::Any,
+        EventHandler::Conditional(Box::new(Keystrokes(chat.presence.clone()))),
+    );
+
+    // Whatever the peer is to be told about us goes out on every tick, and
+    // stops once the connection is closed.
+    let ticking = chat.clone();
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(TICK);
+        loop {
+            ticks.tick().await;
+            let due = ticking.presence.lock().unwrap().due(Instant::now());
+            for kind in due {
+                if ticking
+                    .send(&Message::new(&ticking.username, kind))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    });
+
+    let sending = chat.clone();
+    let sent = history.clone();
     let send: tokio::task::JoinHandle<Result<(), MyError>> = tokio::spawn(async move {
-        let username = buff.trim();
-        let mut buff = String::new();
-        let cipher = cipher1;
+        let mut local = Local {
+            editor,
+            history: sent,
+            scrollback,
+            index: Index::default(),
+            hits: Vec::new(),
+        };
 
         loop {
-            match send(&mut sink, &mut buff, username, &cipher).await {
+            match send(&sending, &mut local).await {
                 Ok(_) => (),
-                Err(MyError::Quit) => return Ok(()),
+                // The receiving half holds the sink too, so it is closed
+                // rather than dropped to leave.
+                Err(MyError::Quit) => {
+                    let mut sink = sending.sink.lock().await;
+                    return SinkExt::<Bytes>::close(&mut *sink)
+                        .await
+                        .map_err(MyError::Io);
+                }
                 Err(err) => return Err(err),
             }
         }
@@ -56,7 +216,7 @@ pub async fn handle_connection(addr: &SocketAddr) -> Result<(), MyError> {
     let recieve: tokio::task::JoinHandle<Result<(), MyError>> = tokio::spawn(async move {
         let cipher = cipher2;
         loop {
-            match recieve(&mut stream, &cipher).await {
+            match recieve(&mut stream, &cipher, session, history.as_ref(), Some(&chat)).await {
                 Ok(_) => (),
                 Err(MyError::Quit) => return Ok(()),
                 Err(err) => return Err(err),
@@ -70,21 +230,135 @@ pub async fn handle_connection(addr: &SocketAddr) -> Result<(), MyError> {
     }
 }
 
+/// Send one message without user interaction and disconnect. Gives up if
+/// no peer answers the key exchange within `wait`.
+pub async fn send_message(
+    addr: &SocketAddr,
+    tls: Option<Tls>,
+    username: &str,
+    room: &str,
+    options: SessionOptions,
+    message: &Message,
+    wait: Duration,
+) -> Result<(), MyError> {
+    let (mut stream, mut sink) = connect(addr, tls).await?;
+    hello(
+        &mut stream,
+        &mut sink,
+        username,
+        room,
+        options.identity.as_ref(),
+    )
+    .await?;
+
+    let (session_key, session) =
+        tokio::time::timeout(wait, key_exchange(&mut stream, &mut sink, &options))
+            .await
+            .map_err(|_| {
+                MyError::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no peer answered the key exchange",
+                ))
+            })??;
+    let cipher = session_key.cipher();
+    drop(session_key);
+
+    send_encrypted(&mut sink, message, &cipher, session).await?;
+    SinkExt::<Bytes>::close(&mut sink)
+        .await
+        .map_err(MyError::Io)
+}
+
+/// Tell the server who we are and which room to join. With an identity we
+/// sign in with it, so nobody else can use the account; without one we
+/// join as a guest.
+async fn hello(
+    stream: &mut Stream,
+    sink: &mut Sink,
+    username: &str,
+    room: &str,
+    identity: Option<&Identity>,
+) -> Result<(), MyError> {
+    if username.is_empty() || username.len() > 64 || room.is_empty() || room.len() > 64 {
+        return Err(MyError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usernames and rooms must be 1 to 64 bytes long",
+        )));
+    }
+    let proof = match identity {
+        Some(identity) => {
+            let ask = Frame::Challenge(Bytes::new()).encode();
+            sink.send(ask).await.map_err(MyError::Io)?;
+            let challenge = challenge(stream).await?;
+            Some(Bytes::from(identity.prove(&challenge, username, room)))
+        }
+        None => None,
+    };
+    let hello = Frame::Hello {
+        account: username.to_owned(),
+        room: room.to_owned(),
+        proof,
+    };
+    sink.send(hello.encode()).await.map_err(MyError::Io)
+}
+
+/// Wait for the server's answer to asking for a challenge.
+async fn challenge(stream: &mut Stream) -> Result<Bytes, MyError> {
+    while let Some(frame) = stream.next().await {
+        match Frame::parse(frame.map_err(MyError::Io)?.freeze()) {
+            Some(Frame::Challenge(challenge)) => return Ok(challenge),
+            Some(Frame::Notice(text)) => println!("{}", text),
+            Some(Frame::Shutdown(reason)) => {
+                println!("Disconnected: {}", reason);
+                return Err(MyError::Quit);
+            }
+            Some(Frame::Error { message, .. }) => println!("Server: {}", message),
+            _ => error!("Recieved an unexpected frame while signing in"),
+        }
+    }
+    debug!("The stream has been closed");
+    Err(MyError::Quit)
+}
+
 async fn key_exchange(
-    stream: &mut FramedRead<tokio::net::tcp::OwnedReadHalf, BytesCodec>,
-    sink: &mut FramedWrite<tokio::net::tcp::OwnedWriteHalf, BytesCodec>,
-) -> Vec<u8> {
+    stream: &mut Stream,
+    sink: &mut Sink,
+    options: &SessionOptions,
+) -> Result<(SessionKey, Session), MyError> {
     let ephemeral_secret = EphemeralSecret::random(&mut OsRng);
     let ephemeral_public = EncodedPoint::from(ephemeral_secret.public_key());
 
-    let bytes = Bytes::from(ephemeral_public.to_bytes());
-    sink.send(bytes).await.unwrap();
+    // With an identity the ephemeral key is signed, so the peer can tell
+    // who it is talking to.
+    let parameters = Session::parameters(options.padding);
+    let payload = match &options.identity {
+        Some(identity) => identity.announce(&ephemeral_public, &parameters),
+        None => announce_anonymously(&ephemeral_public, &parameters),
+    };
+    let bytes = Frame::Data(Bytes::from(payload)).encode();
+    sink.send(bytes.clone()).await.map_err(MyError::Io)?;
     trace!("Sent ephemeral pub key");
 
-    let mut shared_key: Vec<u8> = Vec::new();
+    let mut session_key = None;
     while let Some(line) = stream.next().await {
-        let recieved = match line {
-            Ok(recieved) => recieved,
+        let recieved = match line.map(|line| Frame::parse(line.freeze())) {
+            Ok(Some(Frame::Data(recieved))) => recieved,
+            Ok(Some(Frame::Notice(text))) => {
+                println!("{}", text);
+                continue;
+            }
+            Ok(Some(Frame::Shutdown(reason))) => {
+                println!("Disconnected: {}", reason);
+                return Err(MyError::Quit);
+            }
+            Ok(Some(Frame::Error { message, .. })) => {
+                println!("Server: {}", message);
+                continue;
+            }
+            Ok(Some(Frame::Hello { .. } | Frame::Command(_) | Frame::Challenge(_))) | Ok(None) => {
+                error!("Recieved an unknown frame");
+                continue;
+            }
             Err(err) => {
                 error!("Recieved invalid data: {:?}", err);
                 continue;
@@ -92,40 +366,459 @@ async fn key_exchange(
         };
 
         trace!("Recieved ephemeral pub key");
-        let sender_ephemeral_public =
-            PublicKey::from_sec1_bytes(recieved.as_ref()).expect("public key is invalid!"); // In real usage, don't panic, handle this!
-        let shared = ephemeral_secret.diffie_hellman(&sender_ephemeral_public);
-        shared_key = shared.raw_secret_bytes().to_vec();
+        let announcement = parse_announcement(&recieved).map_err(MyError::Io)?;
+        match &announcement.identity {
+            Some(identity) => println!("Peer identity: {}", fingerprint(identity)),
+            None => println!("The peer has no identity key"),
+        }
+        let shared = ephemeral_secret.diffie_hellman(&announcement.ephemeral);
+        // `shared` wipes itself when dropped; the key copied out of it does
+        // the same.
+        let key = SessionKey::from_slice(shared.raw_secret_bytes())
+            .expect("P-256 shared secrets are 32 bytes");
+        trace!("Derived {:?}", key);
+        let session = Session::negotiate(options.padding, &announcement.parameters)
+            .map_err(|err| MyError::Io(io::Error::other(err)))?;
+        debug!(
+            "Speaking protocol version {:?}, padding {}",
+            session.version, session.padding
+        );
+        session_key = Some((key, session));
+
+        // Our first announcement is lost if the peer was not connected yet,
+        // so repeat it now that it is. A peer that already has it ignores it.
+        sink.send(bytes).await.map_err(MyError::Io)?;
         break;
     }
-    shared_key
+    match session_key {
+        Some(session) => Ok(session),
+        None => {
+            debug!("The stream has been closed");
+            Err(MyError::Quit)
+        }
+    }
 }
 
-async fn send(
-    sink: &mut FramedWrite<tokio::net::tcp::OwnedWriteHalf, BytesCodec>,
-    buff: &mut String,
-    username: &str,
-    cipher: &aes_gcm::AesGcm<aes_gcm::aes::Aes256, aead::consts::U12>,
-) -> Result<(), MyError> {
-    std::io::stdin()
-        .read_line(buff)
-        .expect("reading from stdin failed");
-    let message = buff.trim();
+/// What both halves of an interactive chat share: the way to the peer, the
+/// file transfers and what the peer is to be told about us.
+#[derive(Clone)]
+struct Chat {
+    sink: Arc<tokio::sync::Mutex<Sink>>,
+    cipher: Aes256Gcm,
+    session: Session,
+    username: Arc<str>,
+    transfers: Arc<std::sync::Mutex<Transfers>>,
+    presence: Arc<std::sync::Mutex<Presence>>,
+}
+
+impl Chat {
+    async fn send(&self, message: &Message) -> Result<(), MyError> {
+        let mut sink = self.sink.lock().await;
+        send_encrypted(&mut sink, message, &self.cipher, self.session).await
+    }
+
+    /// Offer the file at `path`. An earlier offer of the same file is made
+    /// again, so the peer resumes it.
+    async fn offer(&self, path: &str, history: Option<&RoomHistory>) -> Result<(), MyError> {
+        let earlier = match history {
+            Some(history) => history
+                .history()
+                .load(history.room())
+                .map_err(MyError::Io)?,
+            None => Vec::new(),
+        };
+        let path = PathBuf::from(path);
+        let username = self.username.clone();
+        let (outgoing, offer, new) = blocking(move || Outgoing::offer(&path, &username, &earlier))
+            .await
+            .map_err(MyError::Io)?;
+        println!("-- offering {}", outgoing.name);
+        self.transfers
+            .lock()
+            .unwrap()
+            .outgoing
+            .insert(offer.id(), outgoing);
+        self.send(&offer).await?;
+        if let (true, Some(history)) = (new, history) {
+            history.append(&offer);
+        }
+        Ok(())
+    }
+
+    /// Stream the chunks of `file` from `from` on, if we offered it and
+    /// are not streaming it already.
+    fn fetched(&self, file: MessageId, from: u64) {
+        let outgoing = {
+            let mut transfers = self.transfers.lock().unwrap();
+            let Some(outgoing) = transfers.outgoing.get(&file).cloned() else {
+                debug!("Asked for a file that was not offered");
+                return;
+            };
+            if !transfers.start_sending(file) {
+                debug!("Asked for a file that is being sent already");
+                return;
+            }
+            outgoing
+        };
+        let chat = self.clone();
+        tokio::spawn(async move {
+            if let Err(MyError::Io(e)) = chat.stream(&outgoing, from).await {
+                println!("-- sending {} failed: {}", outgoing.name, e);
+            }
+            chat.transfers.lock().unwrap().stop_sending(file);
+        });
+    }
+
+    async fn stream(&self, outgoing: &Outgoing, from: u64) -> Result<(), MyError> {
+        // The file is read on a blocking thread of its own, a chunk ahead.
+        let (tx, mut rx) = mpsc::channel(1);
+        let reader = outgoing.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut file = match reader.open() {
+                Ok(file) => file,
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(e));
+                    return;
+                }
+            };
+            for index in from..reader.chunks() {
+                let chunk = reader.chunk(&mut file, index);
+                let failed = chunk.is_err();
+                if tx.blocking_send(chunk).is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        let chunks = outgoing.chunks();
+        for index in from..chunks {
+            let Some(chunk) = rx.recv().await else {
+                return Err(MyError::Io(io::Error::other("the file reader stopped")));
+            };
+            let chunk = chunk.map_err(MyError::Io)?;
+            self.send(&Message::new(&self.username, chunk)).await?;
+            if let Some(percent) = milestone(index + 1, chunks) {
+                println!("-- sending {}: {}%", outgoing.name, percent);
+            }
+            tokio::time::sleep(CHUNK_INTERVAL).await;
+        }
+        Ok(())
+    }
+
+    /// Let the user accept the file `offer` offers, or resume it if they
+    /// did before.
+    async fn offered(&self, offer: &Message) -> Result<(), MyError> {
+        let downloads = self.transfers.lock().unwrap().downloads.clone();
+        let Some(incoming) = Incoming::new(&downloads, offer).map_err(MyError::Io)? else {
+            return Ok(());
+        };
+        if incoming.is_started() {
+            return self.fetch(offer, incoming).await;
+        }
+        let n = self.transfers.lock().unwrap().offered(offer);
+        println!("-- :accept {} fetches {}", n, incoming.name);
+        Ok(())
+    }
+
+    /// Fetch the file of the offer numbered `n`.
+    async fn accept(&self, n: usize) -> Result<(), MyError> {
+        let (offer, downloads) = {
+            let transfers = self.transfers.lock().unwrap();
+            let Some(offer) = transfers.offer(n).cloned() else {
+                println!("-- there is no offer {}", n);
+                return Ok(());
+            };
+            if transfers.incoming.contains_key(&offer.id()) {
+                println!("-- offer {} is being fetched already", n);
+                return Ok(());
+            }
+            (offer, transfers.downloads.clone())
+        };
+        let Some(incoming) = Incoming::new(&downloads, &offer).map_err(MyError::Io)? else {
+            return Ok(());
+        };
+        self.fetch(&offer, incoming).await
+    }
+
+    /// Fetch what we do not have yet of the file `offer` offers.
+    async fn fetch(&self, offer: &Message, incoming: Incoming) -> Result<(), MyError> {
+        // Nothing is left to fetch of an empty file, or of one that was
+        // complete but not saved yet.
+        if incoming.is_complete() {
+            let name = incoming.name.clone();
+            match blocking(move || incoming.save()).await {
+                Ok(path) => println!("-- saved {} as {}", name, path.display()),
+                Err(e) => println!("-- receiving {} failed: {}", name, e),
+            }
+            return Ok(());
+        }
+
+        let from = incoming.next();
+        if from > 0 {
+            let percent = from * 100 / incoming.chunks();
+            println!("-- resuming {} at {}%", incoming.name, percent);
+        }
+        self.transfers
+            .lock()
+            .unwrap()
+            .incoming
+            .insert(offer.id(), Arc::new(std::sync::Mutex::new(incoming)));
+        let fetch = Kind::Fetch {
+            file: offer.id(),
+            from,
+        };
+        self.send(&Message::new(&self.username, fetch)).await
+    }
+
+    /// Add a chunk to its download. Chunks are awaited one at a time, so
+    /// they are written in the order they came.
+    async fn chunk(&self, file: MessageId, index: u64, data: Vec<u8>) {
+        let Some(incoming) = self.transfers.lock().unwrap().incoming.get(&file).cloned() else {
+            debug!("Recieved a chunk of a file that was not offered");
+            return;
+        };
+        let name = incoming.lock().unwrap().name.clone();
+        let progress = blocking(move || incoming.lock().unwrap().add(index, &data)).await;
+        match progress {
+            Ok(Progress::Skipped) => (),
+            Ok(Progress::Partial { done, of }) => {
+                if let Some(percent) = milestone(done, of) {
+                    println!("-- receiving {}: {}%", name, percent);
+                }
+            }
+            Ok(Progress::Saved(path)) => {
+                println!("-- saved {} as {}", name, path.display());
+                self.transfers.lock().unwrap().incoming.remove(&file);
+            }
+            Err(e) => {
+                println!("-- receiving {} failed: {}", name, e);
+                self.transfers.lock().unwrap().incoming.remove(&file);
+            }
+        }
+    }
+}
+
+/// Notes every key pressed at the prompt, then lets the editor act on it.
+struct Keystrokes(Arc<std::sync::Mutex<Presence>>);
+
+impl ConditionalEventHandler for Keystrokes {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, _: &EventContext) -> Option<Cmd> {
+        self.0.lock().unwrap().key(Instant::now());
+        None
+    }
+}
+
+/// Run `f`, which reads or writes files, on a thread where blocking is
+/// fine.
+async fn blocking<R: Send + 'static>(
+    f: impl FnOnce() -> io::Result<R> + Send + 'static,
+) -> io::Result<R> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+fn readline_error(err: ReadlineError) -> MyError {
+    match err {
+        ReadlineError::Io(e) => MyError::Io(e),
+        err => MyError::Io(io::Error::other(err)),
+    }
+}
+
+/// The prompt and the history as seen from it.
+struct Local {
+    editor: DefaultEditor,
+    history: Option<RoomHistory>,
+    scrollback: Scrollback,
+    /// Built on the first search and brought up to date on every one.
+    index: Index,
+    /// The results of the last search, for `:context`.
+    hits: Vec<Hit>,
+}
+
+impl Local {
+    /// List the latest messages matching `query`, numbered for `:context`.
+    fn search(&mut self, query: &str) {
+        let Some(history) = &self.history else {
+            println!("-- history is not kept");
+            return;
+        };
+        let query: Query = match query.parse() {
+            Ok(query) => query,
+            Err(e) => {
+                println!("-- {}", e);
+                return;
+            }
+        };
+        match history.history().load_all() {
+            Ok(rooms) => self.index.update(rooms),
+            Err(e) => {
+                error!("Failed to read the history: {}", e);
+                return;
+            }
+        }
+
+        let mut hits = self.index.search(&query, history.room());
+        if hits.len() > RESULTS {
+            println!("-- {} results, the latest {}:", hits.len(), RESULTS);
+            hits.drain(..hits.len() - RESULTS);
+        } else {
+            println!("-- {} results", hits.len());
+        }
+        for (i, hit) in hits.iter().enumerate() {
+            let message = self.index.message(*hit);
+            println!(
+                "[{}] #{} {}",
+                i + 1,
+                self.index.room(*hit),
+                message.render()
+            );
+        }
+        if !hits.is_empty() {
+            println!("-- :context <n> shows a result among the messages around it");
+        }
+        self.hits = hits;
+    }
+
+    /// Show the `n`th result of the last search among its neighbours.
+    fn context(&self, n: &str) {
+        let Some(hit) = n
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| self.hits.get(n.checked_sub(1)?))
+        else {
+            println!("-- no such search result");
+            return;
+        };
+        let (messages, at) = self.index.context(*hit, CONTEXT);
+        println!("-- #{}", self.index.room(*hit));
+        for (i, message) in messages.iter().enumerate() {
+            let marker = if i == at { ">" } else { " " };
+            println!("{} {}", marker, message.render());
+        }
+        println!("--");
+    }
+}
+
+/// Print up to `n` messages from before the ones shown so far.
+fn show_earlier(history: &RoomHistory, scrollback: &mut Scrollback, n: usize) {
+    match scrollback.back(history, n) {
+        Ok(messages) if messages.is_empty() => println!("-- no earlier messages"),
+        Ok(messages) => {
+            println!("-- earlier messages");
+            for message in messages {
+                println!("{}", message.render());
+            }
+            println!("--");
+        }
+        Err(e) => error!("Failed to read the history: {}", e),
+    }
+}
+
+async fn send(chat: &Chat, local: &mut Local) -> Result<(), MyError> {
+    // Waiting for input must not hold up receiving, or streaming a file, on
+    // the same worker thread.
+    let line = match tokio::task::block_in_place(|| local.editor.readline("")) {
+        Ok(line) => line,
+        Err(ReadlineError::Eof | ReadlineError::Interrupted) => return Err(MyError::Quit),
+        Err(err) => return Err(readline_error(err)),
+    };
+    chat.presence.lock().unwrap().entered();
+    let message = line.trim();
     if message == ":q" {
         return Err(MyError::Quit);
     }
 
-    let message = Message::new(username, message);
-    let serialized = match serde_json::to_string(&message) {
+    // `:kick bob`, `:mute bob 10m` and the like go to the server in the
+    // clear; everything else is a message for the room.
+    if let Some(command) = message.strip_prefix(':') {
+        let name = command.split_whitespace().next().unwrap_or_default();
+        if MODERATION.contains(&name) {
+            let frame = Frame::Command(command.to_owned()).encode();
+            chat.sink
+                .lock()
+                .await
+                .send(frame)
+                .await
+                .map_err(MyError::Io)?;
+            return Ok(());
+        }
+        // `:more` or `:more 50` scrolls back through the history.
+        if name == "more" {
+            let n = command
+                .split_whitespace()
+                .nth(1)
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(SCROLLBACK);
+            match &local.history {
+                Some(history) => show_earlier(history, &mut local.scrollback, n),
+                None => println!("-- history is not kept"),
+            }
+            return Ok(());
+        }
+        // `:search from:bob deploy` finds messages in the history and
+        // `:context 2` shows the second one found in its conversation.
+        // `:send-file notes.txt` offers a file to the peer and `:accept 1`
+        // fetches the first one offered to us.
+        if name == "send-file" {
+            let path = command[name.len()..].trim();
+            if let Err(MyError::Io(e)) = chat.offer(path, local.history.as_ref()).await {
+                println!("-- {}: {}", path, e);
+            }
+            return Ok(());
+        }
+        if name == "accept" {
+            match command[name.len()..].trim().parse() {
+                Ok(n) => {
+                    if let Err(MyError::Io(e)) = chat.accept(n).await {
+                        println!("-- fetching the file failed: {}", e);
+                    }
+                }
+                Err(_) => println!("-- :accept takes the number of an offer"),
+            }
+            return Ok(());
+        }
+        if name == "search" || name == "context" {
+            let rest = command[name.len()..].trim();
+            match name {
+                "search" => local.search(rest),
+                _ => local.context(rest),
+            }
+            return Ok(());
+        }
+    }
+
+    // `/me waves` is an action rather than something said.
+    let kind = match message.strip_prefix("/me ") {
+        Some(action) => Kind::Action(action.trim().to_owned()),
+        None => Kind::Text(message.to_owned()),
+    };
+    let message = Message::new(&chat.username, kind);
+    chat.send(&message).await?;
+    if let Some(history) = &local.history {
+        history.append(&message);
+    }
+    Ok(())
+}
+
+/// Encode and pad a message as agreed for the session, encrypt it and send
+/// it.
+async fn send_encrypted(
+    sink: &mut Sink,
+    message: &Message,
+    cipher: &aes_gcm::AesGcm<aes_gcm::aes::Aes256, aead::consts::U12>,
+    session: Session,
+) -> Result<(), MyError> {
+    let serialized = match session.encode(message) {
         Ok(serialized) => serialized,
         Err(err) => {
-            error!("Failed to convert message to json: {:?}", err);
+            error!("Failed to encode message: {}", err);
             return Ok(());
         }
     };
 
     let nonce = Aes256Gcm::generate_nonce(&mut OsRng); // 96-bits; unique per message
-    let mut ciphertext = match cipher.encrypt(&nonce, serialized.as_ref()) {
+    let mut ciphertext = match cipher.encrypt(&nonce, serialized.expose()) {
         Ok(ciphertext) => ciphertext,
         Err(err) => {
             error!("Failed to encrypt message: {:?}", err);
@@ -136,17 +829,19 @@ async fn send(
     let mut to_send: Vec<u8> = Vec::new();
     to_send.append(&mut nonce.to_vec());
     to_send.append(&mut ciphertext);
-    let bytes = Bytes::from(to_send);
+    let bytes = Frame::Data(Bytes::from(to_send)).encode();
 
     sink.send(bytes).await.map_err(MyError::Io)?;
     trace!("Message sent");
-    buff.clear();
     Ok(())
 }
 
 async fn recieve(
-    stream: &mut FramedRead<tokio::net::tcp::OwnedReadHalf, BytesCodec>,
+    stream: &mut Stream,
     cipher2: &aes_gcm::AesGcm<aes_gcm::aes::Aes256, aead::consts::U12>,
+    session: Session,
+    history: Option<&RoomHistory>,
+    chat: Option<&Chat>,
 ) -> Result<(), MyError> {
     let recieved = match stream.next().await {
         Some(Ok(recieved)) => recieved,
@@ -160,32 +855,196 @@ async fn recieve(
         }
     };
 
-    // Why 12? Because first 92 bits is nonce and the rest is ciphertext
+    let recieved = match Frame::parse(recieved.freeze()) {
+        Some(Frame::Data(recieved)) => recieved,
+        Some(Frame::Notice(text)) => {
+            println!("{}", text);
+            return Ok(());
+        }
+        Some(Frame::Shutdown(reason)) => {
+            println!("Disconnected: {}", reason);
+            return Err(MyError::Quit);
+        }
+        Some(Frame::Error { message, .. }) => {
+            println!("Server: {}", message);
+            return Ok(());
+        }
+        Some(Frame::Hello { .. } | Frame::Command(_) | Frame::Challenge(_)) | None => {
+            error!("Recieved an unknown frame");
+            return Ok(());
+        }
+    };
+
+    // Why 12? Because first 96 bits is nonce and the rest is ciphertext.
+    // Anyone in the room can send a frame too short to hold one.
+    if recieved.len() < 12 {
+        debug!("Recieved a frame too short to decrypt");
+        return Ok(());
+    }
     let (nonce, ciphertext) = recieved.split_at(12);
     let nonce = Nonce::from_slice(nonce);
 
     let plaintext = match cipher2.decrypt(nonce, ciphertext.as_ref()) {
-        Ok(ciphertext) => ciphertext,
+        Ok(plaintext) => Plaintext::from(plaintext),
+        Err(_) if parse_announcement(&recieved).is_ok() => {
+            trace!("Ignoring a repeated key exchange");
+            return Ok(());
+        }
         Err(err) => {
             error!("Failed to decrypt message: {:?}", err);
             return Ok(());
         }
     };
 
-    let plaintext = std::str::from_utf8(&plaintext).unwrap();
-    let deserialized = match serde_json::from_str::<Message>(plaintext) {
+    let deserialized = match session.decode(plaintext) {
         Ok(deserialized) => deserialized,
         Err(err) => {
-            debug!("Recieved invalid json: {:?}", err);
+            debug!("Recieved an invalid message: {}", err);
             return Ok(());
         }
     };
+    trace!("Message recieved");
 
-    println!(
-        "{}: {}: {}",
-        deserialized.timestamp(),
-        deserialized.sender(),
-        deserialized.text(),
-    );
+    // The parts of a file transfer are for the transfer only, and what the
+    // peer says about itself is shown but not kept.
+    match (deserialized.kind(), chat) {
+        (Kind::Fetch { file, from }, Some(chat)) => chat.fetched(*file, *from),
+        (Kind::Chunk { file, index, data }, Some(chat)) => {
+            chat.chunk(*file, *index, data.clone()).await
+        }
+        (Kind::Fetch { .. } | Kind::Chunk { .. }, None) => (),
+        (Kind::Typing(_), _) => println!("-- {}", deserialized.describe()),
+        (Kind::Delivered { target } | Kind::Read { target }, _) => {
+            let what = match deserialized.kind() {
+                Kind::Delivered { .. } => "received",
+                _ => "read",
+            };
+            let time = target.timestamp().with_timezone(&chrono::Local);
+            println!(
+                "-- {} {} your messages up to {}",
+                deserialized.sender(),
+                what,
+                time.format("%H:%M:%S")
+            );
+        }
+        _ => {
+            if let Some(history) = history {
+                history.append(&deserialized);
+            }
+            println!("{}", deserialized.render());
+            if let Some(chat) = chat {
+                chat.presence.lock().unwrap().shown(deserialized.id());
+            }
+        }
+    }
+    if let (Kind::Offer { .. }, Some(chat)) = (deserialized.kind(), chat) {
+        if let Err(MyError::Io(e)) = chat.offered(&deserialized).await {
+            println!("-- fetching the file failed: {}", e);
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    /// Everything logged while it is installed.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A stand-in for the server that hands each client's bytes to the
+    /// other, frames and all.
+    async fn relay() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (a, _) = listener.accept().await.unwrap();
+            let (b, _) = listener.accept().await.unwrap();
+            let (mut a_read, mut a_write) = a.into_split();
+            let (mut b_read, mut b_write) = b.into_split();
+            tokio::spawn(async move { tokio::io::copy(&mut a_read, &mut b_write).await });
+            tokio::io::copy(&mut b_read, &mut a_write).await
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn logs_hold_no_secrets() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::TRACE)
+                .with_writer(move || writer.clone())
+                .finish(),
+        );
+
+        let addr = relay().await;
+        let (mut alice_stream, mut alice_sink) = connect(&addr, None).await.unwrap();
+        let (mut bob_stream, mut bob_sink) = connect(&addr, None).await.unwrap();
+        hello(&mut alice_stream, &mut alice_sink, "alice", "lobby", None)
+            .await
+            .unwrap();
+        hello(&mut bob_stream, &mut bob_sink, "bob", "lobby", None)
+            .await
+            .unwrap();
+        let alice_options = SessionOptions::default();
+        let bob_options = SessionOptions {
+            padding: Padding::Block(128),
+            ..SessionOptions::default()
+        };
+        let (alice_key, bob_key) = tokio::join!(
+            key_exchange(&mut alice_stream, &mut alice_sink, &alice_options),
+            key_exchange(&mut bob_stream, &mut bob_sink, &bob_options),
+        );
+        let ((alice_key, session), (bob_key, _)) = (alice_key.unwrap(), bob_key.unwrap());
+        assert_eq!(session.padding, Padding::Block(128));
+        assert_eq!(alice_key.expose(), bob_key.expose());
+
+        let secret = "the vault code is 4711";
+        let message = Message::new("alice", Kind::Text(secret.to_owned()));
+        send_encrypted(&mut alice_sink, &message, &alice_key.cipher(), session)
+            .await
+            .unwrap();
+        // Alice's repeated key announcement comes first, then the message.
+        for _ in 0..2 {
+            recieve(&mut bob_stream, &bob_key.cipher(), session, None, None)
+                .await
+                .unwrap();
+        }
+
+        // A frame too short to hold a nonce is dropped, not a panic.
+        alice_sink
+            .send(Frame::Data(Bytes::from_static(b"short")).encode())
+            .await
+            .unwrap();
+        recieve(&mut bob_stream, &bob_key.cipher(), session, None, None)
+            .await
+            .unwrap();
+
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("Message recieved"), "{}", logs);
+        assert!(logs.contains("[REDACTED]"), "{}", logs);
+        assert!(!logs.contains(secret), "{}", logs);
+
+        let key = alice_key.expose();
+        let hex: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+        assert!(!logs.to_lowercase().contains(&hex), "{}", logs);
+        assert!(!logs.contains(&format!("{:?}", key)), "{}", logs);
+        assert!(!logs.contains(&format!("{:?}", &key[..])), "{}", logs);
+    }
+}
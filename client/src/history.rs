@@ -0,0 +1,622 @@
+//! Conversation history kept on disk, encrypted.
+//!
+//! Every message shown or sent is appended to the log of its room in the
+//! profile's `history` directory. Logs are encrypted with AES-256-GCM under
+//! a key derived from a passphrase with Argon2id; `history/key` holds the
+//! Argon2 parameters, the salt and a known value encrypted under the key,
+//! which tells a wrong passphrase from a damaged log.
+//!
+//! A log is named after the SHA-256 of its room, so the room names are not
+//! on disk either. It is a sequence of records: a big-endian `u32` length,
+//! a nonce and the ciphertext of one `Entry` encoded with postcard.
+
+use std::{
+    fs, io,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use argon2::{Algorithm, Argon2, Params};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use crate::message::{Message, MessageId};
+use crate::secret::Plaintext;
+
+const KEY_FILE: &str = "key";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/// The most memory, in KiB, iterations and lanes a key file may ask key
+/// derivation for; a hostile archive could otherwise ask for any amount.
+const MAX_COSTS: [u32; 3] = [256 * 1024, 16, 16];
+/// Encrypted into the key file to check the passphrase against.
+const CHECK: &[u8] = b"chat history";
+/// The costs, the salt, the nonce and the encrypted check, with its tag.
+const KEY_FILE_LEN: usize = 12 + SALT_LEN + NONCE_LEN + CHECK.len() + 16;
+
+/// One record of a log.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    room: String,
+    message: Message,
+}
+
+/// The encrypted logs of one profile.
+pub struct History {
+    dir: PathBuf,
+    cipher: Aes256Gcm,
+}
+
+impl History {
+    /// Open the history in `dir` with `passphrase`, creating it if there is
+    /// none yet. Fails with `PermissionDenied` if the passphrase is wrong.
+    pub fn open(dir: &Path, passphrase: &str) -> io::Result<History> {
+        History::open_with(dir, passphrase, Params::default())
+    }
+
+    /// Like `open`, deriving the key of a new history with `params`.
+    fn open_with(dir: &Path, passphrase: &str, params: Params) -> io::Result<History> {
+        use std::os::unix::fs::DirBuilderExt;
+
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        let path = dir.join(KEY_FILE);
+        let cipher = match fs::read(&path) {
+            Ok(key_file) => unlock(&key_file, passphrase)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let (key_file, cipher) = create(passphrase, params)?;
+                write_private(&path, &key_file)?;
+                cipher
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(History {
+            dir: dir.to_owned(),
+            cipher,
+        })
+    }
+
+    /// Append `message` to the log of `room`.
+    pub fn append(&self, room: &str, message: &Message) -> io::Result<()> {
+        let record = self.record(room, message)?;
+        let mut log = self.open_log(&self.log(room))?;
+        // A record cut short by a crash would otherwise hide every record
+        // after it.
+        let complete = complete_len(&log)?;
+        if complete < log.metadata()?.len() {
+            warn!("Dropping an incomplete record from a log");
+            log.set_len(complete)?;
+        }
+        // One write per record, so a crash tears at most the last one.
+        log.write_all(&record)
+    }
+
+    /// Add `messages` to the log of `room` in the order they were sent,
+    /// among those it already holds. The log is rewritten to a temporary
+    /// file and moved in place, so a crash leaves the old one whole.
+    pub fn merge(&self, room: &str, messages: Vec<Message>) -> io::Result<()> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let path = self.log(room);
+        let _log = self.open_log(&path)?;
+        let mut merged: Vec<Message> = self
+            .read(&path)?
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect();
+        merged.extend(messages);
+        // Stable, so messages sent in the same millisecond keep their order.
+        merged.sort_by_key(Message::timestamp);
+
+        let mut records = Vec::new();
+        for message in &merged {
+            records.extend(self.record(room, message)?);
+        }
+        let temp = path.with_extension("log.tmp");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp)?;
+        file.write_all(&records)?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)
+    }
+
+    /// `message` encrypted into a record of the log of `room`.
+    fn record(&self, room: &str, message: &Message) -> io::Result<Vec<u8>> {
+        let entry = Entry {
+            room: room.to_owned(),
+            message: message.clone(),
+        };
+        let plaintext = postcard::to_stdvec(&entry)
+            .map(Plaintext::from)
+            .map_err(io::Error::other)?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext.expose())
+            .map_err(|_| io::Error::other("encryption failed"))?;
+
+        let len = (NONCE_LEN + ciphertext.len()) as u32;
+        let mut record = Vec::with_capacity(4 + len as usize);
+        record.extend_from_slice(&len.to_be_bytes());
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&ciphertext);
+        Ok(record)
+    }
+
+    /// The log at `path`, created if need be and locked against the other
+    /// clients of the same profile until it is dropped.
+    fn open_log(&self, path: &Path) -> io::Result<fs::File> {
+        use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+
+        loop {
+            let log = fs::OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .mode(0o600)
+                .open(path)?;
+            log.lock()?;
+            // A merge may have moved a new log in place while we waited.
+            if log.metadata()?.ino() == fs::metadata(path)?.ino() {
+                return Ok(log);
+            }
+        }
+    }
+
+    /// Every message kept for `room`, oldest first.
+    pub fn load(&self, room: &str) -> io::Result<Vec<Message>> {
+        let entries = self.read(&self.log(room))?;
+        Ok(entries.into_iter().map(|entry| entry.message).collect())
+    }
+
+    /// Every room with a log and its messages, oldest first.
+    pub fn load_all(&self) -> io::Result<Vec<(String, Vec<Message>)>> {
+        let mut logs = Vec::new();
+        for file in fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            if path.extension().is_some_and(|extension| extension == "log") {
+                logs.push(path);
+            }
+        }
+        logs.sort();
+
+        let mut rooms = Vec::new();
+        for path in logs {
+            let entries = self.read(&path)?;
+            if let Some(first) = entries.first() {
+                let room = first.room.clone();
+                rooms.push((
+                    room,
+                    entries.into_iter().map(|entry| entry.message).collect(),
+                ));
+            }
+        }
+        Ok(rooms)
+    }
+
+    /// The entries of the log at `path`, none if there is no such log.
+    fn read(&self, path: &Path) -> io::Result<Vec<Entry>> {
+        let log = match fs::read(path) {
+            Ok(log) => log,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = Vec::new();
+        let mut rest = &log[..];
+        while !rest.is_empty() {
+            let record = rest.get(4..).and_then(|tail| {
+                let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+                tail.get(..len).filter(|_| len > NONCE_LEN)
+            });
+            // A record cut short by a crash ends the log.
+            let Some(record) = record else {
+                warn!("{} ends in an incomplete record", path.display());
+                break;
+            };
+            rest = &rest[4 + record.len()..];
+
+            let (nonce, ciphertext) = record.split_at(NONCE_LEN);
+            let plaintext = self
+                .cipher
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .map(Plaintext::from)
+                .map_err(|_| invalid(format!("{} is damaged", path.display())))?;
+            let entry: Entry = postcard::from_bytes(plaintext.expose())
+                .map_err(|_| invalid(format!("{} is damaged", path.display())))?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    fn log(&self, room: &str) -> PathBuf {
+        let digest = Sha256::digest(room.as_bytes());
+        let name: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.dir.join(name + ".log")
+    }
+}
+
+/// The history of the room being chatted in, shared by the tasks that
+/// send and receive.
+#[derive(Clone)]
+pub struct RoomHistory {
+    history: Arc<History>,
+    room: String,
+}
+
+impl RoomHistory {
+    pub fn new(history: History, room: &str) -> RoomHistory {
+        RoomHistory {
+            history: Arc::new(history),
+            room: room.to_owned(),
+        }
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    /// Keep `message`. A failure is logged rather than interrupting the
+    /// chat.
+    pub fn append(&self, message: &Message) {
+        if let Err(e) = self.history.append(&self.room, message) {
+            error!("Failed to keep a message in the history: {}", e);
+        }
+    }
+}
+
+/// How far back the user has read.
+#[derive(Default)]
+pub struct Scrollback {
+    /// The oldest message shown so far, looked up by its id since an import
+    /// can merge messages in before it; `Some(None)` if the log was empty.
+    oldest: Option<Option<MessageId>>,
+}
+
+impl Scrollback {
+    /// Up to `n` messages before the oldest one shown so far, oldest first;
+    /// the latest ones the first time.
+    pub fn back(&mut self, room: &RoomHistory, n: usize) -> io::Result<Vec<Message>> {
+        let mut messages = room.history.load(&room.room)?;
+        let end = match self.oldest {
+            None => messages.len(),
+            Some(None) => 0,
+            Some(Some(oldest)) => messages
+                .iter()
+                .position(|message| message.id() == oldest)
+                .unwrap_or(0),
+        };
+        messages.truncate(end);
+        let earlier = messages.split_off(end.saturating_sub(n));
+        match earlier.first() {
+            Some(first) => self.oldest = Some(Some(first.id())),
+            None => self.oldest = Some(self.oldest.flatten()),
+        }
+        Ok(earlier)
+    }
+}
+
+/// Encrypt `plaintext` with `passphrase` on its own, for what leaves the
+/// history such as exported archives: a key file as `create` makes it, a
+/// nonce and the ciphertext.
+pub fn seal(plaintext: &Plaintext, passphrase: &str) -> io::Result<Vec<u8>> {
+    seal_with(plaintext, passphrase, Params::default())
+}
+
+fn seal_with(plaintext: &Plaintext, passphrase: &str, params: Params) -> io::Result<Vec<u8>> {
+    let (mut sealed, cipher) = create(passphrase, params)?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext.expose())
+        .map_err(|_| io::Error::other("encryption failed"))?;
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// What `seal` encrypted. Fails with `PermissionDenied` if the passphrase
+/// is wrong.
+pub fn unseal(sealed: &[u8], passphrase: &str) -> io::Result<Plaintext> {
+    if sealed.len() < KEY_FILE_LEN + NONCE_LEN {
+        return Err(invalid("the archive is too short".to_owned()));
+    }
+    let (key_file, rest) = sealed.split_at(KEY_FILE_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    unlock(key_file, passphrase)?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map(Plaintext::from)
+        .map_err(|_| invalid("the archive is damaged".to_owned()))
+}
+
+/// A new key file for `passphrase` and the cipher it unlocks.
+fn create(passphrase: &str, params: Params) -> io::Result<(Vec<u8>, Aes256Gcm)> {
+    let mut salt = [0; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let cipher = derive(passphrase, &salt, params.clone())?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let check = cipher
+        .encrypt(&nonce, CHECK)
+        .map_err(|_| io::Error::other("encryption failed"))?;
+
+    let mut key_file = Vec::new();
+    for cost in [params.m_cost(), params.t_cost(), params.p_cost()] {
+        key_file.extend_from_slice(&cost.to_be_bytes());
+    }
+    key_file.extend_from_slice(&salt);
+    key_file.extend_from_slice(&nonce);
+    key_file.extend_from_slice(&check);
+    Ok((key_file, cipher))
+}
+
+/// The cipher a key file made by `create` and `passphrase` give.
+fn unlock(key_file: &[u8], passphrase: &str) -> io::Result<Aes256Gcm> {
+    if key_file.len() <= 12 + SALT_LEN + NONCE_LEN {
+        return Err(invalid("the key file is too short".to_owned()));
+    }
+    let cost = |i: usize| u32::from_be_bytes(key_file[i * 4..i * 4 + 4].try_into().unwrap());
+    if (0..3).any(|i| cost(i) > MAX_COSTS[i]) {
+        return Err(invalid(
+            "the key file asks for a costlier key derivation than allowed".to_owned(),
+        ));
+    }
+    let params = Params::new(cost(0), cost(1), cost(2), Some(32))
+        .map_err(|e| invalid(format!("the key file is invalid: {}", e)))?;
+    let (salt, rest) = key_file[12..].split_at(SALT_LEN);
+    let (nonce, check) = rest.split_at(NONCE_LEN);
+
+    let cipher = derive(passphrase, salt, params)?;
+    match cipher.decrypt(Nonce::from_slice(nonce), check) {
+        Ok(check) if check == CHECK => Ok(cipher),
+        _ => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "wrong passphrase",
+        )),
+    }
+}
+
+fn derive(passphrase: &str, salt: &[u8], params: Params) -> io::Result<Aes256Gcm> {
+    let mut key = Zeroizing::new([0; 32]);
+    Argon2::new(Algorithm::Argon2id, argon2::Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(Aes256Gcm::new(&(*key).into()))
+}
+
+/// How much of `log` is whole records.
+fn complete_len(log: &fs::File) -> io::Result<u64> {
+    use std::os::unix::fs::FileExt;
+
+    let len = log.metadata()?.len();
+    let mut end = 0;
+    let mut header = [0; 4];
+    while end + 4 <= len {
+        log.read_exact_at(&mut header, end)?;
+        let record = u32::from_be_bytes(header) as u64;
+        if record <= NONCE_LEN as u64 || end + 4 + record > len {
+            break;
+        }
+        end += 4 + record;
+    }
+    Ok(end)
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Kind;
+
+    /// Cheap enough for debug builds.
+    fn params() -> Params {
+        Params::new(256, 1, 1, Some(32)).unwrap()
+    }
+
+    #[test]
+    fn persists_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let history = History::open_with(&path, "hunter2", params()).unwrap();
+        let messages: Vec<Message> = (0..3)
+            .map(|i| Message::new("alice", Kind::Text(format!("secret {}", i))))
+            .collect();
+        for message in &messages {
+            history.append("lobby", message).unwrap();
+        }
+        history
+            .append("elsewhere", &Message::new("bob", Kind::Text("hi".into())))
+            .unwrap();
+
+        let history = History::open_with(&path, "hunter2", params()).unwrap();
+        assert!(history.load("lobby").unwrap() == messages);
+        assert_eq!(history.load("elsewhere").unwrap().len(), 1);
+        assert!(history.load("empty").unwrap().is_empty());
+        let rooms = history.load_all().unwrap();
+        assert_eq!(rooms.len(), 2);
+        assert!(rooms.contains(&("lobby".to_owned(), messages.clone())));
+
+        for file in fs::read_dir(&path).unwrap() {
+            let contents = fs::read(file.unwrap().path()).unwrap();
+            let contents = String::from_utf8_lossy(&contents);
+            assert!(!contents.contains("secret") && !contents.contains("lobby"));
+        }
+
+        let err = History::open_with(&path, "hunter3", params())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn scrolls_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open_with(dir.path(), "pass", params()).unwrap();
+        let room = RoomHistory::new(history, "lobby");
+        let mut joined_empty = Scrollback::default();
+        assert!(joined_empty.back(&room, 2).unwrap().is_empty());
+        let messages: Vec<Message> = (0..5)
+            .map(|i| Message::new("alice", Kind::Text(i.to_string())))
+            .collect();
+        for message in &messages {
+            room.append(message);
+        }
+
+        let mut scrollback = Scrollback::default();
+        assert!(scrollback.back(&room, 2).unwrap() == messages[3..]);
+        // Newer messages do not get in the way of scrolling further back.
+        room.append(&Message::new("bob", Kind::Text("new".into())));
+        assert!(scrollback.back(&room, 2).unwrap() == messages[1..3]);
+        assert!(scrollback.back(&room, 2).unwrap() == messages[..1]);
+        assert!(scrollback.back(&room, 2).unwrap().is_empty());
+        // Everything arrived after joining an empty room, so was seen.
+        assert!(joined_empty.back(&room, 2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn scrolls_back_past_imports() {
+        use chrono::{TimeZone, Utc};
+
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open_with(dir.path(), "pass", params()).unwrap();
+        let at = |second: u32| {
+            Message::from_parts(
+                MessageId::new(),
+                "alice",
+                Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, second).unwrap(),
+                Kind::Text(second.to_string()),
+                None,
+            )
+        };
+        let room = RoomHistory::new(history, "lobby");
+        for second in [0, 2, 4] {
+            room.append(&at(second));
+        }
+        let mut scrollback = Scrollback::default();
+        assert_eq!(scrollback.back(&room, 1).unwrap()[0].text(), Some("4"));
+
+        // Messages imported before the oldest one shown come next, and
+        // those after it are not shown again.
+        room.history.merge("lobby", vec![at(1), at(3)]).unwrap();
+        let texts: Vec<_> = scrollback
+            .back(&room, 3)
+            .unwrap()
+            .iter()
+            .map(|message| message.text().unwrap().to_owned())
+            .collect();
+        assert_eq!(texts, ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn seals() {
+        let plaintext = Plaintext::from("an archive".to_owned());
+        let sealed = seal_with(&plaintext, "pass", params()).unwrap();
+        assert!(!sealed.windows(7).any(|window| window == b"archive"));
+        assert_eq!(unseal(&sealed, "pass").unwrap().expose(), b"an archive");
+        let wrong = unseal(&sealed, "wrong").unwrap_err();
+        assert_eq!(wrong.kind(), io::ErrorKind::PermissionDenied);
+        assert!(unseal(&sealed[..sealed.len() - 1], "pass").is_err());
+
+        // Each cost is checked before any memory is spent on it.
+        for i in 0..3 {
+            let mut costly = sealed.clone();
+            costly[i * 4..i * 4 + 4].copy_from_slice(&(MAX_COSTS[i] + 1).to_be_bytes());
+            let err = unseal(&costly, "pass").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        let default = Params::default();
+        assert!(default.m_cost() <= MAX_COSTS[0] && default.t_cost() <= MAX_COSTS[1]);
+    }
+
+    #[test]
+    fn merges_in_order() {
+        use chrono::{TimeZone, Utc};
+
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open_with(dir.path(), "pass", params()).unwrap();
+        let at = |second: u32, text: &str| {
+            Message::from_parts(
+                MessageId::new(),
+                "alice",
+                Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, second).unwrap(),
+                Kind::Text(text.to_owned()),
+                None,
+            )
+        };
+        history.append("lobby", &at(1, "one")).unwrap();
+        history.append("lobby", &at(4, "four")).unwrap();
+        history
+            .merge("lobby", vec![at(3, "three"), at(0, "zero"), at(5, "five")])
+            .unwrap();
+
+        let texts: Vec<_> = history
+            .load("lobby")
+            .unwrap()
+            .iter()
+            .map(|message| message.text().unwrap().to_owned())
+            .collect();
+        assert_eq!(texts, ["zero", "one", "three", "four", "five"]);
+        // Appending afterwards goes to the merged log.
+        history.append("lobby", &at(6, "six")).unwrap();
+        assert_eq!(history.load("lobby").unwrap().len(), 6);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn survives_a_torn_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open_with(dir.path(), "pass", params()).unwrap();
+        let message = Message::new("alice", Kind::Text("kept".into()));
+        history.append("lobby", &message).unwrap();
+        history.append("lobby", &message).unwrap();
+
+        let log = history.log("lobby");
+        let len = fs::metadata(&log).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&log)
+            .unwrap()
+            .set_len(len - 5)
+            .unwrap();
+        assert_eq!(history.load("lobby").unwrap().len(), 1);
+
+        // What is appended next goes after the last complete record.
+        history.append("lobby", &message).unwrap();
+        assert_eq!(fs::metadata(&log).unwrap().len(), len);
+        let history = History::open_with(dir.path(), "pass", params()).unwrap();
+        assert!(history.load("lobby").unwrap() == [message.clone(), message]);
+    }
+}
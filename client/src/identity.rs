@@ -0,0 +1,252 @@
+//! A long-term identity key that signs the ephemeral key of every session.
+//!
+//! The ephemeral key exchange on its own tells a client nothing about who is
+//! on the other end. With an identity, each side announces its ephemeral key
+//! together with its identity key and a signature over the ephemeral key, and
+//! users can compare fingerprints out of band.
+//!
+//! The key may be followed by a few bytes of session parameters, see
+//! `protocol`, which the signature covers too.
+//!
+//! The same key signs us in to the server, which binds an account to the
+//! first key it is used with.
+
+use std::{fs, io, path::Path};
+
+use p256::{
+    ecdsa::{signature::Signer, signature::Verifier, Signature, SigningKey, VerifyingKey},
+    pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding},
+    EncodedPoint, PublicKey,
+};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+
+/// Length of an uncompressed SEC1 ephemeral public key.
+const EPHEMERAL_LEN: usize = 65;
+/// Length of a compressed SEC1 identity key.
+const IDENTITY_LEN: usize = 33;
+/// Length of a fixed size ECDSA signature.
+const SIGNATURE_LEN: usize = 64;
+/// Most bytes of session parameters an announcement may end with.
+const MAX_PARAMETERS_LEN: usize = 16;
+/// Keeps signatures over a `Hello` from being mistaken for any other
+/// signature made with the same key. The server checks for the same.
+const HELLO_CONTEXT: &[u8] = b"chat hello\0";
+
+/// A user's signing key.
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Identity {
+        Identity {
+            key: SigningKey::random(&mut OsRng),
+        }
+    }
+
+    /// Read a PKCS#8 PEM encoded key.
+    pub fn load(path: &Path) -> io::Result<Identity> {
+        let pem = fs::read_to_string(path)?;
+        let key = SigningKey::from_pkcs8_pem(&pem).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })?;
+        Ok(Identity { key })
+    }
+
+    /// Write the key as PKCS#8 PEM, readable only by the current user.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let pem = self
+            .key
+            .to_pkcs8_pem(LineEnding::LF)
+            .map_err(io::Error::other)?;
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(pem.as_bytes())
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(self.key.verifying_key())
+    }
+
+    /// The payload announcing `ephemeral` and `parameters` to the peer,
+    /// signed with this identity.
+    pub fn announce(&self, ephemeral: &EncodedPoint, parameters: &[u8]) -> Vec<u8> {
+        let signed = [ephemeral.as_bytes(), parameters].concat();
+        let signature: Signature = self.key.sign(&signed);
+        let identity = self.key.verifying_key().to_encoded_point(true);
+
+        let mut payload =
+            Vec::with_capacity(EPHEMERAL_LEN + IDENTITY_LEN + SIGNATURE_LEN + parameters.len());
+        payload.extend_from_slice(ephemeral.as_bytes());
+        payload.extend_from_slice(identity.as_bytes());
+        payload.extend_from_slice(&signature.to_bytes());
+        payload.extend_from_slice(parameters);
+        payload
+    }
+
+    /// The proof for a `Hello` joining `room` as `account`: our identity key
+    /// and a signature over the server's `challenge` and both names, each
+    /// preceded by its length.
+    pub fn prove(&self, challenge: &[u8], account: &str, room: &str) -> Vec<u8> {
+        let mut signed = Vec::with_capacity(HELLO_CONTEXT.len() + challenge.len() + 2 + 255 * 2);
+        signed.extend_from_slice(HELLO_CONTEXT);
+        signed.extend_from_slice(challenge);
+        signed.push(account.len() as u8);
+        signed.extend_from_slice(account.as_bytes());
+        signed.push(room.len() as u8);
+        signed.extend_from_slice(room.as_bytes());
+
+        let signature: Signature = self.key.sign(&signed);
+        let identity = self.key.verifying_key().to_encoded_point(true);
+        [identity.as_bytes(), &signature.to_bytes()[..]].concat()
+    }
+}
+
+/// The payload announcing `ephemeral` and `parameters` without an identity.
+pub fn announce_anonymously(ephemeral: &EncodedPoint, parameters: &[u8]) -> Vec<u8> {
+    [ephemeral.as_bytes(), parameters].concat()
+}
+
+/// A peer's announced ephemeral key and, if it has one, its identity.
+pub struct Announcement {
+    pub ephemeral: PublicKey,
+    pub identity: Option<VerifyingKey>,
+    /// Empty if the peer sent none.
+    pub parameters: Vec<u8>,
+}
+
+/// Parse a key exchange payload, either a bare ephemeral key or one made by
+/// `Identity::announce`, each optionally followed by session parameters.
+/// Fails if the signature does not match.
+pub fn parse_announcement(payload: &[u8]) -> io::Result<Announcement> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+
+    // Parameters are too short to be mistaken for an identity and signature.
+    let signed_len = EPHEMERAL_LEN + IDENTITY_LEN + SIGNATURE_LEN;
+    let signed = match payload.len() {
+        len if (EPHEMERAL_LEN..=EPHEMERAL_LEN + MAX_PARAMETERS_LEN).contains(&len) => false,
+        len if (signed_len..=signed_len + MAX_PARAMETERS_LEN).contains(&len) => true,
+        _ => return Err(invalid("the peer's key exchange has an unexpected length")),
+    };
+    let (ephemeral, rest) = payload.split_at(EPHEMERAL_LEN);
+    let ephemeral_key = PublicKey::from_sec1_bytes(ephemeral)
+        .map_err(|_| invalid("the peer's ephemeral key is invalid"))?;
+    if !signed {
+        return Ok(Announcement {
+            ephemeral: ephemeral_key,
+            identity: None,
+            parameters: rest.to_vec(),
+        });
+    }
+
+    let (identity, rest) = rest.split_at(IDENTITY_LEN);
+    let (signature, parameters) = rest.split_at(SIGNATURE_LEN);
+    let identity = VerifyingKey::from_sec1_bytes(identity)
+        .map_err(|_| invalid("the peer's identity key is invalid"))?;
+    let signature =
+        Signature::from_slice(signature).map_err(|_| invalid("the peer's signature is invalid"))?;
+    identity
+        .verify(&[ephemeral, parameters].concat(), &signature)
+        .map_err(|_| invalid("the peer's ephemeral key is not signed by its identity"))?;
+
+    Ok(Announcement {
+        ephemeral: ephemeral_key,
+        identity: Some(identity),
+        parameters: parameters.to_vec(),
+    })
+}
+
+/// The SHA-256 of an identity key in colon separated hex, to be compared
+/// out of band.
+pub fn fingerprint(key: &VerifyingKey) -> String {
+    let digest = Sha256::digest(key.to_encoded_point(true).as_bytes());
+    let hex: Vec<String> = digest.iter().map(|byte| format!("{:02X}", byte)).collect();
+    hex.join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdh::EphemeralSecret;
+
+    fn ephemeral() -> EncodedPoint {
+        EncodedPoint::from(EphemeralSecret::random(&mut OsRng).public_key())
+    }
+
+    #[test]
+    fn signed_announcement() {
+        let identity = Identity::generate();
+        let ephemeral = ephemeral();
+
+        let announcement = parse_announcement(&identity.announce(&ephemeral, &[])).unwrap();
+        assert_eq!(EncodedPoint::from(announcement.ephemeral), ephemeral);
+        assert_eq!(
+            fingerprint(&announcement.identity.unwrap()),
+            identity.fingerprint()
+        );
+        assert!(announcement.parameters.is_empty());
+
+        let anonymous = parse_announcement(ephemeral.as_bytes()).unwrap();
+        assert!(anonymous.identity.is_none());
+
+        let anonymous = parse_announcement(&announce_anonymously(&ephemeral, &[2])).unwrap();
+        assert_eq!(anonymous.parameters, [2]);
+        let signed = parse_announcement(&identity.announce(&ephemeral, &[2])).unwrap();
+        assert_eq!(signed.parameters, [2]);
+
+        // Someone else's ephemeral key under this identity's signature.
+        let mut forged = identity.announce(&ephemeral, &[]);
+        forged[..EPHEMERAL_LEN].copy_from_slice(self::ephemeral().as_bytes());
+        assert!(parse_announcement(&forged).is_err());
+
+        // Parameters changed on the way, e.g. to downgrade the protocol.
+        let mut downgraded = identity.announce(&ephemeral, &[2]);
+        *downgraded.last_mut().unwrap() = 1;
+        assert!(parse_announcement(&downgraded).is_err());
+    }
+
+    #[test]
+    fn hello_proof() {
+        let identity = Identity::generate();
+        let challenge = [1; 32];
+        let proof = identity.prove(&challenge, "alice", "rust");
+        assert_eq!(proof.len(), IDENTITY_LEN + SIGNATURE_LEN);
+
+        // The server checks the signature over exactly these bytes.
+        let signed = [&b"chat hello\0"[..], &challenge, b"\x05alice\x04rust"].concat();
+        let (key, signature) = proof.split_at(IDENTITY_LEN);
+        let key = VerifyingKey::from_sec1_bytes(key).unwrap();
+        assert_eq!(fingerprint(&key), identity.fingerprint());
+        key.verify(&signed, &Signature::from_slice(signature).unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn save_and_load() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity.pem");
+        let identity = Identity::generate();
+        identity.save(&path).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            Identity::load(&path).unwrap().fingerprint(),
+            identity.fingerprint()
+        );
+        // An existing key is never overwritten.
+        assert!(Identity::generate().save(&path).is_err());
+    }
+}
@@ -1,13 +1,46 @@
+use std::io::{Read, Write};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::{ExitCode, Termination};
+use std::time::Duration;
 
+use clap::{Parser, ValueEnum};
 use dotenvy::dotenv;
-use tracing::Level;
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace, warn};
+use tracing::{level_filters::LevelFilter, Level};
+use tracing_appender::{non_blocking::WorkerGuard, rolling};
+use tracing_subscriber::{
+    fmt::{self, writer::BoxMakeWriter},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
 
+use zeroize::Zeroizing;
+
+use cli::{Cli, Command, Connect, ExportFormat, LogFormat};
+use export::Archive;
+use handle_connection::{MyError, SessionOptions};
+use history::{History, RoomHistory};
+use message::{Kind, Message};
+use profile::Profile;
+use secret::Plaintext;
+
+mod cli;
+mod export;
+mod frame;
 mod handle_connection;
+mod history;
+mod identity;
 mod message;
+mod presence;
+mod profile;
+mod protocol;
+mod search;
+mod secret;
+mod tls;
+mod transfer;
 
 #[repr(u8)]
 pub enum GitBisectResult {
@@ -26,19 +59,382 @@ impl Termination for GitBisectResult {
 
 #[tokio::main]
 async fn main() -> GitBisectResult {
-    dotenv().expect(".env file not found");
+    // `.env` is optional, the same variables can come from the environment.
+    let _ = dotenv();
+    let cli = Cli::parse();
+
+    let _log_guard = match init_logging(&cli) {
+        Ok(guard) => guard,
+        Err(err) => {
+            eprintln!("{}", err);
+            return GitBisectResult::Bad;
+        }
+    };
 
-    let tracing_level = &std::env::var("DEBUG_LEVEL").unwrap_or("INFO".to_string());
-    tracing_subscriber::fmt()
-        .with_max_level(
-            <Level as std::str::FromStr>::from_str(tracing_level).unwrap_or(Level::INFO),
-        )
+    let profile = match Profile::open(&cli.profile) {
+        Ok(profile) => profile,
+        Err(err) => {
+            error!("Failed to open profile `{}`: {}", cli.profile, err);
+            return GitBisectResult::Bad;
+        }
+    };
+
+    let result = match cli.command.unwrap_or(Command::Connect(Connect::default())) {
+        Command::Connect(connect) => chat(&profile, connect).await,
+        Command::Send {
+            connect,
+            timeout,
+            message,
+        } => send(&profile, connect, Duration::from_secs(timeout), message).await,
+        Command::Keygen => match profile.generate_identity() {
+            Ok(identity) => {
+                println!("{}", identity.fingerprint());
+                Ok(())
+            }
+            Err(err) => Err(format!(
+                "Failed to write {}: {}",
+                profile.identity_path().display(),
+                err
+            )),
+        },
+        Command::Fingerprint => match profile.identity() {
+            Ok(Some(identity)) => {
+                println!("{}", identity.fingerprint());
+                Ok(())
+            }
+            Ok(None) => Err(format!(
+                "Profile `{}` has no identity key, create one with `client keygen`",
+                cli.profile
+            )),
+            Err(err) => Err(err.to_string()),
+        },
+        Command::ExportHistory {
+            room,
+            format,
+            encrypt,
+            output,
+        } => export_history(&profile, room, format, encrypt, output),
+        Command::ImportHistory { file } => import_history(&profile, &file),
+    };
+
+    match result {
+        Ok(()) => GitBisectResult::Good,
+        Err(err) => {
+            error!("{}", err);
+            GitBisectResult::Bad
+        }
+    }
+}
+
+/// Install the log subscriber. `RUST_LOG` takes per-module directives on
+/// top of the `DEBUG_LEVEL` everything else is logged at. The returned
+/// guard flushes the log file, if there is one, when it is dropped.
+fn init_logging(cli: &Cli) -> Result<Option<WorkerGuard>, String> {
+    let level = std::env::var("DEBUG_LEVEL")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(Level::INFO);
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::from_level(level).into())
+        .parse(std::env::var("RUST_LOG").unwrap_or_default())
+        .map_err(|err| format!("RUST_LOG: {}", err))?;
+
+    let format = match (cli.log_format, std::env::var("LOG_FORMAT")) {
+        (Some(format), _) => format,
+        (None, Ok(format)) => LogFormat::from_str(&format, true)
+            .map_err(|_| format!("LOG_FORMAT: unknown log format `{}`", format))?,
+        (None, Err(_)) => LogFormat::default(),
+    };
+    let dir = cli
+        .log_dir
+        .clone()
+        .or_else(|| std::env::var_os("LOG_DIR").map(PathBuf::from));
+
+    let (writer, guard) = match &dir {
+        Some(dir) => {
+            let appender = rolling::RollingFileAppender::builder()
+                .rotation(rolling::Rotation::DAILY)
+                .filename_prefix("client.log")
+                .build(dir)
+                .map_err(|err| format!("Failed to open the log file: {}", err))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+    let layer = fmt::layer().with_writer(writer).with_ansi(dir.is_none());
+    let layer = match format {
+        LogFormat::Full => layer.boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
         .init();
+    Ok(guard)
+}
+
+/// Chat interactively.
+async fn chat(profile: &Profile, connect: Connect) -> Result<(), String> {
+    let (addr, tls) = server(profile, &connect)?;
+    let options = session_options(profile, &connect)?;
+    let history = history(profile, &connect, true)?;
+    let username = connect.user.or(profile.settings.user.clone());
+    let room = room(profile, connect.room);
+    let history = history.map(|history| RoomHistory::new(history, &room));
+
+    let downloads = profile.downloads_dir();
+
+    match handle_connection::handle_connection(
+        &addr, tls, username, &room, options, history, downloads,
+    )
+    .await
+    {
+        Ok(_) | Err(MyError::Quit) => Ok(()),
+        Err(MyError::Io(err)) => Err(format!("Connection failed: {}", err)),
+    }
+}
+
+/// Send a single message, from the command line or stdin.
+async fn send(
+    profile: &Profile,
+    connect: Connect,
+    wait: Duration,
+    message: Option<String>,
+) -> Result<(), String> {
+    let (addr, tls) = server(profile, &connect)?;
+    let options = session_options(profile, &connect)?;
+    let history = history(profile, &connect, false)?;
+    let username = connect
+        .user
+        .or(profile.settings.user.clone())
+        .ok_or("A username is needed, pass --user or set `user` in the profile")?;
+    let room = room(profile, connect.room);
+    let text = match message {
+        Some(message) => message,
+        None => {
+            let mut message = String::new();
+            std::io::stdin()
+                .read_to_string(&mut message)
+                .map_err(|err| format!("Failed to read the message: {}", err))?;
+            message.trim_end().to_owned()
+        }
+    };
+    let message = Message::new(&username, Kind::Text(text));
+
+    match handle_connection::send_message(&addr, tls, &username, &room, options, &message, wait)
+        .await
+    {
+        Ok(_) => {
+            if let Some(history) = history {
+                history
+                    .append(&room, &message)
+                    .map_err(|err| format!("Failed to keep the message: {}", err))?;
+            }
+            Ok(())
+        }
+        Err(MyError::Quit) => Err("Disconnected before the message was sent".to_owned()),
+        Err(MyError::Io(err)) => Err(format!("Sending failed: {}", err)),
+    }
+}
+
+/// The profile's identity and the padding to ask for. The flag overrides
+/// the environment, which overrides the profile.
+fn session_options(profile: &Profile, connect: &Connect) -> Result<SessionOptions, String> {
+    let identity = profile.identity().map_err(|err| err.to_string())?;
+    let padding = match (connect.padding, std::env::var("PADDING")) {
+        (Some(padding), _) => padding,
+        (None, Ok(padding)) => padding.parse().map_err(|err| format!("PADDING: {}", err))?,
+        (None, Err(_)) => profile.settings.padding.unwrap_or_default(),
+    };
+    Ok(SessionOptions {
+        identity,
+        padding,
+        typing: !connect.no_typing && profile.settings.typing != Some(false),
+        receipts: !connect.no_receipts && profile.settings.receipts != Some(false),
+    })
+}
+
+/// The profile's history, unless it is turned off with `--no-history` or
+/// `history = false`.
+fn history(
+    profile: &Profile,
+    connect: &Connect,
+    interactive: bool,
+) -> Result<Option<History>, String> {
+    if connect.no_history || profile.settings.history == Some(false) {
+        return Ok(None);
+    }
+    open_history(profile, interactive)
+}
+
+/// The profile's history. The passphrase comes from `HISTORY_PASSPHRASE`,
+/// or is asked for when `interactive`; an empty one goes without history.
+fn open_history(profile: &Profile, interactive: bool) -> Result<Option<History>, String> {
+    let passphrase = match std::env::var("HISTORY_PASSPHRASE") {
+        Ok(passphrase) => Zeroizing::new(passphrase),
+        Err(_) if interactive => rpassword::prompt_password("History passphrase (empty to skip): ")
+            .map(Zeroizing::new)
+            .map_err(|err| format!("Failed to read the passphrase: {}", err))?,
+        Err(_) => return Ok(None),
+    };
+    if passphrase.is_empty() {
+        return Ok(None);
+    }
+    History::open(&profile.history_dir(), &passphrase)
+        .map(Some)
+        .map_err(|err| format!("Failed to open the history: {}", err))
+}
+
+/// Write the history of a room in `format`, sealed with a passphrase if
+/// `encrypt`, to `output` or stdout.
+fn export_history(
+    profile: &Profile,
+    room: Option<String>,
+    format: ExportFormat,
+    encrypt: bool,
+    output: Option<PathBuf>,
+) -> Result<(), String> {
+    let history = open_history(profile, true)?.ok_or("The history passphrase is needed")?;
+    let room = self::room(profile, room);
+    let messages = history
+        .load(&room)
+        .map_err(|err| format!("Failed to read the history: {}", err))?;
+    let export = Archive::new(&room, messages).export(format);
+
+    let contents = if encrypt {
+        let passphrase = archive_passphrase(true)?;
+        let sealed = history::seal(&export, &passphrase)
+            .map_err(|err| format!("Failed to encrypt the export: {}", err))?;
+        [export::MAGIC, &sealed].concat()
+    } else {
+        export.expose().to_vec()
+    };
+    match output {
+        Some(path) => {
+            use std::os::unix::fs::OpenOptionsExt;
+
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&path)
+                .and_then(|mut file| file.write_all(&contents))
+                .map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+        }
+        None => std::io::stdout()
+            .write_all(&contents)
+            .map_err(|err| format!("Failed to write the export: {}", err)),
+    }
+}
+
+/// Add the messages of the export in `file` that the history does not
+/// hold yet.
+fn import_history(profile: &Profile, file: &Path) -> Result<(), String> {
+    let contents =
+        std::fs::read(file).map_err(|err| format!("Failed to read {}: {}", file.display(), err))?;
+    let json = match contents.strip_prefix(export::MAGIC) {
+        Some(sealed) => history::unseal(sealed, &archive_passphrase(false)?)
+            .map_err(|err| format!("Failed to decrypt {}: {}", file.display(), err))?,
+        None => Plaintext::from(contents),
+    };
+    let archive = Archive::parse(&json).map_err(|err| format!("{}: {}", file.display(), err))?;
+
+    let history = open_history(profile, true)?.ok_or("The history passphrase is needed")?;
+    let room = archive.room.clone();
+    let total = archive.messages.len();
+    let known = history
+        .load(&room)
+        .map_err(|err| format!("Failed to read the history: {}", err))?;
+    let new = archive.new_messages(&known);
+    let imported = new.len();
+    history
+        .merge(&room, new)
+        .map_err(|err| format!("Failed to import: {}", err))?;
+    info!(
+        "Imported {} messages into #{}, {} were already there",
+        imported,
+        room,
+        total - imported
+    );
+    Ok(())
+}
+
+/// The passphrase of an encrypted export, from `ARCHIVE_PASSPHRASE` or
+/// asked for; twice when it is new.
+fn archive_passphrase(new: bool) -> Result<Zeroizing<String>, String> {
+    if let Ok(passphrase) = std::env::var("ARCHIVE_PASSPHRASE") {
+        return Ok(Zeroizing::new(passphrase));
+    }
+    let prompt = |prompt: &str| {
+        rpassword::prompt_password(prompt)
+            .map(Zeroizing::new)
+            .map_err(|err| format!("Failed to read the passphrase: {}", err))
+    };
+    let passphrase = prompt("Archive passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err("The archive passphrase may not be empty".to_owned());
+    }
+    if new && *prompt("Repeat it: ")? != *passphrase {
+        return Err("The passphrases differ".to_owned());
+    }
+    Ok(passphrase)
+}
+
+/// The room to join, from the flag or the profile.
+fn room(profile: &Profile, flag: Option<String>) -> String {
+    flag.or(profile.settings.room.clone())
+        .unwrap_or_else(|| "lobby".to_owned())
+}
+
+/// The server's address and TLS settings. Flags override the environment,
+/// which overrides the profile.
+fn server(profile: &Profile, connect: &Connect) -> Result<(SocketAddr, Option<tls::Tls>), String> {
+    let env = |var: &str| std::env::var(var).ok();
+    let settings = &profile.settings;
 
-    let addr = &std::env::var("ADDRESS").expect("ADDRESS must be set.");
-    let addr = addr.parse::<SocketAddr>().unwrap();
+    let addr = match (connect.server, env("ADDRESS")) {
+        (Some(addr), _) => addr,
+        (None, Some(addr)) => addr
+            .parse::<SocketAddr>()
+            .map_err(|err| format!("ADDRESS: {}", err))?,
+        (None, None) => settings
+            .server
+            .ok_or("No server given, pass --server, set ADDRESS or `server` in the profile")?,
+    };
 
-    handle_connection::handle_connection(&addr).await.unwrap();
+    // TLS is used when it is asked for or when there is a certificate to
+    // trust. A pinned certificate takes precedence over a custom CA.
+    let pin = connect
+        .tls_pin
+        .clone()
+        .or_else(|| env("TLS_PIN"))
+        .or(settings.tls.pin.clone());
+    let ca = connect
+        .tls_ca
+        .clone()
+        .or_else(|| env("TLS_CA").map(PathBuf::from))
+        .or(settings.tls.ca.clone());
+    let server_name = connect
+        .tls_server_name
+        .clone()
+        .or_else(|| env("TLS_SERVER_NAME"))
+        .or(settings.tls.server_name.clone());
+    let use_tls =
+        connect.tls || matches!(env("TLS").as_deref(), Some("1" | "true")) || settings.tls.enabled;
+    let trust = match (&pin, &ca) {
+        (Some(pin), _) => Some(tls::Trust::Pin(pin)),
+        (None, Some(ca)) => Some(tls::Trust::Ca(ca)),
+        (None, None) if use_tls => Some(tls::Trust::WebPki),
+        (None, None) => None,
+    };
+    let tls = trust
+        .map(|trust| tls::Tls::new(trust, server_name.as_deref(), addr.ip()))
+        .transpose()
+        .map_err(|err| format!("Invalid TLS configuration: {}", err))?;
 
-    GitBisectResult::Good
+    Ok((addr, tls))
 }
@@ -1,33 +1,291 @@
+use std::{fmt, str::FromStr};
+
 use chrono::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use ulid::Ulid;
+
+use crate::transfer::FileKey;
+
+/// Identifies a message. ULIDs sort by the time they were made in.
+///
+/// Text formats such as JSON get the usual 26 character string, binary ones
+/// the 16 raw bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageId(Ulid);
+
+impl MessageId {
+    pub fn new() -> Self {
+        MessageId(Ulid::new())
+    }
+
+    pub fn to_bytes(self) -> [u8; 16] {
+        self.0.to_bytes()
+    }
+
+    /// When the id was made, to the millisecond.
+    pub fn timestamp(self) -> DateTime<Utc> {
+        DateTime::from(self.0.datetime())
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for MessageId {
+    type Err = ulid::DecodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(MessageId)
+    }
+}
+
+impl Serialize for MessageId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            self.0.serialize(serializer)
+        } else {
+            self.to_bytes().serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            Ulid::deserialize(deserializer).map(MessageId)
+        } else {
+            let bytes = <[u8; 16]>::deserialize(deserializer)?;
+            Ok(MessageId(Ulid(u128::from_be_bytes(bytes))))
+        }
+    }
+}
+
+/// RFC 3339 in text formats, seconds and nanoseconds since the epoch in
+/// binary ones.
+mod timestamp {
+    use super::*;
 
-#[derive(Serialize, Deserialize, Debug)]
+    pub fn serialize<S: Serializer>(
+        time: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            time.serialize(serializer)
+        } else {
+            (time.timestamp(), time.timestamp_subsec_nanos()).serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        if deserializer.is_human_readable() {
+            DateTime::deserialize(deserializer)
+        } else {
+            let (secs, nanos) = <(i64, u32)>::deserialize(deserializer)?;
+            DateTime::from_timestamp(secs, nanos)
+                .ok_or_else(|| serde::de::Error::custom("timestamp out of range"))
+        }
+    }
+}
+
+/// What the sender encrypts for the room.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Message {
+    id: MessageId,
     sender: String,
-    text: String,
-    timestamp: String,
+    /// When it was sent, in UTC.
+    #[serde(with = "timestamp")]
+    timestamp: DateTime<Utc>,
+    kind: Kind,
+    /// The message this one answers.
+    #[serde(default)]
+    reply_to: Option<MessageId>,
+}
+
+/// What a message is. The variants that act on an earlier message name it
+/// by `target`.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Something said.
+    Text(String),
+    /// Something done, typed as `/me waves`.
+    Action(String),
+    /// Sent by the client itself rather than typed, e.g. a key change.
+    System(String),
+    /// A file mentioned by name and size. See `Offer` for one that can be
+    /// fetched.
+    File { name: String, size: u64 },
+    /// New text for an earlier message.
+    Edit { target: MessageId, text: String },
+    /// Take an earlier message back.
+    Delete { target: MessageId },
+    /// An emoji pinned to an earlier message.
+    Reaction { target: MessageId, emoji: String },
+    /// A file to fetch, with its SHA-256 and the key its chunks are
+    /// encrypted under.
+    Offer {
+        name: String,
+        size: u64,
+        hash: [u8; 32],
+        key: FileKey,
+    },
+    /// Asks for the chunks of an offered `file` from `from` on.
+    Fetch { file: MessageId, from: u64 },
+    /// Part of an offered `file`: a nonce and the chunk encrypted under the
+    /// file's key.
+    Chunk {
+        file: MessageId,
+        index: u64,
+        data: Vec<u8>,
+    },
+    /// The sender started, or stopped, typing.
+    Typing(bool),
+    /// The sender got `target` and every message before it.
+    Delivered { target: MessageId },
+    /// The sender read `target` and every message before it.
+    Read { target: MessageId },
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Text(_) => "text",
+            Kind::Action(_) => "action",
+            Kind::System(_) => "system",
+            Kind::File { .. } => "file",
+            Kind::Edit { .. } => "edit",
+            Kind::Delete { .. } => "delete",
+            Kind::Reaction { .. } => "reaction",
+            Kind::Offer { .. } => "offer",
+            Kind::Fetch { .. } => "fetch",
+            Kind::Chunk { .. } => "chunk",
+            Kind::Typing(_) => "typing",
+            Kind::Delivered { .. } => "delivered",
+            Kind::Read { .. } => "read",
+        }
+    }
 }
 
 impl Message {
-    pub fn new(sender_id: &str, text: &str) -> Self {
-        let timestamp: String = Utc::now().format("%H:%M").to_string();
+    /// A new message from `sender`, stamped with a fresh id and the
+    /// current time.
+    pub fn new(sender: &str, kind: Kind) -> Self {
         Self {
-            sender: sender_id.to_owned(),
-            text: text.to_owned(),
-            timestamp,
+            id: MessageId::new(),
+            sender: sender.to_owned(),
+            timestamp: Utc::now(),
+            kind,
+            reply_to: None,
         }
     }
 
+    pub fn id(&self) -> MessageId {
+        self.id
+    }
+
     pub fn sender(&self) -> &str {
-        self.sender.as_ref()
+        &self.sender
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    pub fn kind(&self) -> &Kind {
+        &self.kind
+    }
+
+    /// The words of the message, for search. Deletions and reactions have
+    /// none of their own.
+    pub fn text(&self) -> Option<&str> {
+        match &self.kind {
+            Kind::Text(text) | Kind::Action(text) | Kind::System(text) => Some(text),
+            Kind::Edit { text, .. } => Some(text),
+            Kind::File { name, .. } | Kind::Offer { name, .. } => Some(name),
+            Kind::Delete { .. } | Kind::Reaction { .. } => None,
+            Kind::Fetch { .. } | Kind::Chunk { .. } => None,
+            Kind::Typing(_) | Kind::Delivered { .. } | Kind::Read { .. } => None,
+        }
+    }
+
+    /// A message with every field given, for fixed test vectors.
+    #[cfg(test)]
+    pub fn from_parts(
+        id: MessageId,
+        sender: &str,
+        timestamp: DateTime<Utc>,
+        kind: Kind,
+        reply_to: Option<MessageId>,
+    ) -> Self {
+        Self {
+            id,
+            sender: sender.to_owned(),
+            timestamp,
+            kind,
+            reply_to,
+        }
     }
 
-    pub fn text(&self) -> &str {
-        self.text.as_ref()
+    /// The line shown for this message, with the time in the local time
+    /// zone. Only the time of day is shown for messages from today.
+    pub fn render(&self) -> String {
+        let local = self.timestamp.with_timezone(&Local);
+        let time = if local.date_naive() == Local::now().date_naive() {
+            local.format("%H:%M").to_string()
+        } else {
+            local.format("%Y-%m-%d %H:%M").to_string()
+        };
+        format!("{}: {}", time, self.describe())
     }
 
-    pub fn timestamp(&self) -> &str {
-        self.timestamp.as_ref()
+    /// The message without its time, e.g. `alice: hello`.
+    pub fn describe(&self) -> String {
+        let reply = match self.reply_to {
+            Some(id) => format!("(re {}) ", id),
+            None => String::new(),
+        };
+        let sender = &self.sender;
+        match &self.kind {
+            Kind::Text(text) => format!("{}: {}{}", sender, reply, text),
+            Kind::Action(text) => format!("* {} {}{}", sender, reply, text),
+            Kind::System(text) => format!("-- {}", text),
+            Kind::File { name, size } => format!("{} offers {} ({} bytes)", sender, name, size),
+            Kind::Edit { target, text } => format!("{} edited {}: {}", sender, target, text),
+            Kind::Delete { target } => format!("{} deleted {}", sender, target),
+            Kind::Reaction { target, emoji } => {
+                format!("{} reacted {} to {}", sender, emoji, target)
+            }
+            Kind::Offer { name, size, .. } => format!("{} sends {} ({} bytes)", sender, name, size),
+            Kind::Fetch { file, from } => {
+                format!("{} fetches {} from chunk {}", sender, file, from)
+            }
+            Kind::Chunk { file, index, .. } => {
+                format!("{} sent chunk {} of {}", sender, index, file)
+            }
+            Kind::Typing(true) => format!("{} is typing", sender),
+            Kind::Typing(false) => format!("{} stopped typing", sender),
+            Kind::Delivered { target } => format!("{} received {}", sender, target),
+            Kind::Read { target } => format!("{} read {}", sender, target),
+        }
+    }
+}
+
+/// Only the kind of message is shown, so a logged message does not give its
+/// contents away.
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Message")
+            .field("id", &self.id)
+            .field("sender", &self.sender)
+            .field("timestamp", &self.timestamp)
+            .field("kind", &format_args!("{}", self.kind.name()))
+            .field("reply_to", &self.reply_to)
+            .finish()
     }
 }
 
@@ -37,21 +295,80 @@ mod tests {
 
     #[test]
     fn serialize() {
-        let sender_id = String::from("asd");
-        let text: String = "some text".into();
-        let timestamp: String = Utc::now().format("%H:%M").to_string();
-
-        let msg_json = format!(
-            r#"{{"sender_id":"{}","text":"{}","timestamp":"{}"}}"#,
-            sender_id, text, timestamp
-        );
+        let id: MessageId = "01ARZ3NDEKTSV4RRFFQ69G5FAV".parse().unwrap();
         let msg = Message {
-            sender: sender_id,
-            text,
-            timestamp,
+            id,
+            sender: String::from("asd"),
+            timestamp: Utc.with_ymd_and_hms(2024, 3, 1, 12, 30, 5).unwrap(),
+            kind: Kind::Text("some text".into()),
+            reply_to: None,
         };
 
-        let serialized = serde_json::to_string(&msg).unwrap();
-        assert_eq!(serialized, msg_json);
+        let msg_json = r#"{"id":"01ARZ3NDEKTSV4RRFFQ69G5FAV","sender":"asd","timestamp":"2024-03-01T12:30:05Z","kind":{"text":"some text"},"reply_to":null}"#;
+        assert_eq!(serde_json::to_string(&msg).unwrap(), msg_json);
+    }
+
+    #[test]
+    fn round_trip() {
+        let target = MessageId::new();
+        for kind in [
+            Kind::Text("hello".into()),
+            Kind::Action("waves".into()),
+            Kind::System("bob changed keys".into()),
+            Kind::File {
+                name: "notes.txt".into(),
+                size: 1234,
+            },
+            Kind::Edit {
+                target,
+                text: "hello, world".into(),
+            },
+            Kind::Delete { target },
+            Kind::Reaction {
+                target,
+                emoji: "👍".into(),
+            },
+            Kind::Offer {
+                name: "notes.txt".into(),
+                size: 1234,
+                hash: [7; 32],
+                key: FileKey::from_bytes([9; 32]),
+            },
+            Kind::Fetch {
+                file: target,
+                from: 3,
+            },
+            Kind::Chunk {
+                file: target,
+                index: 3,
+                data: vec![1, 2, 3],
+            },
+            Kind::Typing(true),
+            Kind::Delivered { target },
+            Kind::Read { target },
+        ] {
+            for msg in [
+                Message::new("alice", kind.clone()),
+                Message {
+                    reply_to: Some(target),
+                    ..Message::new("alice", kind)
+                },
+            ] {
+                let json = serde_json::to_string(&msg).unwrap();
+                let back: Message = serde_json::from_str(&json).unwrap();
+                assert!(back == msg, "{}", json);
+            }
+        }
+    }
+
+    #[test]
+    fn debug_hides_contents() {
+        let msg = Message::new("alice", Kind::Text("a secret".into()));
+        let debug = format!("{:?}", msg);
+        assert!(
+            debug.contains("text") && !debug.contains("secret"),
+            "{}",
+            debug
+        );
     }
 }
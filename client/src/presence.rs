@@ -0,0 +1,164 @@
+//! Typing indicators and receipts, sent to the peer inside the encryption.
+//!
+//! Keystrokes and the messages shown only update `Presence`; every `TICK`
+//! the chat sends whatever is `due`. A receipt names the latest message and
+//! covers the earlier ones too, so however many arrive between two ticks,
+//! one receipt of each kind goes out. Typing is announced at most once per
+//! `TYPING_INTERVAL`.
+
+use std::time::{Duration, Instant};
+
+use crate::message::{Kind, MessageId};
+
+/// How often what is due is sent.
+pub const TICK: Duration = Duration::from_millis(500);
+/// How long after the last key the user counts as having stopped typing.
+const IDLE: Duration = Duration::from_secs(5);
+/// The least time between two announcements that the user is typing.
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+/// What the peer is to be told about the user.
+pub struct Presence {
+    /// Whether to tell the peer when the user is typing.
+    typing: bool,
+    /// Whether to tell the peer what was delivered and read.
+    receipts: bool,
+    last_key: Option<Instant>,
+    /// Whether the peer was told the user is typing, and when.
+    announced: bool,
+    announced_at: Option<Instant>,
+    delivered: Option<MessageId>,
+    /// Shown but not read yet.
+    unread: Option<MessageId>,
+    read: Option<MessageId>,
+}
+
+impl Presence {
+    pub fn new(typing: bool, receipts: bool) -> Presence {
+        Presence {
+            typing,
+            receipts,
+            last_key: None,
+            announced: false,
+            announced_at: None,
+            delivered: None,
+            unread: None,
+            read: None,
+        }
+    }
+
+    /// A key was pressed at the prompt. The user has read what is shown.
+    pub fn key(&mut self, now: Instant) {
+        self.last_key = Some(now);
+        self.mark_read();
+    }
+
+    /// A line was entered. A message tells the peer the user stopped
+    /// typing by itself.
+    pub fn entered(&mut self) {
+        self.last_key = None;
+        self.announced = false;
+        self.mark_read();
+    }
+
+    /// A message from the peer was shown.
+    pub fn shown(&mut self, id: MessageId) {
+        self.delivered = Some(id);
+        self.unread = Some(id);
+    }
+
+    fn mark_read(&mut self) {
+        if let Some(id) = self.unread.take() {
+            self.read = Some(id);
+        }
+    }
+
+    /// What to send the peer now.
+    pub fn due(&mut self, now: Instant) -> Vec<Kind> {
+        let mut due = Vec::new();
+        let (delivered, read) = (self.delivered.take(), self.read.take());
+        if self.receipts {
+            // Reading a message implies it was delivered.
+            if let Some(target) = delivered.filter(|&delivered| Some(delivered) != read) {
+                due.push(Kind::Delivered { target });
+            }
+            if let Some(target) = read {
+                due.push(Kind::Read { target });
+            }
+        }
+
+        if self.typing {
+            let active = self.last_key.is_some_and(|key| now - key < IDLE);
+            let rested = self
+                .announced_at
+                .is_none_or(|at| now - at >= TYPING_INTERVAL);
+            if active && !self.announced && rested {
+                self.announced = true;
+                self.announced_at = Some(now);
+                due.push(Kind::Typing(true));
+            } else if !active && self.announced {
+                self.announced = false;
+                due.push(Kind::Typing(false));
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typing() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut presence = Presence::new(true, false);
+        assert!(presence.due(at(0)).is_empty());
+
+        presence.key(at(0));
+        assert!(presence.due(at(500)) == [Kind::Typing(true)]);
+        presence.key(at(600));
+        assert!(presence.due(at(1000)).is_empty());
+        // Going quiet for a while stops it.
+        assert!(presence.due(at(5700)) == [Kind::Typing(false)]);
+
+        // Entering a line stops it without a word, and starting again right
+        // away waits for the interval.
+        presence.key(at(6000));
+        assert!(presence.due(at(6000)) == [Kind::Typing(true)]);
+        presence.entered();
+        presence.key(at(6500));
+        assert!(presence.due(at(7000)).is_empty());
+        assert!(presence.due(at(9000)) == [Kind::Typing(true)]);
+
+        let mut quiet = Presence::new(false, false);
+        quiet.key(at(0));
+        assert!(quiet.due(at(500)).is_empty());
+    }
+
+    #[test]
+    fn receipts() {
+        let now = Instant::now();
+        let (first, second) = (MessageId::new(), MessageId::new());
+        let mut presence = Presence::new(false, true);
+
+        // Only the latest message is acknowledged.
+        presence.shown(first);
+        presence.shown(second);
+        assert!(presence.due(now) == [Kind::Delivered { target: second }]);
+        assert!(presence.due(now).is_empty());
+        presence.key(now);
+        assert!(presence.due(now) == [Kind::Read { target: second }]);
+
+        // Read before the next tick, only the read receipt goes out.
+        presence.shown(first);
+        presence.entered();
+        assert!(presence.due(now) == [Kind::Read { target: first }]);
+
+        let mut quiet = Presence::new(true, false);
+        quiet.shown(first);
+        quiet.entered();
+        assert!(quiet.due(now).is_empty());
+    }
+}
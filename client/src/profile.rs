@@ -0,0 +1,169 @@
+//! Named profiles, each a directory under `$XDG_CONFIG_HOME/chat` holding
+//! the settings for one server and account and the account's identity key.
+
+use std::{
+    env, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::identity::Identity;
+use crate::protocol::Padding;
+
+/// Settings read from `profile.toml`. Everything is optional; command line
+/// flags and environment variables take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: Option<SocketAddr>,
+    pub user: Option<String>,
+    pub room: Option<String>,
+    pub padding: Option<Padding>,
+    /// Keep an encrypted history; on unless set to `false`.
+    pub history: Option<bool>,
+    /// Tell the peer when we are typing; on unless set to `false`.
+    pub typing: Option<bool>,
+    /// Tell the peer what we got and read; on unless set to `false`.
+    pub receipts: Option<bool>,
+    pub tls: TlsSettings,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    /// Trust the public web PKI.
+    pub enabled: bool,
+    pub ca: Option<PathBuf>,
+    pub pin: Option<String>,
+    pub server_name: Option<String>,
+}
+
+pub struct Profile {
+    dir: PathBuf,
+    pub settings: Settings,
+}
+
+impl Profile {
+    /// Open the profile called `name`. A profile that does not exist yet has
+    /// default settings.
+    pub fn open(name: &str) -> io::Result<Profile> {
+        let base = config_home().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "neither XDG_CONFIG_HOME nor HOME is set",
+            )
+        })?;
+        Profile::open_in(&base.join("chat"), name)
+    }
+
+    fn open_in(base: &Path, name: &str) -> io::Result<Profile> {
+        if name.is_empty() || name.contains(['/', '\0']) || name == "." || name == ".." {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{}` is not a valid profile name", name),
+            ));
+        }
+        let dir = base.join(name);
+
+        let path = dir.join("profile.toml");
+        let settings = match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), e),
+                )
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Settings::default(),
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("{}: {}", path.display(), e),
+                ))
+            }
+        };
+
+        Ok(Profile { dir, settings })
+    }
+
+    /// Where received files are saved.
+    pub fn downloads_dir(&self) -> PathBuf {
+        self.dir.join("downloads")
+    }
+
+    /// Where the encrypted history is kept.
+    pub fn history_dir(&self) -> PathBuf {
+        self.dir.join("history")
+    }
+
+    pub fn identity_path(&self) -> PathBuf {
+        self.dir.join("identity.pem")
+    }
+
+    /// The profile's identity, if `keygen` has been run for it.
+    pub fn identity(&self) -> io::Result<Option<Identity>> {
+        match Identity::load(&self.identity_path()) {
+            Ok(identity) => Ok(Some(identity)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Generate and store a new identity. An existing one is never replaced.
+    pub fn generate_identity(&self) -> io::Result<Identity> {
+        use std::os::unix::fs::DirBuilderExt;
+
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)?;
+        let identity = Identity::generate();
+        identity.save(&self.identity_path())?;
+        Ok(identity)
+    }
+}
+
+/// `$XDG_CONFIG_HOME`, falling back to `~/.config` as the spec says.
+fn config_home() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_and_identity() {
+        let base = tempfile::tempdir().unwrap();
+        let profile = Profile::open_in(base.path(), "work").unwrap();
+        assert!(profile.settings.server.is_none());
+        assert!(profile.identity().unwrap().is_none());
+
+        let identity = profile.generate_identity().unwrap();
+        assert!(profile.generate_identity().is_err());
+        fs::write(
+            base.path().join("work/profile.toml"),
+            "server = \"10.0.0.1:6142\"\nuser = \"alice\"\npadding = \"block:256\"\n[tls]\npin = \"AB:CD\"\n",
+        )
+        .unwrap();
+
+        let profile = Profile::open_in(base.path(), "work").unwrap();
+        assert_eq!(
+            profile.settings.server,
+            Some("10.0.0.1:6142".parse().unwrap())
+        );
+        assert_eq!(profile.settings.user.as_deref(), Some("alice"));
+        assert_eq!(profile.settings.padding, Some(Padding::Block(256)));
+        assert_eq!(profile.settings.tls.pin.as_deref(), Some("AB:CD"));
+        assert_eq!(
+            profile.identity().unwrap().unwrap().fingerprint(),
+            identity.fingerprint()
+        );
+
+        assert!(Profile::open_in(base.path(), "../work").is_err());
+    }
+}
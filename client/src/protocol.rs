@@ -0,0 +1,503 @@
+//! How two clients encode messages for each other.
+//!
+//! Each side ends its key exchange announcement with its session
+//! parameters: the newest protocol version it speaks, then the padding it
+//! asks for. Both then use the older of the two versions and the stricter
+//! padding, never less than their own. A peer that sends no parameters is
+//! taken to speak version 1 without padding, so only a client that asks
+//! for no padding talks to it.
+//!
+//! Version 1 is only JSON of the current `Message`. The message schema, the
+//! framing and the handshake have all changed since the first clients, so
+//! none of this makes a client compatible with those.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::message::Message;
+use crate::secret::Plaintext;
+
+/// Marks where the padding after a message starts; only zeros follow it.
+const PADDING_MARKER: u8 = 0x80;
+/// The smallest size `Padding::PowerOfTwo` pads to, and the smallest
+/// block `Padding::Block` takes.
+const MIN_PADDED_LEN: usize = 32;
+
+/// What a session has agreed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    pub version: Version,
+    pub padding: Padding,
+}
+
+impl Session {
+    /// The parameters to announce when asking for `padding`.
+    pub fn parameters(padding: Padding) -> Vec<u8> {
+        let mut parameters = vec![Version::CURRENT as u8];
+        match padding {
+            Padding::None => parameters.push(0),
+            Padding::PowerOfTwo => parameters.push(1),
+            Padding::Block(size) => {
+                parameters.push(2);
+                parameters.extend_from_slice(&size.to_be_bytes());
+            }
+        }
+        parameters
+    }
+
+    /// The session with a peer that announced `parameters`, when we asked
+    /// for `padding`. The peer can make the padding stricter but never
+    /// weaker; one that can not strip padding we asked for is refused.
+    pub fn negotiate(padding: Padding, parameters: &[u8]) -> Result<Session, String> {
+        // Smaller blocks than we allow ask for nothing beyond our policy.
+        let theirs = match parameters.get(1..) {
+            Some([1, ..]) => Some(Padding::PowerOfTwo),
+            Some([2, high, low, ..]) => match u16::from_be_bytes([*high, *low]) {
+                size if (size as usize) < MIN_PADDED_LEN => None,
+                size => Some(Padding::Block(size)),
+            },
+            _ => None,
+        };
+        let padding = match theirs {
+            Some(theirs) => padding.max(theirs),
+            None if parameters.len() > 1 => padding,
+            // A peer that does not mention padding can not strip it.
+            None if padding == Padding::None => padding,
+            None => {
+                return Err(format!(
+                    "the peer can not strip the {} padding asked for",
+                    padding
+                ))
+            }
+        };
+        Ok(Session {
+            version: Version::negotiate(parameters),
+            padding,
+        })
+    }
+
+    pub fn encode(&self, message: &Message) -> Result<Plaintext, String> {
+        Ok(self.padding.apply(self.version.encode(message)?))
+    }
+
+    pub fn decode(&self, plaintext: Plaintext) -> Result<Message, String> {
+        let plaintext = self.padding.strip(plaintext)?;
+        self.version.decode(&plaintext)
+    }
+}
+
+/// The encoding of messages inside the encryption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    /// JSON of the current `Message`; not the JSON the first clients sent.
+    Json = 1,
+    /// postcard: varints and raw bytes, no field names.
+    Binary = 2,
+}
+
+impl Version {
+    /// The newest version this client speaks.
+    pub const CURRENT: Version = Version::Binary;
+
+    /// The version to use with a peer that announced `parameters`.
+    fn negotiate(parameters: &[u8]) -> Version {
+        match parameters.first() {
+            None | Some(0 | 1) => Version::Json,
+            Some(_) => Version::CURRENT,
+        }
+    }
+
+    pub fn encode(self, message: &Message) -> Result<Plaintext, String> {
+        match self {
+            Version::Json => serde_json::to_vec(message)
+                .map(Plaintext::from)
+                .map_err(|err| format!("{:?} error", err.classify())),
+            Version::Binary => postcard::to_stdvec(message)
+                .map(Plaintext::from)
+                .map_err(|err| err.to_string()),
+        }
+    }
+
+    /// Decode a message. The error never quotes the input: serde_json would
+    /// otherwise put parts of the plaintext in it.
+    pub fn decode(self, plaintext: &Plaintext) -> Result<Message, String> {
+        match self {
+            Version::Json => serde_json::from_slice(plaintext.expose())
+                .map_err(|err| format!("{:?} error at column {}", err.classify(), err.column())),
+            Version::Binary => {
+                postcard::from_bytes(plaintext.expose()).map_err(|err| err.to_string())
+            }
+        }
+    }
+}
+
+/// How much a message is padded before it is encrypted, so its ciphertext
+/// does not give its length away. Sorted from the weakest to the strictest
+/// by the smallest length they pad to, since a policy leaks nothing about
+/// messages shorter than that and most messages are short: no padding, then
+/// powers of two and 32 byte blocks, then larger blocks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    None,
+    /// Up to the next power of two, at least 32 bytes.
+    #[default]
+    PowerOfTwo,
+    /// Up to the next multiple of this many bytes, at least 32.
+    Block(u16),
+}
+
+impl Ord for Padding {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Blocks win ties, so both peers settle on the same policy.
+        let rank = |padding: &Padding| match *padding {
+            Padding::None => (0, 0),
+            Padding::PowerOfTwo => (MIN_PADDED_LEN, 0),
+            Padding::Block(size) => (size as usize, 1),
+        };
+        rank(self).cmp(&rank(other))
+    }
+}
+
+impl PartialOrd for Padding {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Padding {
+    /// The length `len` bytes of message are padded to, marker included.
+    fn padded_len(self, len: usize) -> usize {
+        match self {
+            Padding::None => len,
+            Padding::PowerOfTwo => (len + 1).next_power_of_two().max(MIN_PADDED_LEN),
+            Padding::Block(size) => (len + 1).div_ceil(size as usize) * size as usize,
+        }
+    }
+
+    fn apply(self, plaintext: Plaintext) -> Plaintext {
+        if self == Padding::None {
+            return plaintext;
+        }
+        // Sized up front so the message is never copied by a reallocation
+        // that would leave it behind unwiped.
+        let mut padded = Vec::with_capacity(self.padded_len(plaintext.expose().len()));
+        padded.extend_from_slice(plaintext.expose());
+        padded.push(PADDING_MARKER);
+        padded.resize(padded.capacity(), 0);
+        Plaintext::from(padded)
+    }
+
+    fn strip(self, mut plaintext: Plaintext) -> Result<Plaintext, String> {
+        if self == Padding::None {
+            return Ok(plaintext);
+        }
+        let bytes = plaintext.expose();
+        match bytes.iter().rposition(|byte| *byte != 0) {
+            Some(marker) if bytes[marker] == PADDING_MARKER => {
+                plaintext.truncate(marker);
+                Ok(plaintext)
+            }
+            _ => Err("the padding is invalid".to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for Padding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Padding::None => f.write_str("none"),
+            Padding::PowerOfTwo => f.write_str("power-of-two"),
+            Padding::Block(size) => write!(f, "block:{}", size),
+        }
+    }
+}
+
+impl FromStr for Padding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Padding::None),
+            "power-of-two" => Ok(Padding::PowerOfTwo),
+            _ => match s.strip_prefix("block:").map(str::parse::<u16>) {
+                Some(Ok(size)) if size as usize >= MIN_PADDED_LEN => Ok(Padding::Block(size)),
+                Some(Ok(_)) => Err(format!("blocks must be at least {} bytes", MIN_PADDED_LEN)),
+                _ => Err(format!(
+                    "unknown padding `{}`, expected none, power-of-two or block:<bytes>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Padding {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Kind, MessageId};
+    use crate::transfer::FileKey;
+    use chrono::{TimeZone, Utc};
+
+    /// How every golden message starts: the id's 16 bytes, the sender's
+    /// length and name, and the timestamp's seconds as a zigzag varint and
+    /// its nanoseconds.
+    const PREFIX: &str = "01563e3ab5d3d6764c61efb99302bd5b 05616c696365 9aa98ede0c 00";
+
+    /// Messages with everything fixed, and the rest of their encoding under
+    /// `Version::Binary`: the kind's index and fields, then the reply-to.
+    /// A change here breaks every client that is already out there.
+    fn golden() -> Vec<(Message, &'static str)> {
+        let id: MessageId = "01ARZ3NDEKTSV4RRFFQ69G5FAV".parse().unwrap();
+        let target: MessageId = "01BX5ZZKBKACTAV9WEVGEMMVRZ".parse().unwrap();
+        let timestamp = Utc.with_ymd_and_hms(2024, 3, 1, 12, 30, 5).unwrap();
+        let message = |kind, reply_to| Message::from_parts(id, "alice", timestamp, kind, reply_to);
+        vec![
+            (message(Kind::Text("hi".into()), None), "00 026869 00"),
+            (
+                message(Kind::Action("waves".into()), Some(target)),
+                "01 057761766573 01 015f4bffcd735334ada78edc1d4a6f1f",
+            ),
+            (
+                message(Kind::System("bob changed keys".into()), None),
+                "02 10626f62206368616e676564206b657973 00",
+            ),
+            (
+                message(
+                    Kind::File {
+                        name: "notes.txt".into(),
+                        size: 1234,
+                    },
+                    None,
+                ),
+                "03 096e6f7465732e747874 d209 00",
+            ),
+            (
+                message(
+                    Kind::Edit {
+                        target,
+                        text: "hello".into(),
+                    },
+                    None,
+                ),
+                "04 015f4bffcd735334ada78edc1d4a6f1f 0568656c6c6f 00",
+            ),
+            (
+                message(Kind::Delete { target }, None),
+                "05 015f4bffcd735334ada78edc1d4a6f1f 00",
+            ),
+            (
+                message(
+                    Kind::Reaction {
+                        target,
+                        emoji: "+1".into(),
+                    },
+                    None,
+                ),
+                "06 015f4bffcd735334ada78edc1d4a6f1f 022b31 00",
+            ),
+            (
+                message(
+                    Kind::Offer {
+                        name: "a".into(),
+                        size: 300,
+                        hash: [0xaa; 32],
+                        key: FileKey::from_bytes([0xbb; 32]),
+                    },
+                    None,
+                ),
+                "07 0161 ac02 aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa \
+                 bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb 00",
+            ),
+            (
+                message(
+                    Kind::Fetch {
+                        file: target,
+                        from: 2,
+                    },
+                    None,
+                ),
+                "08 015f4bffcd735334ada78edc1d4a6f1f 02 00",
+            ),
+            (
+                message(
+                    Kind::Chunk {
+                        file: target,
+                        index: 2,
+                        data: vec![1, 2, 3],
+                    },
+                    None,
+                ),
+                "09 015f4bffcd735334ada78edc1d4a6f1f 02 03010203 00",
+            ),
+            (message(Kind::Typing(true), None), "0a 01 00"),
+            (
+                message(Kind::Delivered { target }, None),
+                "0b 015f4bffcd735334ada78edc1d4a6f1f 00",
+            ),
+            (
+                message(Kind::Read { target }, None),
+                "0c 015f4bffcd735334ada78edc1d4a6f1f 00",
+            ),
+        ]
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn golden_vectors() {
+        for (message, expected) in golden() {
+            let encoded = Version::Binary.encode(&message).unwrap();
+            let expected = format!("{}{}", PREFIX, expected).replace(' ', "");
+            assert_eq!(hex(encoded.expose()), expected);
+            assert!(Version::Binary.decode(&encoded).unwrap() == message);
+        }
+    }
+
+    #[test]
+    fn every_version_round_trips() {
+        for (message, _) in golden() {
+            for version in [Version::Json, Version::Binary] {
+                let encoded = version.encode(&message).unwrap();
+                assert!(
+                    version.decode(&encoded).unwrap() == message,
+                    "{:?}",
+                    version
+                );
+            }
+        }
+        let garbage = Plaintext::from(b"{\"secret\": 1".to_vec());
+        let err = Version::Json.decode(&garbage).unwrap_err();
+        assert!(!err.contains("secret"), "{}", err);
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(Version::negotiate(&[]), Version::Json);
+        assert_eq!(Version::negotiate(&[1]), Version::Json);
+        assert_eq!(Version::negotiate(&[2]), Version::Binary);
+        // A newer peer talks down to us.
+        assert_eq!(Version::negotiate(&[9]), Version::CURRENT);
+
+        let session = |ours: Padding, theirs: Padding| {
+            Session::negotiate(ours, &Session::parameters(theirs))
+                .unwrap()
+                .padding
+        };
+        assert_eq!(session(Padding::None, Padding::None), Padding::None);
+        assert_eq!(
+            session(Padding::None, Padding::PowerOfTwo),
+            Padding::PowerOfTwo
+        );
+        assert_eq!(
+            session(Padding::Block(256), Padding::PowerOfTwo),
+            Padding::Block(256)
+        );
+        assert_eq!(
+            session(Padding::Block(256), Padding::Block(1024)),
+            Padding::Block(1024)
+        );
+        // A peer can not weaken the padding we asked for, by asking for
+        // tiny blocks or none at all, and both sides agree either way.
+        for (ours, theirs) in [
+            (Padding::PowerOfTwo, Padding::Block(1)),
+            (Padding::PowerOfTwo, Padding::None),
+            (Padding::Block(256), Padding::PowerOfTwo),
+        ] {
+            assert_eq!(session(ours, theirs), ours);
+        }
+        assert_eq!(
+            session(Padding::PowerOfTwo, Padding::Block(32)),
+            session(Padding::Block(32), Padding::PowerOfTwo)
+        );
+        // Peers that announce no padding can not strip it, so only a client
+        // that asks for none talks to them.
+        assert!(Session::negotiate(Padding::PowerOfTwo, &[]).is_err());
+        assert!(Session::negotiate(Padding::PowerOfTwo, &[2]).is_err());
+        assert_eq!(
+            Session::negotiate(Padding::None, &[]).unwrap().padding,
+            Padding::None
+        );
+    }
+
+    #[test]
+    fn padding() {
+        assert_eq!("block:512".parse(), Ok(Padding::Block(512)));
+        assert!("block:0".parse::<Padding>().is_err());
+        assert!("block:31".parse::<Padding>().is_err());
+        for padding in [Padding::None, Padding::PowerOfTwo, Padding::Block(100)] {
+            assert_eq!(padding.to_string().parse(), Ok(padding));
+        }
+
+        for (padding, len, padded) in [
+            (Padding::None, 45, 45),
+            (Padding::PowerOfTwo, 1, 32),
+            (Padding::PowerOfTwo, 45, 64),
+            (Padding::PowerOfTwo, 63, 64),
+            (Padding::PowerOfTwo, 64, 128),
+            (Padding::Block(100), 45, 100),
+            (Padding::Block(100), 99, 100),
+            (Padding::Block(100), 100, 200),
+        ] {
+            let message = vec![7; len];
+            let applied = padding.apply(Plaintext::from(message.clone()));
+            assert_eq!(applied.expose().len(), padded, "{} of {}", padding, len);
+            assert_eq!(padding.strip(applied).unwrap().expose(), message);
+        }
+
+        // Messages of different lengths look alike.
+        let session = Session {
+            version: Version::Binary,
+            padding: Padding::Block(256),
+        };
+        let short = session
+            .encode(&Message::new("a", Kind::Text("hi".into())))
+            .unwrap();
+        let long = Message::new("alice", Kind::Text("hello there, how are you?".into()));
+        let encoded = session.encode(&long).unwrap();
+        assert_eq!(short.expose().len(), encoded.expose().len());
+        assert!(session.decode(encoded).unwrap() == long);
+
+        assert!(Padding::PowerOfTwo
+            .strip(Plaintext::from(vec![1, 2, 0, 0]))
+            .is_err());
+    }
+
+    /// Compare the size and speed of both encodings.
+    ///
+    /// Run with `cargo test --release -p client -- --ignored --nocapture bench_encoding`.
+    #[test]
+    #[ignore]
+    fn bench_encoding() {
+        const ROUNDS: u32 = 100_000;
+        let message = Message::new("alice", Kind::Text("see you at the usual place".into()));
+        for version in [Version::Json, Version::Binary] {
+            let size = version.encode(&message).unwrap().expose().len();
+            let start = std::time::Instant::now();
+            for _ in 0..ROUNDS {
+                std::hint::black_box(version.encode(std::hint::black_box(&message)).unwrap());
+            }
+            let encode = start.elapsed() / ROUNDS;
+            let encoded = version.encode(&message).unwrap();
+            let start = std::time::Instant::now();
+            for _ in 0..ROUNDS {
+                std::hint::black_box(version.decode(std::hint::black_box(&encoded)).unwrap());
+            }
+            let decode = start.elapsed() / ROUNDS;
+            println!(
+                "{:?}: {} bytes, encode {:?}, decode {:?}",
+                version, size, encode, decode
+            );
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Search over the decrypted history.
+//!
+//! The index is kept in memory only and built from the local history, so
+//! nothing about the messages reaches the disk in the clear or the server.
+//! It maps every lower-cased word to where it occurs; a query word matches
+//! the words it is a prefix of.
+//!
+//! Queries are words and filters, e.g. `from:bob since:2024-03-01 deploy`:
+//! - `room:<name>` searches another room, `room:*` every room; the current
+//!   room otherwise
+//! - `from:<sender>` keeps the messages of one sender
+//! - `since:<date>` and `until:<date>` keep those sent on or after, and on
+//!   or before, a local date
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
+
+use chrono::{Local, NaiveDate};
+
+use crate::message::Message;
+
+/// Where to search.
+#[derive(Debug, PartialEq)]
+enum Scope {
+    Current,
+    Room(String),
+    All,
+}
+
+/// What to search for.
+#[derive(Debug, PartialEq)]
+pub struct Query {
+    words: Vec<String>,
+    scope: Scope,
+    from: Option<String>,
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+}
+
+impl FromStr for Query {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let date = |date: &str| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .map_err(|_| format!("`{}` is not a date like 2024-03-01", date))
+        };
+        let mut query = Query {
+            words: Vec::new(),
+            scope: Scope::Current,
+            from: None,
+            since: None,
+            until: None,
+        };
+        for term in s.split_whitespace() {
+            match term.split_once(':') {
+                Some(("room", "*")) => query.scope = Scope::All,
+                Some(("room", room)) => query.scope = Scope::Room(room.to_owned()),
+                Some(("from", sender)) => query.from = Some(sender.to_owned()),
+                Some(("since", since)) => query.since = Some(date(since)?),
+                Some(("until", until)) => query.until = Some(date(until)?),
+                _ => query.words.extend(words(term)),
+            }
+        }
+        if query.words.is_empty() && query.from.is_none() {
+            return Err("Search for some words or from:<sender>".to_owned());
+        }
+        Ok(query)
+    }
+}
+
+/// Where a message is: its room and its position in the room's log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Hit {
+    room: usize,
+    position: usize,
+}
+
+/// The messages of every room and where their words occur.
+#[derive(Default)]
+pub struct Index {
+    rooms: Vec<(String, Vec<Message>)>,
+    words: BTreeMap<String, BTreeSet<Hit>>,
+}
+
+impl Index {
+    /// Index the messages of `rooms`, as loaded from the history, that are
+    /// not indexed yet. Messages are mostly appended to a log, and then only
+    /// those past what the index holds of the room are new; an import
+    /// merges them in among the others, and the room is indexed afresh.
+    pub fn update(&mut self, rooms: Vec<(String, Vec<Message>)>) {
+        for (name, messages) in rooms {
+            let room = match self.rooms.iter().position(|(known, _)| *known == name) {
+                Some(room) => room,
+                None => {
+                    self.rooms.push((name, Vec::new()));
+                    self.rooms.len() - 1
+                }
+            };
+            let known = &self.rooms[room].1;
+            let appended = known.len() <= messages.len()
+                && known.iter().zip(&messages).all(|(a, b)| a.id() == b.id());
+            if !appended {
+                self.forget(room);
+            }
+            let indexed = self.rooms[room].1.len();
+            for (position, message) in messages.into_iter().enumerate().skip(indexed) {
+                for word in message.text().into_iter().flat_map(words) {
+                    self.words
+                        .entry(word)
+                        .or_default()
+                        .insert(Hit { room, position });
+                }
+                self.rooms[room].1.push(message);
+            }
+        }
+    }
+
+    /// Drop what is indexed of `room`, whose messages moved.
+    fn forget(&mut self, room: usize) {
+        self.words.retain(|_, hits| {
+            hits.retain(|hit| hit.room != room);
+            !hits.is_empty()
+        });
+        self.rooms[room].1.clear();
+    }
+
+    /// The messages matching `query`, oldest first. `current` is the room
+    /// being chatted in.
+    pub fn search(&self, query: &Query, current: &str) -> Vec<Hit> {
+        let room = match &query.scope {
+            Scope::Current => Some(current),
+            Scope::Room(room) => Some(room.as_str()),
+            Scope::All => None,
+        };
+        let rooms: BTreeSet<usize> = (0..self.rooms.len())
+            .filter(|&i| room.is_none_or(|room| self.rooms[i].0 == room))
+            .collect();
+
+        // The hits of every word, narrowed down one word at a time.
+        let mut hits: Option<BTreeSet<Hit>> = None;
+        for word in &query.words {
+            let matching: BTreeSet<Hit> = self
+                .words
+                .range(word.clone()..)
+                .take_while(|(indexed, _)| indexed.starts_with(word.as_str()))
+                .flat_map(|(_, hits)| hits.iter().copied())
+                .collect();
+            hits = Some(match hits {
+                Some(hits) => hits.intersection(&matching).copied().collect(),
+                None => matching,
+            });
+        }
+        let hits: Vec<Hit> = match hits {
+            Some(hits) => hits.into_iter().collect(),
+            None => rooms
+                .iter()
+                .flat_map(|&room| {
+                    (0..self.rooms[room].1.len()).map(move |position| Hit { room, position })
+                })
+                .collect(),
+        };
+
+        let mut hits: Vec<Hit> = hits
+            .into_iter()
+            .filter(|hit| rooms.contains(&hit.room))
+            .filter(|hit| {
+                let message = self.message(*hit);
+                let date = message.timestamp().with_timezone(&Local).date_naive();
+                query
+                    .from
+                    .as_ref()
+                    .is_none_or(|from| message.sender() == from)
+                    && query.since.is_none_or(|since| date >= since)
+                    && query.until.is_none_or(|until| date <= until)
+            })
+            .collect();
+        hits.sort_by_key(|hit| self.message(*hit).timestamp());
+        hits
+    }
+
+    pub fn room(&self, hit: Hit) -> &str {
+        &self.rooms[hit.room].0
+    }
+
+    pub fn message(&self, hit: Hit) -> &Message {
+        &self.rooms[hit.room].1[hit.position]
+    }
+
+    /// `hit` with up to `n` messages of its room on either side, and where
+    /// it is among them.
+    pub fn context(&self, hit: Hit, n: usize) -> (&[Message], usize) {
+        let messages = &self.rooms[hit.room].1;
+        let start = hit.position.saturating_sub(n);
+        let end = (hit.position + n + 1).min(messages.len());
+        (&messages[start..end], hit.position - start)
+    }
+}
+
+/// The lower-cased words of `text`.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Kind, MessageId};
+    use chrono::{TimeZone, Utc};
+
+    fn message(sender: &str, day: u32, text: &str) -> Message {
+        Message::from_parts(
+            MessageId::new(),
+            sender,
+            Utc.with_ymd_and_hms(2024, 3, day, 12, 0, 0).unwrap(),
+            Kind::Text(text.to_owned()),
+            None,
+        )
+    }
+
+    #[test]
+    fn parses_queries() {
+        let query: Query = "room:* from:bob since:2024-03-01 Deploy, today"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            query,
+            Query {
+                words: vec!["deploy".into(), "today".into()],
+                scope: Scope::All,
+                from: Some("bob".into()),
+                since: NaiveDate::from_ymd_opt(2024, 3, 1),
+                until: None,
+            }
+        );
+        assert!("until:yesterday x".parse::<Query>().is_err());
+        assert!("room:lobby".parse::<Query>().is_err());
+    }
+
+    #[test]
+    fn searches() {
+        let lobby = vec![
+            message("alice", 1, "Deploying the server"),
+            message("bob", 2, "the deploy failed"),
+            message("alice", 3, "lunch?"),
+            message("bob", 20, "deployed at last"),
+        ];
+        let mut index = Index::default();
+        index.update(vec![("lobby".into(), lobby[..2].to_vec())]);
+        // Only what was not indexed yet is added.
+        index.update(vec![
+            ("lobby".into(), lobby.clone()),
+            ("ops".into(), vec![message("carol", 2, "deploy window")]),
+        ]);
+
+        let texts = |query: &str, current: &str| -> Vec<String> {
+            let query = query.parse().unwrap();
+            index
+                .search(&query, current)
+                .into_iter()
+                .map(|hit| index.message(hit).text().unwrap().to_owned())
+                .collect()
+        };
+        assert_eq!(
+            texts("deploy", "lobby"),
+            [
+                "Deploying the server",
+                "the deploy failed",
+                "deployed at last"
+            ]
+        );
+        assert_eq!(texts("deploy failed", "lobby"), ["the deploy failed"]);
+        assert_eq!(texts("deploy", "ops"), ["deploy window"]);
+        assert_eq!(texts("deploy room:*", "ops").len(), 4);
+        assert_eq!(texts("room:ops deploy", "lobby"), ["deploy window"]);
+        assert_eq!(
+            texts("from:bob", "lobby"),
+            ["the deploy failed", "deployed at last"]
+        );
+        assert_eq!(
+            texts("deploy since:2024-03-02 until:2024-03-10 room:*", "x"),
+            ["the deploy failed", "deploy window"]
+        );
+        assert!(texts("dinner", "lobby").is_empty());
+
+        let query = "lunch".parse().unwrap();
+        let hit = index.search(&query, "lobby")[0];
+        assert_eq!(index.room(hit), "lobby");
+        let (context, at) = index.context(hit, 1);
+        assert!(context == &lobby[1..4]);
+        assert_eq!(at, 1);
+    }
+
+    /// Messages imported in among the indexed ones move those after them.
+    #[test]
+    fn reindexes_merged_rooms() {
+        let mut lobby = vec![
+            message("alice", 1, "first"),
+            message("bob", 3, "deploy done"),
+        ];
+        let mut index = Index::default();
+        index.update(vec![("lobby".into(), lobby.clone())]);
+        lobby.insert(1, message("carol", 2, "imported"));
+        index.update(vec![("lobby".into(), lobby.clone())]);
+
+        let hits = |query: &str| -> Vec<String> {
+            index
+                .search(&query.parse().unwrap(), "lobby")
+                .into_iter()
+                .map(|hit| index.message(hit).text().unwrap().to_owned())
+                .collect()
+        };
+        assert_eq!(hits("deploy"), ["deploy done"]);
+        assert_eq!(hits("imported"), ["imported"]);
+        assert_eq!(hits("from:alice"), ["first"]);
+        let hit = index.search(&"deploy".parse().unwrap(), "lobby")[0];
+        let (context, at) = index.context(hit, 1);
+        assert!(context == &lobby[1..]);
+        assert_eq!(at, 1);
+    }
+}
@@ -0,0 +1,112 @@
+//! Values that must never end up in a log.
+//!
+//! Both types print as `[REDACTED]` with `{}` and `{:?}`, so logging one by
+//! mistake leaks nothing, and both wipe their bytes when dropped. Getting at
+//! the secret takes an explicit call.
+
+use std::fmt;
+
+use aes_gcm::{aead::KeyInit, Aes256Gcm};
+use zeroize::{Zeroize, Zeroizing};
+
+const REDACTED: &str = "[REDACTED]";
+
+/// The AES-256 key agreed on with the peer for this session.
+pub struct SessionKey([u8; 32]);
+
+impl SessionKey {
+    /// Take the key out of a Diffie-Hellman shared secret. `None` unless it
+    /// is 32 bytes long.
+    pub fn from_slice(bytes: &[u8]) -> Option<SessionKey> {
+        Some(SessionKey(bytes.try_into().ok()?))
+    }
+
+    /// A cipher keyed with this key.
+    pub fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(&self.0.into())
+    }
+
+    /// The key itself.
+    #[cfg(test)]
+    pub fn expose(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl Drop for SessionKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SessionKey")
+            .field(&format_args!("{}", REDACTED))
+            .finish()
+    }
+}
+
+impl fmt::Display for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// A message before it is encrypted or after it is decrypted.
+pub struct Plaintext(Zeroizing<Vec<u8>>);
+
+impl Plaintext {
+    /// The bytes to encrypt, or the decrypted ones.
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Cut off everything from `len` on, such as padding.
+    pub fn truncate(&mut self, len: usize) {
+        self.0.truncate(len);
+    }
+}
+
+impl From<Vec<u8>> for Plaintext {
+    fn from(bytes: Vec<u8>) -> Self {
+        Plaintext(Zeroizing::new(bytes))
+    }
+}
+
+impl From<String> for Plaintext {
+    fn from(text: String) -> Self {
+        Plaintext::from(text.into_bytes())
+    }
+}
+
+impl fmt::Debug for Plaintext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Plaintext")
+            .field(&format_args!("{}", REDACTED))
+            .finish()
+    }
+}
+
+impl fmt::Display for Plaintext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted() {
+        let key = SessionKey::from_slice(&[0xAB; 32]).unwrap();
+        assert_eq!(format!("{:?}", key), "SessionKey([REDACTED])");
+        assert_eq!(key.to_string(), "[REDACTED]");
+        assert!(SessionKey::from_slice(&[0; 31]).is_none());
+
+        let text = Plaintext::from("attack at dawn".to_owned());
+        assert!(!format!("{:?} {}", text, text).contains("dawn"));
+        assert_eq!(text.expose(), b"attack at dawn");
+    }
+}
@@ -0,0 +1,276 @@
+//! Optional TLS for the connection to the server.
+//!
+//! The server's certificate is checked against the public web PKI roots by
+//! default, against a custom CA when one is given, or against a pinned
+//! SHA-256 fingerprint of the certificate itself.
+
+use std::{io, net::IpAddr, path::Path, sync::Arc};
+
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{self, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    },
+    TlsConnector,
+};
+
+/// How the client decides whether to trust the server's certificate.
+pub enum Trust<'a> {
+    /// The public web PKI roots.
+    WebPki,
+    /// Certificates issued by the CA(s) in this PEM file.
+    Ca(&'a Path),
+    /// Exactly the certificate with this SHA-256 fingerprint, in hex.
+    Pin(&'a str),
+}
+
+/// Everything needed to wrap a TCP connection in TLS.
+pub struct Tls {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl Tls {
+    /// Configure TLS for a server. `server_name` is the name its certificate
+    /// is checked against; the server's IP address is used when it is `None`.
+    pub fn new(trust: Trust, server_name: Option<&str>, ip: IpAddr) -> io::Result<Tls> {
+        let invalid =
+            |e: &dyn std::fmt::Display| io::Error::new(io::ErrorKind::InvalidInput, e.to_string());
+
+        let config = match trust {
+            Trust::WebPki => {
+                let roots =
+                    RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                ClientConfig::builder()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth()
+            }
+            Trust::Ca(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(path)
+                    .map_err(|e| invalid(&format!("{}: {}", path.display(), e)))?
+                {
+                    let cert = cert.map_err(|e| invalid(&format!("{}: {}", path.display(), e)))?;
+                    roots.add(cert).map_err(|e| invalid(&e))?;
+                }
+                ClientConfig::builder()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth()
+            }
+            Trust::Pin(pin) => {
+                let verifier = PinnedCertVerifier {
+                    fingerprint: parse_fingerprint(pin)?,
+                    provider: Arc::new(crypto::ring::default_provider()),
+                };
+                ClientConfig::builder()
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(verifier))
+                    .with_no_client_auth()
+            }
+        };
+
+        let server_name = match server_name {
+            Some(name) => ServerName::try_from(name.to_owned()).map_err(|e| invalid(&e))?,
+            None => ServerName::IpAddress(ip.into()),
+        };
+
+        Ok(Tls {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    /// Perform the TLS handshake over an established TCP connection.
+    pub async fn connect(&self, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+        self.connector
+            .connect(self.server_name.clone(), stream)
+            .await
+    }
+}
+
+/// Parse a hex encoded SHA-256 fingerprint. Colons between bytes, as printed
+/// by `openssl x509 -fingerprint -sha256`, are allowed.
+fn parse_fingerprint(pin: &str) -> io::Result<[u8; 32]> {
+    let hex: String = pin.chars().filter(|c| *c != ':').collect();
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("`{}` is not a hex encoded SHA-256 fingerprint", pin),
+        )
+    };
+
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut fingerprint = [0u8; 32];
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(fingerprint)
+}
+
+/// Accepts only the certificate whose SHA-256 fingerprint was pinned,
+/// regardless of who issued it or which names it is valid for. Handshake
+/// signatures are still verified, so the server must hold the private key.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity.as_ref()).as_slice() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "the server certificate does not match the pinned fingerprint".into(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_rustls::{
+        rustls::{pki_types::PrivateKeyDer, ServerConfig},
+        TlsAcceptor,
+    };
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    /// Start a TLS echo server with a fresh self-signed certificate for
+    /// `localhost`. Returns its address and the certificate.
+    async fn echo_server() -> (SocketAddr, rcgen::CertifiedKey<rcgen::KeyPair>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.cert.der().clone()],
+                PrivateKeyDer::try_from(cert.signing_key.serialize_der()).unwrap(),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    let mut buf = [0u8; 5];
+                    if stream.read_exact(&mut buf).await.is_ok() {
+                        let _ = stream.write_all(&buf).await;
+                    }
+                }
+            }
+        });
+        (addr, cert)
+    }
+
+    async fn echo(addr: SocketAddr, tls: &Tls) -> io::Result<()> {
+        let mut stream = tls.connect(TcpStream::connect(addr).await?).await?;
+        stream.write_all(b"hello").await?;
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn custom_ca() {
+        let (addr, cert) = echo_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let ca = dir.path().join("ca.pem");
+        std::fs::write(&ca, cert.cert.pem()).unwrap();
+
+        let tls = Tls::new(Trust::Ca(&ca), Some("localhost"), LOCALHOST).unwrap();
+        echo(addr, &tls).await.unwrap();
+
+        // The certificate is not valid for the server's IP address.
+        let tls = Tls::new(Trust::Ca(&ca), None, LOCALHOST).unwrap();
+        assert!(echo(addr, &tls).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pinned_certificate() {
+        let (addr, cert) = echo_server().await;
+        let fingerprint: Vec<String> = Sha256::digest(cert.cert.der())
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+
+        let tls = Tls::new(Trust::Pin(&fingerprint.join(":")), None, LOCALHOST).unwrap();
+        echo(addr, &tls).await.unwrap();
+
+        let tls = Tls::new(Trust::Pin(&"00".repeat(32)), None, LOCALHOST).unwrap();
+        assert!(echo(addr, &tls).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificate() {
+        let (addr, _) = echo_server().await;
+        let tls = Tls::new(Trust::WebPki, Some("localhost"), LOCALHOST).unwrap();
+        assert!(echo(addr, &tls).await.is_err());
+    }
+
+    #[test]
+    fn fingerprint_format() {
+        assert!(parse_fingerprint(&"ab".repeat(32)).is_ok());
+        assert!(parse_fingerprint(&format!("{}AB", "AB:".repeat(31))).is_ok());
+        assert!(parse_fingerprint("abcd").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+    }
+}
@@ -0,0 +1,576 @@
+//! Files sent through the room in encrypted chunks.
+//!
+//! `:send-file` offers a file with a `Kind::Offer` giving its name, size,
+//! SHA-256 and a fresh key. Once the receiver's user accepts it, the
+//! receiver answers with a `Kind::Fetch` naming the first chunk it lacks
+//! and the sender streams `Kind::Chunk`s from there, one stream per file
+//! at a time. Files larger than `MAX_SIZE` are neither offered nor
+//! fetched. Chunks are encrypted under the file's key, bound to the
+//! offer and their index, inside the session's encryption. The key
+//! outlives the session in the history, so offering a file again reuses
+//! the earlier offer and the receiver resumes where it stopped.
+//!
+//! Downloads are written to `<offer id>.part` in the downloads directory
+//! and only renamed to the offered name once the content hash matches.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+
+use crate::message::{Kind, Message, MessageId};
+
+/// The plaintext in a chunk, all but the last. Sealed and encoded, a chunk
+/// stays under 32 KiB, which power of two padding does not double.
+pub const CHUNK_LEN: u64 = 32_000;
+const NONCE_LEN: usize = 12;
+/// The largest file that is offered or fetched, 4 GiB.
+pub const MAX_SIZE: u64 = 4 << 30;
+
+/// The key the chunks of one file are encrypted under.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileKey([u8; 32]);
+
+impl FileKey {
+    fn generate() -> FileKey {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        FileKey(key)
+    }
+
+    /// A key with every byte given, for fixed test vectors.
+    #[cfg(test)]
+    pub fn from_bytes(key: [u8; 32]) -> FileKey {
+        FileKey(key)
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(&self.0.into())
+    }
+}
+
+impl Drop for FileKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for FileKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FileKey([REDACTED])")
+    }
+}
+
+/// The chunks a file of `size` bytes is sent in.
+fn chunks(size: u64) -> u64 {
+    size.div_ceil(CHUNK_LEN)
+}
+
+/// What the chunk at `index` of the file `file` is authenticated with, so
+/// that chunks can not be swapped.
+fn aad(file: MessageId, index: u64) -> Vec<u8> {
+    [&file.to_bytes()[..], &index.to_be_bytes()].concat()
+}
+
+/// Whether `done` of `of` chunks is the first at or past another tenth,
+/// and the percentage if so.
+pub fn milestone(done: u64, of: u64) -> Option<u64> {
+    let percent = |done: u64| done * 100 / of.max(1);
+    (done == of || percent(done) / 10 != percent(done.saturating_sub(1)) / 10)
+        .then(|| percent(done))
+}
+
+/// A file being sent.
+#[derive(Clone)]
+pub struct Outgoing {
+    id: MessageId,
+    pub name: String,
+    path: PathBuf,
+    size: u64,
+    key: FileKey,
+}
+
+impl Outgoing {
+    /// Offer the file at `path` as `sender`. An offer of the same content
+    /// among `earlier` messages is sent again rather than a new one, so
+    /// receivers resume it; the second value tells whether it is new.
+    pub fn offer(
+        path: &Path,
+        sender: &str,
+        earlier: &[Message],
+    ) -> io::Result<(Outgoing, Message, bool)> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?
+            .to_owned();
+        let mut file = fs::File::open(path)?;
+        if file.metadata()?.len() > MAX_SIZE {
+            return Err(too_large());
+        }
+        let mut hasher = Sha256::new();
+        let size = io::copy(&mut file, &mut hasher)?;
+        let hash: [u8; 32] = hasher.finalize().into();
+
+        let earlier = earlier.iter().rev().find(|message| {
+            message.sender() == sender
+                && matches!(message.kind(), Kind::Offer { name: n, size: s, hash: h, .. }
+                    if *n == name && *s == size && *h == hash)
+        });
+        let (offer, new) = match earlier {
+            Some(offer) => (offer.clone(), false),
+            None => {
+                let kind = Kind::Offer {
+                    name: name.clone(),
+                    size,
+                    hash,
+                    key: FileKey::generate(),
+                };
+                (Message::new(sender, kind), true)
+            }
+        };
+        let Kind::Offer { key, .. } = offer.kind() else {
+            unreachable!("offers are offers");
+        };
+        let outgoing = Outgoing {
+            id: offer.id(),
+            name,
+            path: path.to_owned(),
+            size,
+            key: key.clone(),
+        };
+        Ok((outgoing, offer, new))
+    }
+
+    pub fn chunks(&self) -> u64 {
+        chunks(self.size)
+    }
+
+    /// The chunk at `index`, read from `file` and sealed.
+    pub fn chunk(&self, file: &mut fs::File, index: u64) -> io::Result<Kind> {
+        let mut plaintext = Vec::with_capacity(CHUNK_LEN as usize);
+        file.seek(SeekFrom::Start(index * CHUNK_LEN))?;
+        file.take(CHUNK_LEN).read_to_end(&mut plaintext)?;
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: &plaintext,
+            aad: &aad(self.id, index),
+        };
+        let ciphertext = self
+            .key
+            .cipher()
+            .encrypt(&nonce, payload)
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        plaintext.zeroize();
+        Ok(Kind::Chunk {
+            file: self.id,
+            index,
+            data: [&nonce[..], &ciphertext].concat(),
+        })
+    }
+
+    pub fn open(&self) -> io::Result<fs::File> {
+        fs::File::open(&self.path)
+    }
+}
+
+/// How a download is going after a chunk.
+#[derive(Debug, PartialEq)]
+pub enum Progress {
+    /// Not the chunk that comes next, e.g. from a second stream.
+    Skipped,
+    /// `done` of `of` chunks are in.
+    Partial { done: u64, of: u64 },
+    /// Complete and checked, saved at this path.
+    Saved(PathBuf),
+}
+
+/// A file being received.
+pub struct Incoming {
+    id: MessageId,
+    pub name: String,
+    size: u64,
+    hash: [u8; 32],
+    key: FileKey,
+    dir: PathBuf,
+    /// Chunks already in the `.part` file.
+    done: u64,
+}
+
+impl Incoming {
+    /// Receive the file `offer` offers into `dir`, picking up what an
+    /// earlier attempt left. `None` if it is not an offer.
+    pub fn new(dir: &Path, offer: &Message) -> io::Result<Option<Incoming>> {
+        use std::os::unix::fs::DirBuilderExt;
+
+        let Kind::Offer {
+            name,
+            size,
+            hash,
+            key,
+        } = offer.kind()
+        else {
+            return Ok(None);
+        };
+        if *size > MAX_SIZE {
+            return Err(too_large());
+        }
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        let mut incoming = Incoming {
+            id: offer.id(),
+            name: name.clone(),
+            size: *size,
+            hash: *hash,
+            key: key.clone(),
+            dir: dir.to_owned(),
+            done: 0,
+        };
+        // Only whole chunks count; a torn one is fetched again.
+        let part = match fs::metadata(incoming.part()) {
+            Ok(metadata) => metadata.len().min(incoming.size),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        incoming.done = if part == incoming.size {
+            chunks(part)
+        } else {
+            part / CHUNK_LEN
+        };
+        Ok(Some(incoming))
+    }
+
+    /// The index of the first chunk still missing.
+    pub fn next(&self) -> u64 {
+        self.done
+    }
+
+    pub fn chunks(&self) -> u64 {
+        chunks(self.size)
+    }
+
+    /// Whether an earlier attempt left part of the file, so the user
+    /// already accepted it.
+    pub fn is_started(&self) -> bool {
+        self.done > 0
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.done == self.chunks()
+    }
+
+    /// Add the chunk at `index`, and save the file once it is complete.
+    pub fn add(&mut self, index: u64, data: &[u8]) -> io::Result<Progress> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        if index != self.done || self.is_complete() {
+            return Ok(Progress::Skipped);
+        }
+        if data.len() < NONCE_LEN {
+            return Err(invalid("a chunk is too short"));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &aad(self.id, index),
+        };
+        let mut plaintext = self
+            .key
+            .cipher()
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| invalid("a chunk failed to decrypt"))?;
+        let expected = CHUNK_LEN.min(self.size - index * CHUNK_LEN);
+        if plaintext.len() as u64 != expected {
+            return Err(invalid("a chunk has the wrong length"));
+        }
+
+        let mut part = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .mode(0o600)
+            .open(self.part())?;
+        part.set_len(index * CHUNK_LEN)?;
+        part.seek(SeekFrom::End(0))?;
+        part.write_all(&plaintext)?;
+        plaintext.zeroize();
+        self.done += 1;
+
+        if self.is_complete() {
+            self.save().map(Progress::Saved)
+        } else {
+            Ok(Progress::Partial {
+                done: self.done,
+                of: self.chunks(),
+            })
+        }
+    }
+
+    /// Check the content hash of the complete `.part` file and give it the
+    /// offered name. A file that does not match is thrown away.
+    pub fn save(&self) -> io::Result<PathBuf> {
+        let part = self.part();
+        let mut hasher = Sha256::new();
+        match fs::File::open(&part) {
+            Ok(mut file) => {
+                io::copy(&mut file, &mut hasher)?;
+            }
+            // An empty file has no chunks, so no `.part` either.
+            Err(e) if e.kind() == io::ErrorKind::NotFound && self.size == 0 => {
+                fs::File::create(&part)?;
+            }
+            Err(e) => return Err(e),
+        }
+        if hasher.finalize()[..] != self.hash {
+            fs::remove_file(&part)?;
+            return Err(invalid("the content hash does not match, it was discarded"));
+        }
+        let path = self.destination();
+        fs::rename(&part, &path)?;
+        Ok(path)
+    }
+
+    fn part(&self) -> PathBuf {
+        self.dir.join(format!("{}.part", self.id))
+    }
+
+    /// A free path in the downloads directory for the offered name, which
+    /// can not point anywhere else.
+    fn destination(&self) -> PathBuf {
+        let name = Path::new(&self.name)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| !name.starts_with('.'))
+            .unwrap_or("download");
+        let (stem, extension) = match name.rsplit_once('.') {
+            Some((stem, extension)) => (stem, format!(".{}", extension)),
+            None => (name, String::new()),
+        };
+        let mut path = self.dir.join(name);
+        for n in 1.. {
+            if !path.exists() {
+                break;
+            }
+            path = self.dir.join(format!("{} ({}){}", stem, n, extension));
+        }
+        path
+    }
+}
+
+/// The transfers of one chat.
+pub struct Transfers {
+    /// Where downloads are saved.
+    pub downloads: PathBuf,
+    pub outgoing: HashMap<MessageId, Outgoing>,
+    /// Each locked apart from the rest while a chunk is written to disk.
+    pub incoming: HashMap<MessageId, Arc<Mutex<Incoming>>>,
+    /// Offers the user may accept, numbered from 1 in the order they came.
+    offers: Vec<Message>,
+    /// The files being streamed to the peer.
+    sending: HashSet<MessageId>,
+}
+
+impl Transfers {
+    pub fn new(downloads: PathBuf) -> Transfers {
+        Transfers {
+            downloads,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            offers: Vec::new(),
+            sending: HashSet::new(),
+        }
+    }
+
+    /// Keep `offer` for the user to accept and return its number. The same
+    /// offer made again keeps its number.
+    pub fn offered(&mut self, offer: &Message) -> usize {
+        match self.offers.iter().position(|o| o.id() == offer.id()) {
+            Some(i) => i + 1,
+            None => {
+                self.offers.push(offer.clone());
+                self.offers.len()
+            }
+        }
+    }
+
+    /// The offer numbered `n`.
+    pub fn offer(&self, n: usize) -> Option<&Message> {
+        self.offers.get(n.checked_sub(1)?)
+    }
+
+    /// Note that `file` is being streamed; false if it already is.
+    pub fn start_sending(&mut self, file: MessageId) -> bool {
+        self.sending.insert(file)
+    }
+
+    pub fn stop_sending(&mut self, file: MessageId) {
+        self.sending.remove(&file);
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("larger than the {} GiB allowed", MAX_SIZE >> 30),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send `outgoing` to `incoming` from chunk `from` until `until`.
+    fn stream(outgoing: &Outgoing, incoming: &mut Incoming, until: u64) -> Progress {
+        let mut file = outgoing.open().unwrap();
+        let mut progress = Progress::Skipped;
+        for index in incoming.next()..until {
+            let Kind::Chunk {
+                file: id,
+                index,
+                data,
+            } = outgoing.chunk(&mut file, index).unwrap()
+            else {
+                unreachable!()
+            };
+            assert_eq!(id, outgoing.id);
+            progress = incoming.add(index, &data).unwrap();
+        }
+        progress
+    }
+
+    #[test]
+    fn transfers_and_resumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        let contents: Vec<u8> = (0..CHUNK_LEN * 3 + 5).map(|i| i as u8).collect();
+        fs::write(&path, &contents).unwrap();
+        let downloads = dir.path().join("downloads");
+
+        let (outgoing, offer, new) = Outgoing::offer(&path, "alice", &[]).unwrap();
+        assert!(new);
+        assert_eq!(outgoing.chunks(), 4);
+        let mut incoming = Incoming::new(&downloads, &offer).unwrap().unwrap();
+        assert_eq!(
+            stream(&outgoing, &mut incoming, 2),
+            Progress::Partial { done: 2, of: 4 }
+        );
+
+        // Offered again after a disconnect, the same offer goes out and the
+        // download picks up at the third chunk.
+        let (outgoing, again, new) =
+            Outgoing::offer(&path, "alice", std::slice::from_ref(&offer)).unwrap();
+        assert!(!new && again == offer);
+        let mut incoming = Incoming::new(&downloads, &again).unwrap().unwrap();
+        assert_eq!(incoming.next(), 2);
+        let mut file = outgoing.open().unwrap();
+        let Kind::Chunk { data, .. } = outgoing.chunk(&mut file, 3).unwrap() else {
+            unreachable!()
+        };
+        assert_eq!(incoming.add(3, &data).unwrap(), Progress::Skipped);
+        let saved = downloads.join("notes.txt");
+        assert_eq!(
+            stream(&outgoing, &mut incoming, 4),
+            Progress::Saved(saved.clone())
+        );
+        assert_eq!(fs::read(&saved).unwrap(), contents);
+
+        // A second download of the same name does not overwrite the first.
+        let mut incoming = Incoming::new(&downloads, &offer).unwrap().unwrap();
+        assert_eq!(
+            stream(&outgoing, &mut incoming, 4),
+            Progress::Saved(downloads.join("notes (1).txt"))
+        );
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.bin");
+        fs::write(&path, vec![7; CHUNK_LEN as usize + 1]).unwrap();
+        let downloads = dir.path().join("downloads");
+        let (outgoing, offer, _) = Outgoing::offer(&path, "alice", &[]).unwrap();
+        let mut file = outgoing.open().unwrap();
+
+        // A chunk moved to another index does not decrypt.
+        let mut incoming = Incoming::new(&downloads, &offer).unwrap().unwrap();
+        let Kind::Chunk { data, .. } = outgoing.chunk(&mut file, 1).unwrap() else {
+            unreachable!()
+        };
+        assert!(incoming.add(0, &data).is_err());
+
+        // A file changed after it was offered fails the hash check.
+        fs::write(&path, vec![8; CHUNK_LEN as usize + 1]).unwrap();
+        let mut file = outgoing.open().unwrap();
+        for index in 0..2 {
+            let Kind::Chunk { data, .. } = outgoing.chunk(&mut file, index).unwrap() else {
+                unreachable!()
+            };
+            let result = incoming.add(index, &data);
+            if index == 1 {
+                assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+            }
+        }
+        assert_eq!(fs::read_dir(&downloads).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn offers_need_accepting_and_a_limited_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let downloads = dir.path().join("downloads");
+        let offer = |size| {
+            let kind = Kind::Offer {
+                name: "big.iso".into(),
+                size,
+                hash: [0; 32],
+                key: FileKey::generate(),
+            };
+            Message::new("mallory", kind)
+        };
+
+        let huge = offer(MAX_SIZE + 1);
+        let err = Incoming::new(&downloads, &huge).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!downloads.exists());
+        assert!(Incoming::new(&downloads, &offer(MAX_SIZE)).is_ok());
+
+        let mut transfers = Transfers::new(downloads);
+        let (first, second) = (offer(1), offer(2));
+        assert_eq!(transfers.offered(&first), 1);
+        assert_eq!(transfers.offered(&second), 2);
+        assert_eq!(transfers.offered(&first), 1);
+        assert!(transfers.offer(2) == Some(&second));
+        assert!(transfers.offer(0).is_none() && transfers.offer(3).is_none());
+
+        // A file is streamed once at a time, however often it is fetched.
+        assert!(transfers.start_sending(first.id()));
+        assert!(!transfers.start_sending(first.id()));
+        transfers.stop_sending(first.id());
+        assert!(transfers.start_sending(first.id()));
+    }
+
+    #[test]
+    fn milestones() {
+        let reported: Vec<u64> = (1..=20).filter_map(|done| milestone(done, 20)).collect();
+        assert_eq!(reported, [10, 20, 30, 40, 50, 60, 70, 80, 90, 100]);
+        assert_eq!(milestone(1, 1), Some(100));
+    }
+}
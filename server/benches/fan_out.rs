@@ -0,0 +1,132 @@
+//! Compare allocations and throughput of broadcasting to a 1000 member room
+//! with a shared buffer against copying it for every recipient.
+//!
+//! Run with `cargo bench -p server --bench fan_out`.
+//!
+//! The server's modules are compiled into this binary, so the counting
+//! allocator below only replaces the system one here.
+
+#![allow(dead_code)]
+// Checking all targets sets `cfg(test)` without compiling the modules' tests,
+// which leaves their imports unused.
+#![cfg_attr(test, allow(unused_imports))]
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tokio::{io::AsyncWriteExt, sync::watch};
+
+#[path = "../src/accounts.rs"]
+mod accounts;
+#[path = "../src/admission.rs"]
+mod admission;
+#[path = "../src/config.rs"]
+mod config;
+#[path = "../src/frame.rs"]
+mod frame;
+#[path = "../src/handle_connection.rs"]
+mod handle_connection;
+#[path = "../src/metrics.rs"]
+mod metrics;
+#[path = "../src/moderation.rs"]
+mod moderation;
+#[path = "../src/queue.rs"]
+mod queue;
+#[path = "../src/ratelimit.rs"]
+mod ratelimit;
+#[path = "../src/shards.rs"]
+mod shards;
+#[path = "../src/transport.rs"]
+mod transport;
+
+use handle_connection::{Member, PeerAddr, Shared, DEFAULT_ROOM};
+use queue::Policy;
+
+const PEERS: u16 = 1000;
+const ROUNDS: usize = 100;
+
+/// Counts every heap allocation made by this binary.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Fan `ROUNDS` messages out to a room of `PEERS` members whose queues are
+/// drained into a socket-like sink, and return the elapsed time and the
+/// number of allocations made while doing so.
+///
+/// `copy` selects the old behaviour of giving every recipient its own copy
+/// of the message.
+async fn fan_out(copy: bool) -> (Duration, usize) {
+    let shared = Shared::new(ROUNDS, Policy::DropOldest);
+    let mut txs = Vec::new();
+    let mut writers = Vec::new();
+    for port in 0..PEERS {
+        let (tx, mut rx) = queue::channel(ROUNDS, Policy::DropOldest);
+        let addr = PeerAddr::from(SocketAddr::from(([127, 0, 0, 1], port + 10000)));
+        let member = Member::new(tx.clone(), Arc::from("bench"), watch::channel(None).0);
+        shared.insert(addr, member);
+        txs.push(tx);
+        writers.push(tokio::spawn(async move {
+            let mut socket = tokio::io::sink();
+            for _ in 0..ROUNDS {
+                let mut msg = rx.recv().await.unwrap();
+                socket.write_all_buf(&mut msg).await.unwrap();
+            }
+        }));
+    }
+    let sender = PeerAddr::from(SocketAddr::from(([127, 0, 0, 1], 1)));
+    let message = vec![0u8; 1024];
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let message = Bytes::from(message.clone());
+        if copy {
+            for tx in &txs {
+                let _ = tx.push(Bytes::copy_from_slice(&message));
+            }
+        } else {
+            shared.broadcast(sender, DEFAULT_ROOM, &message);
+        }
+    }
+    for writer in writers {
+        writer.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    (elapsed, ALLOCATIONS.load(Ordering::Relaxed) - allocations)
+}
+
+#[tokio::main]
+async fn main() {
+    for (name, copy) in [("copied", true), ("shared", false)] {
+        let (elapsed, allocations) = fan_out(copy).await;
+        println!(
+            "{}: {} allocations, {:.0} deliveries/s",
+            name,
+            allocations,
+            (PEERS as usize * ROUNDS) as f64 / elapsed.as_secs_f64(),
+        );
+    }
+}
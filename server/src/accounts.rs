@@ -0,0 +1,239 @@
+//! Which identity key each account belongs to.
+//!
+//! A client proves who it is by asking for a `Challenge` and signing it,
+//! together with the account and room it joins, with its identity key. The
+//! first key an account is seen with is bound to it and kept in
+//! `accounts.toml` in the storage directory; from then on only that key may
+//! use the account. Clients without a key may still join as guests under
+//! a name nobody has bound, but they never hold a role above member.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use rand_core::{OsRng, RngCore};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+/// The name of the file account keys are kept in.
+pub const FILE: &str = "accounts.toml";
+
+/// Length of the random challenge a client signs.
+pub const CHALLENGE_LEN: usize = 32;
+
+/// Length of a compressed SEC1 identity key.
+const IDENTITY_LEN: usize = 33;
+/// Length of a fixed size ECDSA signature.
+const SIGNATURE_LEN: usize = 64;
+/// Length of the proof at the end of a signed `Hello`: the identity key
+/// followed by the signature.
+pub const PROOF_LEN: usize = IDENTITY_LEN + SIGNATURE_LEN;
+
+/// Keeps signatures over a `Hello` from being mistaken for any other
+/// signature made with the same key.
+const CONTEXT: &[u8] = b"chat hello\0";
+
+/// A fresh challenge for a client to sign.
+pub fn challenge() -> [u8; CHALLENGE_LEN] {
+    let mut challenge = [0; CHALLENGE_LEN];
+    OsRng.fill_bytes(&mut challenge);
+    challenge
+}
+
+/// What a client signs to join `room` as `account`: the challenge it was
+/// given and both names, each preceded by its length.
+fn signed(challenge: &[u8], account: &str, room: &str) -> Vec<u8> {
+    let mut signed = Vec::with_capacity(CONTEXT.len() + challenge.len() + 2 + 255 * 2);
+    signed.extend_from_slice(CONTEXT);
+    signed.extend_from_slice(challenge);
+    signed.push(account.len() as u8);
+    signed.extend_from_slice(account.as_bytes());
+    signed.push(room.len() as u8);
+    signed.extend_from_slice(room.as_bytes());
+    signed
+}
+
+/// Check the `proof` of a `Hello` for `account` and `room` against the
+/// `challenge` the client was given, and return the key that signed it.
+pub fn verify(
+    challenge: Option<&[u8; CHALLENGE_LEN]>,
+    account: &str,
+    room: &str,
+    proof: &[u8],
+) -> Result<VerifyingKey, &'static str> {
+    let challenge = challenge.ok_or("ask for a challenge before signing in")?;
+    if proof.len() != PROOF_LEN {
+        return Err("invalid identity proof");
+    }
+    let (identity, signature) = proof.split_at(IDENTITY_LEN);
+    let identity = VerifyingKey::from_sec1_bytes(identity).map_err(|_| "invalid identity key")?;
+    let signature = Signature::from_slice(signature).map_err(|_| "invalid signature")?;
+    identity
+        .verify(&signed(challenge, account, room), &signature)
+        .map_err(|_| "the signature does not match")?;
+    Ok(identity)
+}
+
+/// The identity key of every account that has signed in with one.
+pub struct Accounts {
+    /// Where the keys are saved; nowhere if `None`.
+    path: Option<PathBuf>,
+    /// Compressed SEC1 keys in hex, by account.
+    keys: RwLock<BTreeMap<String, String>>,
+}
+
+impl Default for Accounts {
+    fn default() -> Self {
+        Accounts {
+            path: None,
+            keys: RwLock::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Accounts {
+    /// Load the keys saved in `dir`, if there are any, and save new ones
+    /// there.
+    pub fn load(dir: &Path) -> io::Result<Accounts> {
+        let path = dir.join(FILE);
+        let keys = match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), e),
+                )
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Accounts {
+            path: Some(path),
+            keys: RwLock::new(keys),
+        })
+    }
+
+    /// Write the keys to a temporary file and move it in place, so a crash
+    /// never leaves half a file behind.
+    fn save(&self, keys: &BTreeMap<String, String>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = toml::to_string(keys)
+            .map_err(io::Error::other)
+            .and_then(|text| {
+                let temp = path.with_extension("toml.tmp");
+                fs::write(&temp, text)?;
+                fs::rename(&temp, path)
+            });
+        if let Err(e) = result {
+            error!("Failed to save {}: {}", path.display(), e);
+        }
+    }
+
+    /// Whether a client that proved it holds `identity`, or none, may use
+    /// `account`. Returns whether the account is authenticated; the first
+    /// key an account is used with is bound to it.
+    pub fn sign_in(
+        &self,
+        account: &str,
+        identity: Option<&VerifyingKey>,
+    ) -> Result<bool, &'static str> {
+        let key = identity.map(|identity| hex(identity.to_encoded_point(true).as_bytes()));
+        {
+            let keys = self.keys.read().unwrap();
+            match (keys.get(account), &key) {
+                (Some(bound), Some(key)) if bound == key => return Ok(true),
+                (Some(_), Some(_)) => return Err("this account belongs to another identity key"),
+                (Some(_), None) => return Err("this account requires its identity key"),
+                (None, None) => return Ok(false),
+                (None, Some(_)) => (),
+            }
+        }
+
+        let mut keys = self.keys.write().unwrap();
+        let key = key.expect("only keys are bound");
+        // Someone else may have bound it since the read lock was dropped.
+        let bound = keys.entry(account.to_owned()).or_insert_with(|| {
+            info!("{} is bound to its identity key", account);
+            key.clone()
+        });
+        if *bound != key {
+            return Err("this account belongs to another identity key");
+        }
+        self.save(&keys);
+        Ok(true)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Sign in to `room` as `account` with `key`, as a client would, and return
+/// the proof for its `Hello`.
+#[cfg(test)]
+pub fn prove(
+    key: &p256::ecdsa::SigningKey,
+    challenge: &[u8],
+    account: &str,
+    room: &str,
+) -> Vec<u8> {
+    use p256::ecdsa::signature::Signer;
+
+    let signature: Signature = key.sign(&signed(challenge, account, room));
+    let identity = key.verifying_key().to_encoded_point(true);
+    [identity.as_bytes(), &signature.to_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::SigningKey;
+
+    #[test]
+    fn proofs() {
+        let key = SigningKey::random(&mut OsRng);
+        let challenge = challenge();
+        let proof = prove(&key, &challenge, "alice", "rust");
+
+        assert_eq!(
+            verify(Some(&challenge), "alice", "rust", &proof),
+            Ok(*key.verifying_key())
+        );
+        assert!(verify(None, "alice", "rust", &proof).is_err());
+        // A proof is only good for the challenge and names it was made for.
+        assert!(verify(Some(&self::challenge()), "alice", "rust", &proof).is_err());
+        assert!(verify(Some(&challenge), "mallory", "rust", &proof).is_err());
+        assert!(verify(Some(&challenge), "alice", "go", &proof).is_err());
+        assert!(verify(Some(&challenge), "alice", "rust", &proof[1..]).is_err());
+
+        // Clients sign exactly these bytes.
+        assert_eq!(
+            signed(&[1; 32], "alice", "rust"),
+            [&b"chat hello\0"[..], &[1; 32], b"\x05alice\x04rust"].concat()
+        );
+    }
+
+    #[test]
+    fn accounts_are_bound_to_their_first_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = *SigningKey::random(&mut OsRng).verifying_key();
+        let mallory = *SigningKey::random(&mut OsRng).verifying_key();
+
+        let accounts = Accounts::load(dir.path()).unwrap();
+        assert_eq!(accounts.sign_in("guest", None), Ok(false));
+        assert_eq!(accounts.sign_in("alice", Some(&alice)), Ok(true));
+        assert_eq!(accounts.sign_in("alice", Some(&alice)), Ok(true));
+        assert!(accounts.sign_in("alice", Some(&mallory)).is_err());
+        assert!(accounts.sign_in("alice", None).is_err());
+
+        // The binding survives a restart.
+        let accounts = Accounts::load(dir.path()).unwrap();
+        assert_eq!(accounts.sign_in("alice", Some(&alice)), Ok(true));
+        assert!(accounts.sign_in("alice", Some(&mallory)).is_err());
+    }
+}
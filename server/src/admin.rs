@@ -0,0 +1,259 @@
+//! A local control interface for the server's administrator.
+//!
+//! The server listens on a Unix socket, `admin.sock` in the storage
+//! directory by default, that only its own user may connect to. Each
+//! request is one line of text and is answered with one line of JSON,
+//! either `{"ok": <result>}` or `{"error": "<message>"}`. `chat-admin`
+//! speaks this for you, but `socat - UNIX-CONNECT:data/admin.sock` works
+//! too.
+//!
+//! Requests:
+//!
+//! - `connections`: every connected peer, its account, room and queue
+//! - `rooms`: every room with someone in it and how many
+//! - `queues`: queue depths, deepest first
+//! - `kick`, `ban`, `unban`, `mute`, `unmute`, `op`, `deop` followed by the
+//!   room and the arguments of the moderation command, e.g. `ban lobby bob`
+//! - `notice <room|*> <text>`: a notice from the server
+//! - `reload`: read the configuration again, as on SIGHUP
+//! - `shutdown`: shut down as on SIGTERM
+
+use std::{io, path::Path, sync::Arc};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+use tokio_util::sync::CancellationToken;
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use crate::handle_connection::Shared;
+use crate::moderation::Command;
+use crate::reload::Reloader;
+use crate::unix;
+
+/// Everything the administrator can act on.
+pub struct Admin {
+    pub state: Arc<Shared>,
+    pub reloader: Arc<Reloader>,
+    /// Cancelled to shut the server down.
+    pub stop: CancellationToken,
+}
+
+/// Bind the administration socket, readable and writable only by the
+/// server's user.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    unix::bind(path, 0o600)
+}
+
+/// Answer requests on `listener` until accepting fails.
+pub async fn serve(listener: UnixListener, admin: Arc<Admin>) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let admin = Arc::clone(&admin);
+        tokio::spawn(async move {
+            if let Err(e) = handle(&admin, stream).await {
+                debug!("Administration connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle(admin: &Admin, stream: UnixStream) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let request = line.trim();
+        if request.is_empty() {
+            continue;
+        }
+        info!("Administration request: {}", request);
+        let response = match admin.execute(request) {
+            Ok(result) => json!({ "ok": result }),
+            Err(message) => json!({ "error": message }),
+        };
+        let mut response = response.to_string();
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+impl Admin {
+    /// Carry out one request.
+    pub fn execute(&self, request: &str) -> Result<Value, String> {
+        let (name, rest) = request.split_once(' ').unwrap_or((request, ""));
+        let rest = rest.trim();
+
+        match name {
+            "connections" => {
+                let connections: Vec<Value> = self
+                    .state
+                    .connections()
+                    .into_iter()
+                    .map(|connection| {
+                        json!({
+                            "addr": connection.addr.to_string(),
+                            "account": &*connection.account,
+                            "room": &*connection.room,
+                            "queue": connection.queue.depth,
+                        })
+                    })
+                    .collect();
+                Ok(Value::from(connections))
+            }
+            "rooms" => Ok(self
+                .state
+                .rooms()
+                .into_iter()
+                .map(|(room, peers)| (room.to_string(), Value::from(peers)))
+                .collect()),
+            "queues" => {
+                let mut connections = self.state.connections();
+                connections.sort_by_key(|connection| std::cmp::Reverse(connection.queue.depth));
+                let queues: Vec<Value> = connections
+                    .into_iter()
+                    .map(|connection| {
+                        json!({
+                            "addr": connection.addr.to_string(),
+                            "depth": connection.queue.depth,
+                            "capacity": connection.queue.capacity,
+                            "high_water": connection.queue.high_water,
+                            "dropped": connection.queue.dropped,
+                        })
+                    })
+                    .collect();
+                Ok(Value::from(queues))
+            }
+            "kick" | "ban" | "unban" | "mute" | "unmute" | "op" | "deop" => {
+                let (room, args) = rest
+                    .split_once(' ')
+                    .ok_or_else(|| format!("usage: {} <room> <arguments>", name))?;
+                let command: Command = format!("{} {}", name, args).parse()?;
+                self.state.moderate(room, None, command).map(Value::from)
+            }
+            "notice" => {
+                let (room, text) = rest
+                    .split_once(' ')
+                    .ok_or("usage: notice <room|*> <text>")?;
+                let room = (room != "*").then_some(room);
+                Ok(json!({ "sent": self.state.notice(room, text.trim()) }))
+            }
+            "reload" => self
+                .reloader
+                .reload()
+                .map(|pending| match pending.is_empty() {
+                    true => Value::from("configuration reloaded"),
+                    false => Value::from(format!(
+                        "configuration reloaded; {} need a restart",
+                        pending.join(", ")
+                    )),
+                })
+                .map_err(|e| e.to_string()),
+            "shutdown" => {
+                self.stop.cancel();
+                Ok(Value::from("shutting down"))
+            }
+            _ => Err(format!("unknown request `{}`", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Args, Config};
+    use crate::frame::{self, Kind};
+    use crate::handle_connection::process;
+    use crate::queue::Policy;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_stream::StreamExt;
+    use tokio_util::codec::FramedRead;
+    use tracing_subscriber::EnvFilter;
+
+    struct AdminClient {
+        writer: tokio::net::unix::OwnedWriteHalf,
+        responses: tokio::io::Lines<BufReader<tokio::net::unix::OwnedReadHalf>>,
+    }
+
+    impl AdminClient {
+        async fn connect(path: &Path) -> AdminClient {
+            let (reader, writer) = UnixStream::connect(path).await.unwrap().into_split();
+            AdminClient {
+                writer,
+                responses: BufReader::new(reader).lines(),
+            }
+        }
+
+        async fn request(&mut self, line: &str) -> Value {
+            self.writer
+                .write_all(format!("{}\n", line).as_bytes())
+                .await
+                .unwrap();
+            let response = self.responses.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&response).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(Shared::new(16, Policy::DropOldest));
+        let (_, log) = tracing_subscriber::reload::Layer::new(EnvFilter::new("info"));
+        let config = Config::load(&Args::default(), |_| None).unwrap();
+        let admin = Arc::new(Admin {
+            state: state.clone(),
+            reloader: Arc::new(Reloader::new(Args::default(), config, state.clone(), log)),
+            stop: CancellationToken::new(),
+        });
+
+        let path = dir.path().join("admin.sock");
+        tokio::spawn(serve(bind(&path).unwrap(), admin.clone()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let (stream, addr) = listener.accept().await.unwrap();
+                let _ = process(state, stream, addr.into()).await;
+            });
+        }
+        let mut client = FramedRead::new(TcpStream::connect(addr).await.unwrap(), frame::codec());
+        client
+            .get_mut()
+            .write_all(&frame::encode_hello("client", "lobby", &[]))
+            .await
+            .unwrap();
+        while state.peer_count() < 1 {
+            tokio::task::yield_now().await;
+        }
+
+        let mut control = AdminClient::connect(&path).await;
+        let connections = control.request("connections").await;
+        assert_eq!(connections["ok"][0]["room"], "lobby");
+        assert_eq!(control.request("rooms").await["ok"]["lobby"], 1);
+        assert_eq!(control.request("queues").await["ok"][0]["capacity"], 1024);
+
+        assert_eq!(
+            control.request("notice * maintenance at noon").await["ok"]["sent"],
+            1
+        );
+        let notice = client.next().await.unwrap().unwrap();
+        assert_eq!(frame::kind(&notice), Some(Kind::Notice));
+        assert_eq!(&notice[frame::HEADER_LEN + 1..], b"maintenance at noon");
+
+        assert!(control.request("ban lobby").await["error"].is_string());
+        assert!(control.request("frobnicate").await["error"].is_string());
+        assert!(control.request("ban lobby 127.0.0.1").await["ok"].is_string());
+        let error = client.next().await.unwrap().unwrap();
+        assert_eq!(frame::kind(&error), Some(Kind::Error));
+        assert!(client.next().await.is_none());
+
+        assert!(!admin.stop.is_cancelled());
+        control.request("shutdown").await;
+        assert!(admin.stop.is_cancelled());
+    }
+}
@@ -0,0 +1,212 @@
+//! Deciding whether to take on a new connection at all.
+//!
+//! Connections are refused when the server is full, when one address holds
+//! too many of them, or when the address is not allowed by the CIDR lists.
+//! Every admitted connection holds a `Permit` that gives its slot back when
+//! it is dropped.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::handle_connection::PeerAddr;
+
+/// Who may connect and how many connections there may be.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    /// Connections open at once, from anywhere.
+    pub max_connections: usize,
+    /// Connections open at once from a single IP address.
+    pub max_per_ip: usize,
+    pub access: Access,
+}
+
+impl Limits {
+    pub const UNLIMITED: Limits = Limits {
+        max_connections: usize::MAX,
+        max_per_ip: usize::MAX,
+        access: Access {
+            allow: Vec::new(),
+            deny: Vec::new(),
+        },
+    };
+}
+
+/// CIDR ranges that may or may not connect. Unix socket clients are not
+/// affected.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Access {
+    /// If not empty, only these ranges may connect.
+    pub allow: Vec<IpNet>,
+    /// These ranges may never connect, even if they are allowed.
+    pub deny: Vec<IpNet>,
+}
+
+impl Access {
+    fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+    }
+}
+
+/// Why a connection was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refused {
+    /// `max_connections` are already open.
+    Full,
+    /// `max_per_ip` connections are already open from this address.
+    TooManyFromAddress,
+    /// The address is denied, or not allowed.
+    Denied,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Refused::Full => "the server is full",
+            Refused::TooManyFromAddress => "too many connections from this address",
+            Refused::Denied => "the address is not allowed to connect",
+        })
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counts open connections.
+#[derive(Default)]
+pub struct Admission {
+    counts: Mutex<Counts>,
+}
+
+/// An admitted connection's slot. Dropping it frees the slot.
+pub struct Permit {
+    admission: Arc<Admission>,
+    ip: Option<IpAddr>,
+}
+
+impl Admission {
+    /// Admit a connection from `addr` if `limits` allow it.
+    pub fn admit(self: &Arc<Self>, addr: &PeerAddr, limits: &Limits) -> Result<Permit, Refused> {
+        let ip = addr.ip();
+        if let Some(ip) = ip {
+            if !limits.access.permits(ip) {
+                return Err(Refused::Denied);
+            }
+        }
+
+        let mut counts = self.counts.lock().unwrap();
+        if counts.total >= limits.max_connections {
+            return Err(Refused::Full);
+        }
+        if let Some(ip) = ip {
+            let count = counts.per_ip.entry(ip).or_default();
+            if *count >= limits.max_per_ip {
+                return Err(Refused::TooManyFromAddress);
+            }
+            *count += 1;
+        }
+        counts.total += 1;
+
+        Ok(Permit {
+            admission: Arc::clone(self),
+            ip,
+        })
+    }
+
+    /// Number of connections holding a permit.
+    pub fn len(&self) -> usize {
+        self.counts.lock().unwrap().total
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut counts = self.admission.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(ip) = self.ip {
+            if let Some(count) = counts.per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn inet(addr: &str) -> PeerAddr {
+        PeerAddr::Inet(addr.parse::<SocketAddr>().unwrap())
+    }
+
+    #[test]
+    fn connection_limits() {
+        let admission = Arc::new(Admission::default());
+        let limits = Limits {
+            max_connections: 3,
+            max_per_ip: 2,
+            ..Limits::UNLIMITED
+        };
+
+        let first = admission.admit(&inet("10.0.0.1:1000"), &limits).unwrap();
+        let _second = admission.admit(&inet("10.0.0.1:1001"), &limits).unwrap();
+        assert_eq!(
+            admission
+                .admit(&inet("[::ffff:10.0.0.1]:1002"), &limits)
+                .err(),
+            Some(Refused::TooManyFromAddress)
+        );
+        let _third = admission
+            .admit(&PeerAddr::Unix { id: 1, uid: 0 }, &limits)
+            .unwrap();
+        assert_eq!(
+            admission.admit(&inet("10.0.0.2:1000"), &limits).err(),
+            Some(Refused::Full)
+        );
+
+        drop(first);
+        assert_eq!(admission.len(), 2);
+        admission.admit(&inet("10.0.0.1:1003"), &limits).unwrap();
+    }
+
+    #[test]
+    fn cidr_lists() {
+        let admission = Arc::new(Admission::default());
+        let limits = Limits {
+            access: Access {
+                allow: vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()],
+                deny: vec!["10.6.0.0/16".parse().unwrap()],
+            },
+            ..Limits::UNLIMITED
+        };
+
+        assert!(admission.admit(&inet("10.1.2.3:1"), &limits).is_ok());
+        assert!(admission.admit(&inet("[::1]:1"), &limits).is_ok());
+        assert_eq!(
+            admission.admit(&inet("10.6.0.1:1"), &limits).err(),
+            Some(Refused::Denied)
+        );
+        assert_eq!(
+            admission.admit(&inet("192.168.0.1:1"), &limits).err(),
+            Some(Refused::Denied)
+        );
+        assert!(admission
+            .admit(&PeerAddr::Unix { id: 1, uid: 0 }, &limits)
+            .is_ok());
+    }
+}
@@ -0,0 +1,184 @@
+//! Inspect and control a running chat server through its administration
+//! socket.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use clap::{Parser, Subcommand};
+use serde_json::Value;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Administer a running chat server")]
+struct Cli {
+    /// The server's administration socket
+    #[arg(
+        short,
+        long,
+        env = "ADMIN_SOCKET",
+        value_name = "PATH",
+        default_value = "data/admin.sock"
+    )]
+    socket: PathBuf,
+
+    /// Print the server's JSON response as it is
+    #[arg(long)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List connected peers
+    Connections,
+    /// List rooms with peers in them
+    Rooms,
+    /// List outbound queues, deepest first
+    Queues,
+    /// Disconnect an account from a room
+    Kick { room: String, account: String },
+    /// Disconnect an account or address (CIDR ranges too) and keep it out
+    Ban { room: String, target: String },
+    /// Lift a ban
+    Unban { room: String, target: String },
+    /// Refuse an account's messages for a while, e.g. 90s, 15m, 2h or 1d
+    Mute {
+        room: String,
+        account: String,
+        duration: String,
+    },
+    /// Lift a mute
+    Unmute { room: String, account: String },
+    /// Make an account an operator of a room
+    Op { room: String, account: String },
+    /// Take an account's operator role away
+    Deop { room: String, account: String },
+    /// Send a notice from the server
+    Notice {
+        /// Only to this room; to everyone when omitted
+        #[arg(short, long)]
+        room: Option<String>,
+        text: Vec<String>,
+    },
+    /// Read the configuration again
+    Reload,
+    /// Shut the server down
+    Shutdown,
+}
+
+impl Command {
+    /// The request line for the server.
+    fn request(&self) -> String {
+        match self {
+            Command::Connections => "connections".to_owned(),
+            Command::Rooms => "rooms".to_owned(),
+            Command::Queues => "queues".to_owned(),
+            Command::Kick { room, account } => format!("kick {} {}", room, account),
+            Command::Ban { room, target } => format!("ban {} {}", room, target),
+            Command::Unban { room, target } => format!("unban {} {}", room, target),
+            Command::Mute {
+                room,
+                account,
+                duration,
+            } => format!("mute {} {} {}", room, account, duration),
+            Command::Unmute { room, account } => format!("unmute {} {}", room, account),
+            Command::Op { room, account } => format!("op {} {}", room, account),
+            Command::Deop { room, account } => format!("deop {} {}", room, account),
+            Command::Notice { room, text } => format!(
+                "notice {} {}",
+                room.as_deref().unwrap_or("*"),
+                text.join(" ")
+            ),
+            Command::Reload => "reload".to_owned(),
+            Command::Shutdown => "shutdown".to_owned(),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(&cli) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{}: {}", cli.socket.display(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Send the request and print the response. Returns whether the server
+/// carried it out.
+fn run(cli: &Cli) -> io::Result<bool> {
+    let mut stream = UnixStream::connect(&cli.socket)?;
+    let mut request = cli.command.request().replace('\n', " ");
+    request.push('\n');
+    stream.write_all(request.as_bytes())?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let response: Value =
+        serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    if let Some(error) = response.get("error") {
+        eprintln!("{}", error.as_str().unwrap_or_default());
+        return Ok(false);
+    }
+    let result = &response["ok"];
+    if cli.json {
+        println!("{}", result);
+        return Ok(true);
+    }
+
+    match &cli.command {
+        Command::Connections => {
+            println!(
+                "{:<28} {:<20} {:<20} {:>6}",
+                "ADDRESS", "ACCOUNT", "ROOM", "QUEUE"
+            );
+            for connection in result.as_array().into_iter().flatten() {
+                println!(
+                    "{:<28} {:<20} {:<20} {:>6}",
+                    text(&connection["addr"]),
+                    text(&connection["account"]),
+                    text(&connection["room"]),
+                    connection["queue"].to_string(),
+                );
+            }
+        }
+        Command::Rooms => {
+            println!("{:<20} {:>6}", "ROOM", "PEERS");
+            for (room, peers) in result.as_object().into_iter().flatten() {
+                println!("{:<20} {:>6}", room, peers.to_string());
+            }
+        }
+        Command::Queues => {
+            println!(
+                "{:<28} {:>6} {:>8} {:>10} {:>8}",
+                "ADDRESS", "DEPTH", "CAPACITY", "HIGH WATER", "DROPPED"
+            );
+            for queue in result.as_array().into_iter().flatten() {
+                println!(
+                    "{:<28} {:>6} {:>8} {:>10} {:>8}",
+                    text(&queue["addr"]),
+                    queue["depth"].to_string(),
+                    queue["capacity"].to_string(),
+                    queue["high_water"].to_string(),
+                    queue["dropped"].to_string(),
+                );
+            }
+        }
+        Command::Notice { .. } => println!("Sent to {} peers", result["sent"]),
+        _ => println!("{}", text(result)),
+    }
+    Ok(true)
+}
+
+fn text(value: &Value) -> &str {
+    value.as_str().unwrap_or_default()
+}
@@ -0,0 +1,785 @@
+//! The server's settings, layered from lowest to highest precedence:
+//!
+//! 1. built-in defaults,
+//! 2. a TOML file (`--config`, `CONFIG`, or `server.toml` if it exists),
+//! 3. environment variables, also read from `.env`,
+//! 4. command line flags.
+//!
+//! Every error names the setting it is about, so a typo does not end in a
+//! panic somewhere deep in startup.
+//!
+//! On SIGHUP the layers are read again; see `reload`.
+
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::Parser;
+use serde::{Deserialize, Deserializer};
+use tracing::{level_filters::LevelFilter, Level};
+use tracing_subscriber::EnvFilter;
+
+use crate::admission::{self, Access};
+use crate::frame;
+use crate::handle_connection::Settings;
+use crate::queue::Policy;
+use crate::ratelimit::RateLimits;
+
+/// The config file used when none is given and it exists.
+const DEFAULT_FILE: &str = "server.toml";
+
+/// Command line flags. Every flag overrides the config file and environment.
+#[derive(Parser, Debug, Default)]
+#[command(version, about = "An end-to-end encrypted chat relay")]
+pub struct Args {
+    /// TOML config file [default: server.toml if it exists]
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Address to accept framed TCP clients on
+    #[arg(long, value_name = "ADDR")]
+    pub address: Option<SocketAddr>,
+    /// Address to accept WebSocket clients on
+    #[arg(long, value_name = "ADDR")]
+    pub websocket: Option<SocketAddr>,
+    /// Path of a Unix socket to accept local clients on
+    #[arg(long, value_name = "PATH")]
+    pub unix_socket: Option<PathBuf>,
+    /// PEM certificate chain to serve TLS with
+    #[arg(long, value_name = "FILE")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of the TLS certificate
+    #[arg(long, value_name = "FILE")]
+    pub tls_key: Option<PathBuf>,
+    /// Directory the server keeps its state in
+    #[arg(long, value_name = "DIR")]
+    pub storage: Option<PathBuf>,
+    /// Address to serve Prometheus metrics on, at /metrics
+    #[arg(long, value_name = "ADDR")]
+    pub metrics: Option<SocketAddr>,
+    /// Path of the Unix socket `chat-admin` connects to
+    #[arg(long, value_name = "PATH")]
+    pub admin_socket: Option<PathBuf>,
+    /// Most log level to print: error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<Level>,
+    /// Log line format: full, compact, pretty or json
+    #[arg(long, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// Per-module log directives, e.g. `server::admin=debug`
+    #[arg(long, value_name = "DIRECTIVES")]
+    pub log_filter: Option<String>,
+    /// Directory to write rotating log files to, instead of standard output
+    #[arg(long, value_name = "DIR")]
+    pub log_dir: Option<PathBuf>,
+}
+
+/// All of the server's settings.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Message of the day, sent to every client when it connects.
+    pub motd: Option<String>,
+    pub listen: Listen,
+    pub tls: Tls,
+    pub storage: Storage,
+    pub admin: Admin,
+    pub metrics: Metrics,
+    pub limits: Limits,
+    pub access: Access,
+    pub timeouts: Timeouts,
+    pub log: Log,
+    /// Settings of individual rooms, by name.
+    pub rooms: HashMap<String, Room>,
+}
+
+/// Where clients can connect.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Listen {
+    /// Framed TCP clients.
+    pub address: SocketAddr,
+    /// WebSocket clients, if any.
+    pub websocket: Option<SocketAddr>,
+    /// Local clients on a Unix domain socket, if any.
+    pub unix_socket: Option<PathBuf>,
+    /// File mode of the Unix socket, which decides who may connect.
+    pub unix_socket_mode: u32,
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Listen {
+            address: SocketAddr::from(([127, 0, 0, 1], 6142)),
+            websocket: None,
+            unix_socket: None,
+            unix_socket_mode: 0o660,
+        }
+    }
+}
+
+/// TLS is served on every TCP listener when both files are given.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+/// Where the server keeps state that outlives it.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Storage {
+    pub path: PathBuf,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage {
+            path: PathBuf::from("data"),
+        }
+    }
+}
+
+/// The administration socket, see `admin`.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Admin {
+    pub enabled: bool,
+    /// Where the socket is; `admin.sock` in the storage directory if unset.
+    pub socket: Option<PathBuf>,
+}
+
+impl Default for Admin {
+    fn default() -> Self {
+        Admin {
+            enabled: true,
+            socket: None,
+        }
+    }
+}
+
+/// The Prometheus endpoint.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Metrics {
+    /// Where `/metrics` is served; nowhere if unset.
+    pub address: Option<SocketAddr>,
+}
+
+/// Bounds on what a single peer may cost the server.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Messages held for a peer that is not keeping up.
+    pub queue_capacity: usize,
+    /// What happens once that queue is full.
+    #[serde(deserialize_with = "parse")]
+    pub queue_policy: Policy,
+    /// How fast peers may send, in rooms without their own limits.
+    pub rate: RateLimits,
+    /// Connections open at once.
+    pub max_connections: usize,
+    /// Connections open at once from a single IP address.
+    pub max_connections_per_ip: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            queue_capacity: 1024,
+            queue_policy: Policy::DropOldest,
+            rate: RateLimits::default(),
+            max_connections: 10_000,
+            max_connections_per_ip: 32,
+        }
+    }
+}
+
+/// Settings of a single room.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Room {
+    /// The account that owns the room, instead of whoever joined it first.
+    pub owner: Option<String>,
+    /// Replaces `limits.rate` in this room.
+    pub rate: Option<RateLimits>,
+}
+
+/// Timeouts, in seconds.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// How long connections get to flush their queues on shutdown.
+    pub shutdown: u64,
+    /// How long a client has from connecting to sending its first frame,
+    /// including the TLS handshake and WebSocket upgrade. 0 waits forever.
+    pub handshake: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            shutdown: 10,
+            handshake: 10,
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn shutdown(&self) -> Duration {
+        Duration::from_secs(self.shutdown)
+    }
+
+    pub fn handshake(&self) -> Option<Duration> {
+        (self.handshake > 0).then(|| Duration::from_secs(self.handshake))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+    /// Level of everything `filter` does not mention.
+    #[serde(deserialize_with = "parse")]
+    pub level: Level,
+    /// `EnvFilter` directives for single modules, e.g.
+    /// `server::admin=debug,tokio_tungstenite=warn`.
+    pub filter: Option<String>,
+    pub format: LogFormat,
+    /// Write to rotating files instead of standard output.
+    pub file: Option<LogFile>,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log {
+            level: Level::INFO,
+            filter: None,
+            format: LogFormat::Full,
+            file: None,
+        }
+    }
+}
+
+impl Log {
+    /// The filter for `level` with the `filter` directives on top.
+    pub fn env_filter(&self) -> Result<EnvFilter, ConfigError> {
+        EnvFilter::builder()
+            .with_default_directive(LevelFilter::from_level(self.level).into())
+            .parse(self.filter.as_deref().unwrap_or_default())
+            .map_err(|e| invalid("log.filter", e))
+    }
+}
+
+/// Log files, named `<prefix>.<date>` and started anew every `rotation`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogFile {
+    pub directory: PathBuf,
+    pub prefix: String,
+    pub rotation: Rotation,
+}
+
+impl Default for LogFile {
+    fn default() -> Self {
+        LogFile {
+            directory: PathBuf::from("logs"),
+            prefix: "server.log".to_owned(),
+            rotation: Rotation::Daily,
+        }
+    }
+}
+
+/// How often a new log file is started.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "minutely" => Ok(Rotation::Minutely),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            "never" => Ok(Rotation::Never),
+            _ => Err(format!(
+                "unknown rotation `{}`, expected one of: minutely, hourly, daily, never",
+                s
+            )),
+        }
+    }
+}
+
+/// How log lines are laid out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One line per event with all of its fields.
+    #[default]
+    Full,
+    /// One shorter line per event.
+    Compact,
+    /// Multiple lines per event, for reading by a human.
+    Pretty,
+    /// One JSON object per event, for log collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <LogFormat as clap::ValueEnum>::from_str(s, true).map_err(|_| {
+            format!(
+                "unknown log format `{}`, expected one of: full, compact, pretty, json",
+                s
+            )
+        })
+    }
+}
+
+/// A setting that could not be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read.
+    Read(PathBuf, io::Error),
+    /// The config file is not valid; the message names the key.
+    Parse(PathBuf, Box<toml::de::Error>),
+    /// A setting has a value that can not be used.
+    Invalid { key: String, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Invalid { key, message } => write!(f, "{}: {}", key, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn invalid(key: impl Into<String>, message: impl fmt::Display) -> ConfigError {
+    ConfigError::Invalid {
+        key: key.into(),
+        message: message.to_string(),
+    }
+}
+
+impl Config {
+    /// Load the settings from every layer. `env` looks up an environment
+    /// variable.
+    pub fn load(args: &Args, env: impl Fn(&str) -> Option<String>) -> Result<Config, ConfigError> {
+        let file = args
+            .config
+            .clone()
+            .or_else(|| env("CONFIG").map(PathBuf::from));
+        let mut config = match file {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_FILE).exists() => Config::from_file(Path::new(DEFAULT_FILE))?,
+            None => Config::default(),
+        };
+
+        config.apply_env(env)?;
+        config.apply_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The settings applied to connected peers.
+    pub fn settings(&self) -> Settings {
+        Settings {
+            queue_capacity: self.limits.queue_capacity,
+            queue_policy: self.limits.queue_policy,
+            motd: self.motd.clone(),
+            rate: self.limits.rate.clone(),
+            rooms: self.rooms.clone(),
+            admission: admission::Limits {
+                max_connections: self.limits.max_connections,
+                max_per_ip: self.limits.max_connections_per_ip,
+                access: self.access.clone(),
+            },
+            handshake_timeout: self.timeouts.handshake(),
+        }
+    }
+
+    /// The settings that only take effect when the server is restarted and
+    /// differ between `self` and `other`.
+    pub fn restart_required(&self, other: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.listen != other.listen {
+            changed.push("listen");
+        }
+        if self.tls != other.tls {
+            changed.push("tls");
+        }
+        if self.storage != other.storage {
+            changed.push("storage");
+        }
+        if self.admin != other.admin {
+            changed.push("admin");
+        }
+        if self.metrics != other.metrics {
+            changed.push("metrics");
+        }
+        if self.log.format != other.log.format {
+            changed.push("log.format");
+        }
+        if self.log.file != other.log.file {
+            changed.push("log.file");
+        }
+        changed
+    }
+
+    /// Take the settings that only take effect on a restart back from the
+    /// `running` configuration, so `self` holds what is actually in effect.
+    pub fn keep_restart_only(&mut self, running: &mut Config) {
+        std::mem::swap(&mut self.listen, &mut running.listen);
+        std::mem::swap(&mut self.tls, &mut running.tls);
+        std::mem::swap(&mut self.storage, &mut running.storage);
+        std::mem::swap(&mut self.admin, &mut running.admin);
+        std::mem::swap(&mut self.metrics, &mut running.metrics);
+        std::mem::swap(&mut self.log.format, &mut running.log.format);
+        std::mem::swap(&mut self.log.file, &mut running.log.file);
+    }
+
+    /// Where the administration socket is, if it is enabled.
+    pub fn admin_socket(&self) -> Option<PathBuf> {
+        self.admin.enabled.then(|| {
+            self.admin
+                .socket
+                .clone()
+                .unwrap_or_else(|| self.storage.path.join("admin.sock"))
+        })
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.into(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.into(), Box::new(e)))
+    }
+
+    /// Override settings with the environment variables the server has
+    /// always read.
+    fn apply_env(&mut self, env: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        fn set<T: FromStr>(
+            env: &impl Fn(&str) -> Option<String>,
+            var: &str,
+            target: &mut T,
+        ) -> Result<(), ConfigError>
+        where
+            T::Err: fmt::Display,
+        {
+            if let Some(value) = env(var) {
+                *target = value.parse().map_err(|e| invalid(var, e))?;
+            }
+            Ok(())
+        }
+        fn set_some<T: FromStr>(
+            env: &impl Fn(&str) -> Option<String>,
+            var: &str,
+            target: &mut Option<T>,
+        ) -> Result<(), ConfigError>
+        where
+            T::Err: fmt::Display,
+        {
+            if let Some(value) = env(var) {
+                *target = Some(value.parse().map_err(|e| invalid(var, e))?);
+            }
+            Ok(())
+        }
+
+        set_some(&env, "MOTD", &mut self.motd)?;
+        set(&env, "ADDRESS", &mut self.listen.address)?;
+        set_some(&env, "WS_ADDRESS", &mut self.listen.websocket)?;
+        set_some(&env, "UNIX_SOCKET", &mut self.listen.unix_socket)?;
+        if let Some(mode) = env("UNIX_SOCKET_MODE") {
+            self.listen.unix_socket_mode = u32::from_str_radix(&mode, 8)
+                .map_err(|_| invalid("UNIX_SOCKET_MODE", "must be an octal file mode"))?;
+        }
+        set_some(&env, "TLS_CERT", &mut self.tls.cert)?;
+        set_some(&env, "TLS_KEY", &mut self.tls.key)?;
+        set(&env, "STORAGE_PATH", &mut self.storage.path)?;
+        set(&env, "ADMIN_ENABLED", &mut self.admin.enabled)?;
+        set_some(&env, "ADMIN_SOCKET", &mut self.admin.socket)?;
+        set_some(&env, "METRICS_ADDRESS", &mut self.metrics.address)?;
+        set(&env, "QUEUE_CAPACITY", &mut self.limits.queue_capacity)?;
+        set(&env, "QUEUE_POLICY", &mut self.limits.queue_policy)?;
+        set(&env, "MAX_CONNECTIONS", &mut self.limits.max_connections)?;
+        set(
+            &env,
+            "MAX_CONNECTIONS_PER_IP",
+            &mut self.limits.max_connections_per_ip,
+        )?;
+        set(&env, "SHUTDOWN_TIMEOUT", &mut self.timeouts.shutdown)?;
+        set(&env, "HANDSHAKE_TIMEOUT", &mut self.timeouts.handshake)?;
+        set(&env, "DEBUG_LEVEL", &mut self.log.level)?;
+        set_some(&env, "RUST_LOG", &mut self.log.filter)?;
+        set_some(&env, "LOG_FILTER", &mut self.log.filter)?;
+        set(&env, "LOG_FORMAT", &mut self.log.format)?;
+        if let Some(directory) = env("LOG_DIR") {
+            self.log.file.get_or_insert_with(LogFile::default).directory = directory.into();
+        }
+        if let Some(rotation) = env("LOG_ROTATION") {
+            self.log.file.get_or_insert_with(LogFile::default).rotation =
+                rotation.parse().map_err(|e| invalid("LOG_ROTATION", e))?;
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &Args) {
+        if let Some(address) = args.address {
+            self.listen.address = address;
+        }
+        if let Some(websocket) = args.websocket {
+            self.listen.websocket = Some(websocket);
+        }
+        if let Some(path) = &args.unix_socket {
+            self.listen.unix_socket = Some(path.clone());
+        }
+        if let Some(cert) = &args.tls_cert {
+            self.tls.cert = Some(cert.clone());
+        }
+        if let Some(key) = &args.tls_key {
+            self.tls.key = Some(key.clone());
+        }
+        if let Some(path) = &args.storage {
+            self.storage.path = path.clone();
+        }
+        if let Some(address) = args.metrics {
+            self.metrics.address = Some(address);
+        }
+        if let Some(path) = &args.admin_socket {
+            self.admin.socket = Some(path.clone());
+        }
+        if let Some(level) = args.log_level {
+            self.log.level = level;
+        }
+        if let Some(format) = args.log_format {
+            self.log.format = format;
+        }
+        if let Some(filter) = &args.log_filter {
+            self.log.filter = Some(filter.clone());
+        }
+        if let Some(directory) = &args.log_dir {
+            self.log.file.get_or_insert_with(LogFile::default).directory = directory.clone();
+        }
+    }
+
+    /// Check the settings that are valid on their own but not together, or
+    /// not at all.
+    fn validate(&self) -> Result<(), ConfigError> {
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(_), None) => return Err(invalid("tls.key", "must be set along with tls.cert")),
+            (None, Some(_)) => return Err(invalid("tls.cert", "must be set along with tls.key")),
+            _ => {}
+        }
+        if self.listen.unix_socket_mode > 0o777 {
+            return Err(invalid(
+                "listen.unix_socket_mode",
+                format!("{:o} is not a file mode", self.listen.unix_socket_mode),
+            ));
+        }
+        if self.limits.queue_capacity == 0 {
+            return Err(invalid("limits.queue_capacity", "must be at least 1"));
+        }
+        if self.limits.max_connections == 0 {
+            return Err(invalid("limits.max_connections", "must be at least 1"));
+        }
+        if self.limits.max_connections_per_ip == 0 {
+            return Err(invalid(
+                "limits.max_connections_per_ip",
+                "must be at least 1",
+            ));
+        }
+        self.log.env_filter()?;
+        validate_rates("limits.rate", &self.limits.rate)?;
+        for (name, room) in &self.rooms {
+            if let Some(rate) = &room.rate {
+                validate_rates(&format!("rooms.{}.rate", name), rate)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn validate_rates(key: &str, limits: &RateLimits) -> Result<(), ConfigError> {
+    let rates = [
+        ("connection.messages", &limits.connection.messages),
+        ("connection.bytes", &limits.connection.bytes),
+        ("account.messages", &limits.account.messages),
+        ("account.bytes", &limits.account.bytes),
+        ("abuse", &limits.abuse),
+    ];
+    for (name, rate) in rates {
+        if !(rate.per_second > 0.0 && rate.burst >= 1.0) {
+            return Err(invalid(
+                format!("{}.{}", key, name),
+                "per_second must be positive and burst at least 1",
+            ));
+        }
+    }
+    // A frame larger than the burst would be throttled forever.
+    for (name, rate) in [
+        ("connection.bytes", &limits.connection.bytes),
+        ("account.bytes", &limits.account.bytes),
+    ] {
+        if rate.burst < frame::MAX_FRAME_LEN as f64 {
+            return Err(invalid(
+                format!("{}.{}", key, name),
+                format!(
+                    "burst must hold the largest frame, {} bytes",
+                    frame::MAX_FRAME_LEN
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Deserialize a value from a string with its `FromStr` implementation.
+fn parse<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn load(toml: &str, env: &[(&str, &str)], args: &[&str]) -> Result<Config, ConfigError> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        fs::write(&path, toml).unwrap();
+
+        let mut args = Args::parse_from(std::iter::once("server").chain(args.iter().copied()));
+        args.config = Some(path);
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Config::load(&args, |var| env.get(var).cloned())
+    }
+
+    #[test]
+    fn layers() {
+        let config = load(
+            r#"
+            [listen]
+            address = "0.0.0.0:7000"
+            unix_socket = "/tmp/chat.sock"
+            unix_socket_mode = 0o600
+
+            [limits]
+            queue_capacity = 64
+            queue_policy = "disconnect"
+
+            [access]
+            allow = ["10.0.0.0/8", "::1/128"]
+
+            [log]
+            level = "debug"
+            format = "compact"
+            filter = "server::admin=trace"
+
+            [log.file]
+            rotation = "hourly"
+            "#,
+            &[
+                ("QUEUE_CAPACITY", "128"),
+                ("ADDRESS", "0.0.0.0:7001"),
+                ("LOG_DIR", "/var/log/chat"),
+            ],
+            &["--address", "0.0.0.0:7002"],
+        )
+        .unwrap();
+
+        assert_eq!(config.listen.address, "0.0.0.0:7002".parse().unwrap());
+        assert_eq!(config.listen.unix_socket_mode, 0o600);
+        assert_eq!(config.limits.queue_capacity, 128);
+        assert_eq!(config.limits.queue_policy, Policy::Disconnect);
+        assert_eq!(config.log.level, Level::DEBUG);
+        assert_eq!(config.log.format, LogFormat::Compact);
+        assert_eq!(
+            config.log.file,
+            Some(LogFile {
+                directory: PathBuf::from("/var/log/chat"),
+                prefix: "server.log".to_owned(),
+                rotation: Rotation::Hourly,
+            })
+        );
+        assert_eq!(
+            config.log.env_filter().unwrap().max_level_hint(),
+            Some(LevelFilter::TRACE)
+        );
+        assert_eq!(config.timeouts.shutdown(), Duration::from_secs(10));
+        assert_eq!(config.access.allow.len(), 2);
+        assert_eq!(config.limits.max_connections_per_ip, 32);
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        let err = load("[limits]\nqueue_policy = \"drop-all\"", &[], &[]).unwrap_err();
+        assert!(err.to_string().contains("queue_policy"), "{}", err);
+
+        let err = load("[listen]\nadress = \"0.0.0.0:1\"", &[], &[]).unwrap_err();
+        assert!(err.to_string().contains("adress"), "{}", err);
+
+        let err = load("[log]\nfilter = \"server=loud\"", &[], &[]).unwrap_err();
+        assert!(err.to_string().starts_with("log.filter:"), "{}", err);
+
+        let err = load("", &[("SHUTDOWN_TIMEOUT", "soon")], &[]).unwrap_err();
+        assert!(err.to_string().starts_with("SHUTDOWN_TIMEOUT:"), "{}", err);
+
+        let err = load(
+            "[rooms.lobby.rate.connection]\nmessages = { per_second = 0, burst = 5 }",
+            &[],
+            &[],
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("rooms.lobby.rate.connection.messages:"),
+            "{}",
+            err
+        );
+
+        let err = load(
+            "[rooms.lobby.rate.account]\nbytes = { per_second = 1024, burst = 65536 }",
+            &[],
+            &[],
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("rooms.lobby.rate.account.bytes:"),
+            "{}",
+            err
+        );
+
+        let err = load("[access]\ndeny = [\"10.0.0.0/33\"]", &[], &[]).unwrap_err();
+        assert!(err.to_string().contains("deny"), "{}", err);
+
+        let err = load("[tls]\ncert = \"cert.pem\"", &[], &[]).unwrap_err();
+        assert!(err.to_string().starts_with("tls.key:"), "{}", err);
+    }
+}
@@ -0,0 +1,216 @@
+//! The wire format spoken between the server and its clients.
+//!
+//! Every frame is prefixed with its length as a big endian `u32`, followed by
+//! a one byte `Kind` and the payload. The server reads frames with the length
+//! header still attached, so a `Data` frame can be relayed to other peers
+//! without being re-encoded.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio_util::codec::LengthDelimitedCodec;
+
+/// Length of the big endian `u32` length prefix.
+pub const HEADER_LEN: usize = 4;
+
+/// The longest frame a client may send, header included. Every byte burst
+/// of the rate limits must hold one, or such frames could never pass.
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// What a frame carries.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// An end-to-end encrypted payload, relayed untouched to other peers.
+    Data = 0,
+    /// Human readable UTF-8 text from the server.
+    Notice = 1,
+    /// The server is going away; the payload is the UTF-8 reason.
+    Shutdown = 2,
+    /// The first frame of a client, naming its account and the room to
+    /// join. See `hello`.
+    Hello = 3,
+    /// A request was refused. The payload is an `ErrorCode` byte followed
+    /// by a UTF-8 explanation.
+    Error = 4,
+    /// A moderation command from a client, as UTF-8 text such as
+    /// `kick alice`. See `moderation::Command`.
+    Command = 5,
+    /// Sent empty by a client that wants to sign in with its identity key,
+    /// answered by the server with random bytes to sign. See `accounts`.
+    Challenge = 6,
+}
+
+/// Why a request was refused.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The client is sending faster than its room allows; the frame was
+    /// dropped.
+    Throttled = 1,
+    /// The frame was not valid at this point.
+    BadRequest = 2,
+    /// The client may not do that, for example moderate without being an
+    /// operator or join a room it is banned from.
+    Forbidden = 3,
+    /// The client is muted in its room; the frame was dropped.
+    Muted = 4,
+}
+
+impl TryFrom<u8> for Kind {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, u8> {
+        match byte {
+            0 => Ok(Kind::Data),
+            1 => Ok(Kind::Notice),
+            2 => Ok(Kind::Shutdown),
+            3 => Ok(Kind::Hello),
+            4 => Ok(Kind::Error),
+            5 => Ok(Kind::Command),
+            6 => Ok(Kind::Challenge),
+            _ => Err(byte),
+        }
+    }
+}
+
+/// A codec that splits the stream into frames and keeps the length header.
+/// Frames longer than `MAX_FRAME_LEN` are an error.
+pub fn codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(MAX_FRAME_LEN - HEADER_LEN)
+        .length_adjustment(HEADER_LEN as isize)
+        .num_skip(0)
+        .new_codec()
+}
+
+/// Encode a complete frame, header included, ready to be written to a socket.
+pub fn encode(kind: Kind, payload: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + 1 + payload.len());
+    frame.put_u32(1 + payload.len() as u32);
+    frame.put_u8(kind as u8);
+    frame.put_slice(payload);
+    frame.freeze()
+}
+
+/// Encode an `Error` frame.
+pub fn error(code: ErrorCode, message: &str) -> Bytes {
+    let mut payload = Vec::with_capacity(1 + message.len());
+    payload.push(code as u8);
+    payload.extend_from_slice(message.as_bytes());
+    encode(Kind::Error, &payload)
+}
+
+/// The first frame of a client.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Hello<'a> {
+    pub account: &'a str,
+    pub room: &'a str,
+    /// The client's identity key and its signature over the challenge and
+    /// both names, if it signed in. See `accounts::verify`.
+    pub proof: Option<&'a [u8]>,
+}
+
+/// Parse a `Hello` frame read with `codec`.
+///
+/// The payload is the length of the account name as one byte, the account
+/// name, the length of the room name as one byte and the room name, both
+/// UTF-8, optionally followed by an `accounts::PROOF_LEN` byte proof.
+pub fn hello(frame: &[u8]) -> Option<Hello<'_>> {
+    let payload = frame.get(HEADER_LEN + 1..)?;
+    let (account, rest) = prefixed(payload)?;
+    let (room, proof) = prefixed(rest)?;
+    let proof = match proof.len() {
+        0 => None,
+        crate::accounts::PROOF_LEN => Some(proof),
+        _ => return None,
+    };
+    Some(Hello {
+        account: std::str::from_utf8(account).ok()?,
+        room: std::str::from_utf8(room).ok()?,
+        proof,
+    })
+}
+
+/// Split a field preceded by its one byte length off `bytes`.
+fn prefixed(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (len, rest) = bytes.split_first()?;
+    if rest.len() < *len as usize {
+        return None;
+    }
+    Some(rest.split_at(*len as usize))
+}
+
+/// Encode a `Hello` frame, as a client would.
+#[cfg(test)]
+pub fn encode_hello(account: &str, room: &str, proof: &[u8]) -> Bytes {
+    let mut payload = vec![account.len() as u8];
+    payload.extend_from_slice(account.as_bytes());
+    payload.push(room.len() as u8);
+    payload.extend_from_slice(room.as_bytes());
+    payload.extend_from_slice(proof);
+    encode(Kind::Hello, &payload)
+}
+
+/// The kind of a frame read with `codec`.
+pub fn kind(frame: &[u8]) -> Option<Kind> {
+    frame
+        .get(HEADER_LEN)
+        .and_then(|byte| Kind::try_from(*byte).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_util::codec::Decoder;
+
+    #[test]
+    fn round_trip() {
+        let mut buf = BytesMut::from(&encode(Kind::Notice, b"hello")[..]);
+        buf.extend_from_slice(&encode(Kind::Data, b"")[..]);
+
+        let frame = codec().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(kind(&frame), Some(Kind::Notice));
+        assert_eq!(&frame[HEADER_LEN + 1..], b"hello");
+
+        let frame = codec().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(kind(&frame), Some(Kind::Data));
+        assert!(buf.is_empty());
+
+        let payload = vec![0; MAX_FRAME_LEN - HEADER_LEN - 1];
+        let mut buf = BytesMut::from(&encode(Kind::Data, &payload)[..]);
+        assert_eq!(
+            codec().decode(&mut buf).unwrap().unwrap().len(),
+            MAX_FRAME_LEN
+        );
+        let mut buf = BytesMut::from(&encode(Kind::Data, &[&payload[..], &[0]].concat())[..]);
+        let err = codec().decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn hello_payload() {
+        let frame = encode(Kind::Hello, b"\x05alice\x05lobby");
+        assert_eq!(
+            hello(&frame),
+            Some(Hello {
+                account: "alice",
+                room: "lobby",
+                proof: None
+            })
+        );
+        let proof = [7; crate::accounts::PROOF_LEN];
+        let frame = encode_hello("alice", "lobby", &proof);
+        assert_eq!(hello(&frame).unwrap().proof, Some(&proof[..]));
+
+        assert_eq!(hello(&encode(Kind::Hello, b"\x09alice")), None);
+        assert_eq!(hello(&encode(Kind::Hello, b"\x05alice\x09lobby")), None);
+        assert_eq!(hello(&encode(Kind::Hello, b"\x05alice\x05lobbyjunk")), None);
+        assert_eq!(hello(&encode(Kind::Hello, b"")), None);
+    }
+
+    #[test]
+    fn unknown_kind() {
+        assert_eq!(kind(&[0, 0, 0, 1, 200]), None);
+        assert_eq!(kind(&[0, 0, 0, 1, 7]), None);
+        assert_eq!(kind(&[0, 0, 0, 0]), None);
+    }
+}
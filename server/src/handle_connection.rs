@@ -1,95 +1,602 @@
 use bytes::Bytes;
 use tokio::{
-    net::TcpStream,
-    sync::{mpsc, Mutex},
+    io::{AsyncRead, AsyncWrite},
+    sync::watch,
 };
-use tokio_stream::StreamExt;
-use tokio_util::codec::{BytesCodec, Framed};
+use tokio_util::{codec::FramedRead, sync::CancellationToken};
 
-use futures::SinkExt;
-use std::{collections::HashMap, error::Error, io, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::{Duration, Instant, SystemTime},
+};
 
 #[allow(unused_imports)]
-use tracing::{debug, error, info, trace, warn};
+use tracing::{debug, error, field, info, trace, warn, Span};
+
+use crate::accounts::{self, Accounts, CHALLENGE_LEN};
+use crate::admission::{self, Admission, Permit, Refused};
+use crate::config::Room;
+use crate::frame::{self, ErrorCode, Kind};
+use crate::metrics::{Dropped, Metrics};
+use crate::moderation::{self, Command, Moderation, Role, Target};
+use crate::queue::{self, Policy, Rx, Tx};
+use crate::ratelimit::{self, Bucket, Limiter, RateLimits};
+use crate::shards::ShardedMap;
+use crate::transport::Transport;
 
-/// Shorthand for the transmit half of the message channel.
-type Tx = mpsc::UnboundedSender<Vec<u8>>;
+/// Where a peer is connected from. This also identifies the peer for as
+/// long as its connection is open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    /// A TCP (or TLS, or WebSocket) connection.
+    Inet(SocketAddr),
+    /// A Unix domain socket connection, numbered in the order they were
+    /// accepted, and the user id of the process on the other end.
+    Unix { id: u64, uid: u32 },
+}
 
-/// Shorthand for the receive half of the message channel.
-type Rx = mpsc::UnboundedReceiver<Vec<u8>>;
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        PeerAddr::Inet(addr)
+    }
+}
+
+impl PeerAddr {
+    /// The IP address of a TCP peer, with IPv4 clients of a dual stack
+    /// listener (`::ffff:a.b.c.d`) as plain IPv4.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Inet(addr) => Some(addr.ip().to_canonical()),
+            PeerAddr::Unix { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Inet(addr) => addr.fmt(f),
+            PeerAddr::Unix { id, uid } => write!(f, "unix:{} (uid {})", id, uid),
+        }
+    }
+}
+
+/// The room a client is in until it says otherwise with a `Hello` frame.
+pub const DEFAULT_ROOM: &str = "lobby";
 
 /// Data that is shared between all peers in the chat server.
 ///
 /// This is the set of `Tx` handles for all connected clients. Whenever a
-/// message is received from a client, it is broadcasted to all peers by
-/// iterating over the `peers` entries and sending a copy of the message on each
-/// `Tx`.
+/// message is received from a client, it is broadcasted to all peers in the
+/// same room by iterating over that room's entry in `rooms` and sending a
+/// handle to the message on each `Tx`, so a broadcast costs as much as the
+/// room is large, however many other rooms there are.
+///
+/// `peers` and `rooms` are sharded maps, so registering, removing and
+/// broadcasting only ever lock one shard at a time and connections do not
+/// contend on a single global lock.
 pub struct Shared {
-    peers: HashMap<SocketAddr, Tx>,
+    peers: ShardedMap<PeerAddr, Member>,
+
+    /// The queue of every peer, by room.
+    rooms: ShardedMap<Arc<str>, HashMap<PeerAddr, Tx>>,
+
+    /// Settings that can be changed without disconnecting anyone.
+    settings: RwLock<Settings>,
+
+    /// The rate limiter of every account with a connection. All connections
+    /// of an account share one; it goes away with the last of them.
+    accounts: Mutex<HashMap<Arc<str>, Weak<Mutex<Limiter>>>>,
+
+    /// Open connections, counted from the moment they are accepted.
+    admission: Arc<Admission>,
+
+    /// The identity key each account is bound to.
+    keys: Accounts,
+
+    /// Roles, bans and mutes in every room.
+    moderation: Moderation,
+
+    /// What has been relayed and dropped since the server started.
+    metrics: Metrics,
+
+    /// Cancelled when the server starts shutting down. Every connection then
+    /// flushes its queue, says goodbye and closes.
+    shutdown: CancellationToken,
+}
+
+/// The part of the configuration that is applied to connected peers in
+/// place when it is reloaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// Maximum number of messages waiting to be written to a single peer.
+    pub queue_capacity: usize,
+
+    /// What happens to a peer whose queue is full.
+    pub queue_policy: Policy,
+
+    /// Sent to every client when it connects.
+    pub motd: Option<String>,
+
+    /// How fast peers may send, in rooms without their own limits.
+    pub rate: RateLimits,
+
+    /// Settings of individual rooms, by name.
+    pub rooms: HashMap<String, Room>,
+
+    /// Who may connect, and how many at once.
+    pub admission: admission::Limits,
+
+    /// How long a client has to send its first frame after connecting.
+    pub handshake_timeout: Option<Duration>,
+}
+
+impl Settings {
+    /// The rate limits that apply in `room`.
+    fn rate(&self, room: &str) -> &RateLimits {
+        self.rooms
+            .get(room)
+            .and_then(|room| room.rate.as_ref())
+            .unwrap_or(&self.rate)
+    }
+}
+
+/// A connected peer as seen by everyone else.
+pub struct Member {
+    tx: Tx,
+    room: Arc<str>,
+    account: Arc<str>,
+
+    /// Whether the peer proved it holds the account's identity key. Guests
+    /// hold no role whatever their name.
+    authenticated: bool,
+
+    /// Set to the reason when the peer is kicked or banned from its room.
+    kick: watch::Sender<Option<String>>,
+}
+
+/// A connected peer, as reported to the administrator.
+pub struct Connection {
+    pub addr: PeerAddr,
+    pub account: Arc<str>,
+    pub room: Arc<str>,
+    pub queue: queue::Stats,
 }
 
 /// The state for each connected client.
-struct Peer {
-    /// The TCP socket wrapped with the `Lines` codec, defined below.
+struct Peer<T> {
+    /// The connection to the client.
     ///
-    /// This handles sending and receiving data on the socket. When using
-    /// `Lines`, we can work at the line level instead of having to manage the
-    /// raw byte operations.
-    lines: Framed<TcpStream, BytesCodec>,
+    /// This handles sending and receiving frames. Using a `Transport`, we
+    /// can work at the frame level instead of having to manage the raw byte
+    /// operations, whether the client is on a socket or a WebSocket.
+    transport: T,
 
     /// Receive half of the message channel.
     ///
     /// This is used to receive messages from peers. When a message is received
     /// off of this `Rx`, it will be written to the socket.
     rx: Rx,
+
+    /// Changes when the peer is kicked or banned from its room.
+    kicked: watch::Receiver<Option<String>>,
+
+    /// How others will reach the peer. It is only put in the routing table
+    /// once the peer has joined a room, so nothing is sent to a client that
+    /// has not introduced itself or is banned.
+    member: Option<Member>,
 }
 
 impl Shared {
-    /// Create a new, empty, instance of `Shared`.
-    pub fn new() -> Self {
+    /// Create a new, empty, instance of `Shared`. Nobody is rate limited,
+    /// refused or timed out until the limits are set with `reconfigure`.
+    pub fn new(queue_capacity: usize, queue_policy: Policy) -> Self {
         Shared {
-            peers: HashMap::new(),
+            peers: ShardedMap::new(),
+            rooms: ShardedMap::new(),
+            settings: RwLock::new(Settings {
+                queue_capacity,
+                queue_policy,
+                motd: None,
+                rate: RateLimits::UNLIMITED,
+                rooms: HashMap::new(),
+                admission: admission::Limits::UNLIMITED,
+                handshake_timeout: None,
+            }),
+            accounts: Mutex::new(HashMap::new()),
+            admission: Arc::new(Admission::default()),
+            keys: Accounts::default(),
+            moderation: Moderation::default(),
+            metrics: Metrics::default(),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// Keep account keys in `accounts` rather than in memory only.
+    pub fn with_accounts(mut self, accounts: Accounts) -> Self {
+        self.keys = accounts;
+        self
+    }
+
+    /// Keep moderation state in `moderation` rather than in memory only.
+    pub fn with_moderation(mut self, moderation: Moderation) -> Self {
+        self.moderation = moderation;
+        self
+    }
+
+    /// Replace the settings. Queue limits change for connected peers too.
+    pub fn reconfigure(&self, settings: Settings) {
+        let mut current = self.settings.write().unwrap();
+        if (settings.queue_capacity, settings.queue_policy)
+            != (current.queue_capacity, current.queue_policy)
+        {
+            self.peers.for_each(|_, member| {
+                member
+                    .tx
+                    .set_limits(settings.queue_capacity, settings.queue_policy)
+            });
         }
+        *current = settings;
     }
 
-    /// Send a `LineCodec` encoded message to every peer, except
+    /// Ask every connection to flush its queue and close.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Send a `frame` encoded message to every peer in `room`, except
     /// for the sender.
-    async fn broadcast(&mut self, sender: SocketAddr, message: &[u8]) {
-        for peer in self.peers.iter_mut() {
-            if *peer.0 != sender {
-                let _ = peer.1.send(message.into());
+    ///
+    /// Every recipient gets a reference to the same buffer.
+    pub fn broadcast(&self, sender: PeerAddr, room: &str, message: &Bytes) {
+        let start = Instant::now();
+        self.rooms.with(room, |members| {
+            for (addr, tx) in members {
+                if *addr != sender && tx.push(message.clone()).is_err() {
+                    debug!("Outbound queue of {} is closed", addr);
+                }
+            }
+        });
+        self.metrics.relayed(message.len(), start.elapsed());
+    }
+
+    /// Start routing messages in its room to `member`.
+    pub fn insert(&self, addr: PeerAddr, member: Member) {
+        let tx = member.tx.clone();
+        let room = member.room.clone();
+        self.peers.insert(addr, member);
+        self.rooms
+            .with_default(room, |members| members.insert(addr, tx));
+    }
+
+    /// Stop routing messages to the peer at `addr`.
+    fn remove(&self, addr: &PeerAddr) -> Option<Member> {
+        let member = self.peers.remove(addr)?;
+        self.rooms.remove_if(&member.room, |members| {
+            members.remove(addr);
+            members.is_empty()
+        });
+        Some(member)
+    }
+
+    /// Let a peer into its room, unless it is banned there, and start
+    /// routing messages to it.
+    fn join(&self, addr: PeerAddr, member: Member) -> Result<Role, &'static str> {
+        let owner = self.owner(&member.room);
+        let role = self.moderation.join(
+            &member.room,
+            &member.account,
+            member.authenticated,
+            addr.ip(),
+            owner.as_deref(),
+        )?;
+
+        // The settings stay locked until the peer is in the map, so a
+        // concurrent `reconfigure` can not miss its queue.
+        let settings = self.settings.read().unwrap();
+        member
+            .tx
+            .set_limits(settings.queue_capacity, settings.queue_policy);
+        self.insert(addr, member);
+        Ok(role)
+    }
+
+    /// The owner of `room` set in the configuration, if any.
+    fn owner(&self, room: &str) -> Option<String> {
+        let settings = self.settings.read().unwrap();
+        settings.rooms.get(room)?.owner.clone()
+    }
+
+    /// Carry out a moderation command in `room` for `actor`, or for the
+    /// server's administrator if `actor` is `None`, and disconnect whoever
+    /// it kicks or bans. Returns what was done.
+    pub fn moderate(
+        &self,
+        room: &str,
+        actor: Option<&str>,
+        command: Command,
+    ) -> Result<String, String> {
+        let owner = self.owner(room);
+        let outcome =
+            self.moderation
+                .apply(room, actor, owner.as_deref(), command, SystemTime::now())?;
+        info!(
+            "{} {}",
+            actor.unwrap_or("The administrator"),
+            outcome.message
+        );
+
+        if let Some(target) = outcome.eject {
+            let reason = match (&target, actor) {
+                (Target::Account(_), Some(actor)) => format!("{} by {}", outcome.message, actor),
+                _ => outcome.message.clone(),
+            };
+            self.peers.for_each(|addr, member| {
+                let matches = match &target {
+                    Target::Account(account) => *member.account == **account,
+                    // The owner is never kept out by an address ban.
+                    Target::Address(net) => {
+                        let owner = member.authenticated
+                            && self
+                                .moderation
+                                .role(room, &member.account, owner.as_deref())
+                                == Role::Owner;
+                        !owner && addr.ip().is_some_and(|ip| moderation::covers(net, ip))
+                    }
+                };
+                if matches && *member.room == *room {
+                    member.kick.send_replace(Some(reason.clone()));
+                }
+            });
+        }
+        Ok(outcome.message)
+    }
+
+    /// Read the saved moderation state again and disconnect whoever it now
+    /// bans.
+    pub fn reload_moderation(&self) -> io::Result<()> {
+        self.moderation.reload()?;
+        self.peers.for_each(|addr, member| {
+            let owner = self.owner(&member.room);
+            let banned = self.moderation.is_banned(
+                &member.room,
+                &member.account,
+                member.authenticated,
+                addr.ip(),
+                owner.as_deref(),
+            );
+            if banned {
+                let reason = format!("banned from {}", member.room);
+                member.kick.send_replace(Some(reason));
             }
+        });
+        Ok(())
+    }
+
+    /// The rate limiter shared by all connections of `account`.
+    fn account_limiter(&self, account: &Arc<str>) -> Arc<Mutex<Limiter>> {
+        let mut accounts = self.accounts.lock().unwrap();
+        if let Some(limiter) = accounts.get(account).and_then(Weak::upgrade) {
+            return limiter;
         }
+        accounts.retain(|_, limiter| limiter.strong_count() > 0);
+        let limiter = Arc::new(Mutex::new(Limiter::new(Instant::now())));
+        accounts.insert(account.clone(), Arc::downgrade(&limiter));
+        limiter
+    }
+
+    /// Take a connection slot for a client that just connected from `addr`.
+    /// The slot is held until the permit is dropped.
+    pub fn admit(&self, addr: &PeerAddr) -> Result<Permit, Refused> {
+        let settings = self.settings.read().unwrap();
+        self.admission.admit(addr, &settings.admission)
+    }
+
+    /// When a client connecting now must have sent its first frame, TLS
+    /// handshake and WebSocket upgrade included.
+    pub fn handshake_deadline(&self) -> Option<tokio::time::Instant> {
+        let timeout = self.settings.read().unwrap().handshake_timeout?;
+        Some(tokio::time::Instant::now() + timeout)
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Number of open connections, including those still handshaking.
+    pub fn connection_count(&self) -> usize {
+        self.admission.len()
+    }
+
+    /// Number of connected peers.
+    pub fn peer_count(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Every connected peer with its account, room and queue.
+    pub fn connections(&self) -> Vec<Connection> {
+        let mut connections = Vec::new();
+        self.peers.for_each(|addr, member| {
+            connections.push(Connection {
+                addr: *addr,
+                account: member.account.clone(),
+                room: member.room.clone(),
+                queue: member.tx.stats(),
+            })
+        });
+        connections
+    }
+
+    /// Every room with someone in it and how many peers are there.
+    pub fn rooms(&self) -> BTreeMap<Arc<str>, usize> {
+        let mut rooms = BTreeMap::new();
+        self.peers
+            .for_each(|_, member| *rooms.entry(member.room.clone()).or_default() += 1);
+        rooms
+    }
+
+    /// Send a notice from the server to everyone in `room`, or to everyone
+    /// if it is `None`. Returns how many peers it was queued for.
+    pub fn notice(&self, room: Option<&str>, text: &str) -> usize {
+        let notice = frame::encode(Kind::Notice, text.as_bytes());
+        let mut sent = 0;
+        self.peers.for_each(|_, member| {
+            if room.is_none_or(|room| *member.room == *room)
+                && member.tx.push(notice.clone()).is_ok()
+            {
+                sent += 1;
+            }
+        });
+        sent
+    }
+
+    /// Queue depth and drop counters of every connected peer.
+    pub fn queue_stats(&self) -> Vec<(PeerAddr, queue::Stats)> {
+        let mut stats = Vec::new();
+        self.peers
+            .for_each(|addr, member| stats.push((*addr, member.tx.stats())));
+        stats
     }
 }
 
-impl Peer {
-    /// Create a new instance of `Peer`.
-    async fn new(
-        state: Arc<Mutex<Shared>>,
-        lines: Framed<TcpStream, BytesCodec>,
-    ) -> io::Result<Peer> {
-        // Get the client socket address
-        let addr = lines.get_ref().peer_addr()?;
+impl Member {
+    /// A guest named `account` in the default room, until it joins one.
+    pub fn new(tx: Tx, account: Arc<str>, kick: watch::Sender<Option<String>>) -> Member {
+        Member {
+            tx,
+            room: Arc::from(DEFAULT_ROOM),
+            account,
+            authenticated: false,
+            kick,
+        }
+    }
+}
 
-        // Create a channel for this peer
-        let (tx, rx) = mpsc::unbounded_channel();
+impl<T: Transport> Peer<T> {
+    /// Create a new instance of `Peer`, with a channel that is added to the
+    /// shared state map once it joins a room.
+    fn new(state: &Shared, transport: T, addr: PeerAddr) -> Peer<T> {
+        let settings = state.settings.read().unwrap();
+        let (tx, rx) = queue::channel(settings.queue_capacity, settings.queue_policy);
+        let (kick, kicked) = watch::channel(None);
 
-        // Add an entry for this `Peer` in the shared state map.
-        state.lock().await.peers.insert(addr, tx);
+        Peer {
+            transport,
+            rx,
+            kicked,
+            member: Some(Member::new(tx, Arc::from(addr.to_string()), kick)),
+        }
+    }
 
-        Ok(Peer { lines, rx })
+    /// The peer as others see it once it has joined `room` as `account`.
+    fn member(&mut self, account: &Arc<str>, room: &Arc<str>, authenticated: bool) -> Member {
+        let mut member = self.member.take().expect("a peer joins only once");
+        member.account = account.clone();
+        member.room = room.clone();
+        member.authenticated = authenticated;
+        member
     }
 }
 
-/// Process an individual chat client
-pub async fn process(
-    state: Arc<Mutex<Shared>>,
-    stream: TcpStream,
-    addr: SocketAddr,
-) -> Result<(), Box<dyn Error>> {
-    let lines = Framed::new(stream, BytesCodec::new());
+/// The span a connection's events are logged in. The account and room are
+/// recorded once the peer has joined one.
+pub fn span(addr: &PeerAddr) -> Span {
+    tracing::info_span!(
+        "connection",
+        peer = %addr,
+        account = field::Empty,
+        room = field::Empty
+    )
+}
+
+/// Record where the peer of the current connection span ended up.
+fn record_join(account: &str, room: &str) {
+    let span = Span::current();
+    span.record("account", account);
+    span.record("room", room);
+}
+
+/// Process an individual chat client connected over a byte stream, such as
+/// TCP or TLS. The handshake timeout starts now.
+pub async fn process<S>(state: Arc<Shared>, stream: S, addr: PeerAddr) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let deadline = state.handshake_deadline();
+    process_until(state, stream, addr, deadline).await
+}
+
+/// Process an individual chat client connected over a byte stream, which
+/// has to introduce itself before `deadline`.
+pub async fn process_until<S>(
+    state: Arc<Shared>,
+    stream: S,
+    addr: PeerAddr,
+    deadline: Option<tokio::time::Instant>,
+) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    serve(
+        state,
+        FramedRead::new(stream, frame::codec()),
+        addr,
+        deadline,
+    )
+    .await
+}
+
+/// Process an individual chat client connecting over a WebSocket. The HTTP
+/// upgrade is performed on `stream` first, and it and the first frame have
+/// to be done before `deadline`.
+pub async fn process_websocket<S>(
+    state: Arc<Shared>,
+    stream: S,
+    addr: PeerAddr,
+    deadline: Option<tokio::time::Instant>,
+) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let websocket = handshake(&state, deadline, tokio_tungstenite::accept_async(stream)).await?;
+    serve(state, websocket, addr, deadline).await
+}
+
+/// Run a step of connecting, such as a TLS handshake, unless `deadline`
+/// passes first, and count it if it fails. Every step of a connection
+/// shares one deadline, taken when it was accepted.
+pub async fn handshake<F, T, E>(
+    state: &Shared,
+    deadline: Option<tokio::time::Instant>,
+    step: F,
+) -> Result<T, Box<dyn Error>>
+where
+    F: std::future::Future<Output = Result<T, E>>,
+    E: Into<Box<dyn Error>>,
+{
+    let result = match deadline {
+        Some(deadline) => match tokio::time::timeout_at(deadline, step).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out").into()),
+        },
+        None => step.await.map_err(Into::into),
+    };
+    if result.is_err() {
+        state.metrics.handshake_failed();
+    }
+    result
+}
 
+async fn serve<T: Transport>(
+    state: Arc<Shared>,
+    transport: T,
+    addr: PeerAddr,
+    deadline: Option<tokio::time::Instant>,
+) -> Result<(), Box<dyn Error>> {
     // Send a prompt to the client to enter their username.
     /* lines.send("Please enter your username:").await?;
 
@@ -106,10 +613,38 @@ pub async fn process(
         }
     }; */
 
-    let username = addr.to_string();
+    // Until the client introduces itself with a `Hello` frame it is in the
+    // default room and an account of its own.
+    let mut username: Arc<str> = Arc::from(addr.to_string());
+    let mut room: Arc<str> = Arc::from(DEFAULT_ROOM);
+    let mut greeted = false;
+    let mut authenticated = false;
+    // What the client signs if it signs in with its identity key.
+    let mut challenge: Option<[u8; CHALLENGE_LEN]> = None;
+
+    // A client that never says anything is dropped at the handshake
+    // deadline instead of holding its connection slot forever.
+    let handshake = match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline),
+        None => tokio::time::sleep(Duration::MAX),
+    };
+    tokio::pin!(handshake);
+
+    let now = Instant::now();
+    let mut limiter = Limiter::new(now);
+    let mut account = Arc::new(Mutex::new(Limiter::new(now)));
+    let mut abuse = Bucket::new(now);
+
+    // Set up the peer's channels; it is registered with the state once it
+    // joins a room.
+    let mut peer = Peer::new(&state, transport, addr);
 
-    // Register our peer with state which internally sets up some channels.
-    let mut peer = Peer::new(state.clone(), lines).await?;
+    let motd = state.settings.read().unwrap().motd.clone();
+    if let Some(motd) = motd {
+        peer.transport
+            .write(frame::encode(Kind::Notice, motd.as_bytes()))
+            .await?;
+    }
 
     // A client has connected, let's let everyone know.
     /* {
@@ -120,45 +655,1002 @@ pub async fn process(
     } */
 
     // Process incoming messages until our stream is exhausted by a disconnect.
-    loop {
-        tokio::select! {
-            // A message was received from a peer. Send it to the current user.
-            Some(msg) = peer.rx.recv() => {
-                let bytes = Bytes::from(msg);
-                peer.lines.send(bytes).await?;
-            }
-            result = peer.lines.next() => match result {
-                // A message was received from the current user, we should
-                // broadcast this message to the other users.
-                Some(Ok(msg)) => {
-                    let mut state = state.lock().await;
-                    // let msg = format!("{}: {}", username, msg);
-
-                    state.broadcast(addr, &msg).await;
+    let result = async {
+        loop {
+            tokio::select! {
+                // The server is shutting down. Deliver whatever is still
+                // queued for this peer, tell it why it is being disconnected
+                // and leave.
+                _ = state.shutdown.cancelled() => {
+                    while let Some(msg) = peer.rx.try_recv() {
+                        peer.transport.write(msg).await?;
+                    }
+                    let notice = frame::encode(Kind::Shutdown, b"server is shutting down");
+                    peer.transport.write(notice).await?;
+                    peer.transport.close().await?;
+                    break;
                 }
-                // An error occurred.
-                Some(Err(e)) => {
-                    error!(
-                        "an error occurred while processing messages for {}; error = {:?}",
-                        username,
-                        e
-                    );
+                // A moderator kicked or banned this peer.
+                Ok(()) = peer.kicked.changed() => {
+                    let reason = peer.kicked.borrow_and_update().clone().unwrap_or_default();
+                    let error = frame::error(ErrorCode::Forbidden, &reason);
+                    peer.transport.write(error).await?;
+                    peer.transport.close().await?;
+                    break;
                 }
-                // The stream has been exhausted.
-                None => break,
-            },
+                _ = &mut handshake, if !greeted => {
+                    debug!("{} did not introduce itself in time, disconnecting", addr);
+                    state.metrics.handshake_failed();
+                    let error = frame::error(ErrorCode::BadRequest, "handshake timed out");
+                    peer.transport.write(error).await?;
+                    peer.transport.close().await?;
+                    break;
+                }
+                // A message was received from a peer. Send it to the current user.
+                msg = peer.rx.recv() => match msg {
+                    Some(msg) => peer.transport.write(msg).await?,
+                    // The queue overflowed under `Policy::Disconnect`.
+                    None => {
+                        warn!("{} is not keeping up with its queue, disconnecting", username);
+                        break;
+                    }
+                },
+                result = peer.transport.read() => match result {
+                    // A message was received from the current user, we should
+                    // broadcast this message to the other users.
+                    Some(Ok(msg)) => match frame::kind(&msg) {
+                        Some(Kind::Challenge) if !greeted => {
+                            let challenge = challenge.get_or_insert_with(accounts::challenge);
+                            peer.transport.write(frame::encode(Kind::Challenge, challenge)).await?;
+                        }
+                        Some(Kind::Hello) if !greeted => {
+                            greeted = true;
+                            match frame::hello(&msg).filter(|h| valid_name(h.account) && valid_name(h.room)) {
+                                Some(hello) => {
+                                    authenticated = match sign_in(&state, &hello, challenge.as_ref()) {
+                                        Ok(authenticated) => authenticated,
+                                        Err(reason) => {
+                                            state.metrics.handshake_failed();
+                                            let error = frame::error(ErrorCode::Forbidden, reason);
+                                            peer.transport.write(error).await?;
+                                            break;
+                                        }
+                                    };
+                                    username = Arc::from(hello.account);
+                                    room = Arc::from(hello.room);
+                                    match state.join(addr, peer.member(&username, &room, authenticated)) {
+                                        Ok(role) => {
+                                            record_join(&username, &room);
+                                            let guest = if authenticated { "" } else { ", guest" };
+                                            debug!("{} is {} in room {} ({:?}{})", addr, username, room, role, guest);
+                                        }
+                                        Err(reason) => {
+                                            let error = frame::error(ErrorCode::Forbidden, reason);
+                                            peer.transport.write(error).await?;
+                                            break;
+                                        }
+                                    }
+                                    account = state.account_limiter(&username);
+                                }
+                                None => {
+                                    state.metrics.handshake_failed();
+                                    let error = frame::error(ErrorCode::BadRequest, "invalid account or room name");
+                                    peer.transport.write(error).await?;
+                                    break;
+                                }
+                            }
+                        }
+                        Some(Kind::Hello) => {
+                            let error = frame::error(ErrorCode::BadRequest, "already joined a room");
+                            peer.transport.write(error).await?;
+                        }
+                        Some(Kind::Command) if !greeted => {
+                            let error = frame::error(ErrorCode::BadRequest, "introduce yourself before moderating");
+                            peer.transport.write(error).await?;
+                        }
+                        Some(Kind::Command) if !authenticated => {
+                            let error = frame::error(ErrorCode::Forbidden, "sign in with an identity key to moderate");
+                            peer.transport.write(error).await?;
+                        }
+                        Some(Kind::Command) => {
+                            let text = String::from_utf8_lossy(&msg[frame::HEADER_LEN + 1..]);
+                            let reply = match text.parse::<Command>() {
+                                Ok(command) => match state.moderate(&room, Some(&username), command) {
+                                    Ok(done) => frame::encode(Kind::Notice, done.as_bytes()),
+                                    Err(e) => frame::error(ErrorCode::Forbidden, &e),
+                                },
+                                Err(e) => frame::error(ErrorCode::BadRequest, &e),
+                            };
+                            peer.transport.write(reply).await?;
+                        }
+                        Some(Kind::Data) => {
+                            // A client that skipped `Hello` is in the default
+                            // room, unless it is banned there.
+                            if !greeted {
+                                greeted = true;
+                                if let Err(reason) = state.join(addr, peer.member(&username, &room, false)) {
+                                    let error = frame::error(ErrorCode::Forbidden, reason);
+                                    peer.transport.write(error).await?;
+                                    break;
+                                }
+                                record_join(&username, &room);
+                            }
+
+                            // Muted peers are turned away before anything is
+                            // relayed or counted against their rate.
+                            if let Some(left) = state.moderation.muted(&room, &username, SystemTime::now()) {
+                                state.metrics.dropped(Dropped::Muted, 1);
+                                let error = frame::error(
+                                    ErrorCode::Muted,
+                                    &format!("you are muted for another {}s", left.as_secs().max(1)),
+                                );
+                                peer.transport.write(error).await?;
+                                continue;
+                            }
+
+                            // `None` if the message may pass, otherwise whether
+                            // throttling it is still forgiven.
+                            let throttled = {
+                                let settings = state.settings.read().unwrap();
+                                let limits = settings.rate(&room);
+                                let now = Instant::now();
+                                let mut account = account.lock().unwrap();
+                                if ratelimit::admit(limits, &mut limiter, &mut account, msg.len(), now) {
+                                    None
+                                } else {
+                                    Some(abuse.try_take(&limits.abuse, 1.0, now))
+                                }
+                            };
+
+                            if throttled.is_some() {
+                                state.metrics.dropped(Dropped::Throttled, 1);
+                            }
+                            match throttled {
+                                None => state.broadcast(addr, &room, &msg),
+                                Some(true) => {
+                                    let error = frame::error(
+                                        ErrorCode::Throttled,
+                                        "sending too fast, the message was dropped",
+                                    );
+                                    peer.transport.write(error).await?;
+                                }
+                                Some(false) => {
+                                    warn!("{} keeps exceeding its rate limit, disconnecting", username);
+                                    let error = frame::error(
+                                        ErrorCode::Throttled,
+                                        "disconnected for sending too fast",
+                                    );
+                                    peer.transport.write(error).await?;
+                                    peer.transport.close().await?;
+                                    break;
+                                }
+                            }
+                        }
+                        kind => debug!("Ignoring {:?} frame from {}", kind, username),
+                    },
+                    // An error occurred. The stream can not be resynchronised
+                    // after a framing error, so the client is dropped.
+                    Some(Err(e)) => {
+                        error!(
+                            "an error occurred while processing messages for {}; error = {:?}",
+                            username,
+                            e
+                        );
+                        if e.kind() == io::ErrorKind::InvalidData {
+                            let message = format!("frames are limited to {} bytes", frame::MAX_FRAME_LEN);
+                            let _ = peer.transport.write(frame::error(ErrorCode::BadRequest, &message)).await;
+                        }
+                        break;
+                    }
+                    // The stream has been exhausted.
+                    None => break,
+                },
+            }
         }
+        Ok::<_, io::Error>(())
     }
+    .await;
 
     // If this section is reached it means that the client was disconnected!
     // Let's let everyone still connected know about it.
+    // Frames its queue dropped are now only counted in the metrics.
+    if let Some(member) = state.remove(&addr) {
+        state
+            .metrics
+            .dropped(Dropped::QueueFull, member.tx.stats().dropped);
+    }
+
+    // let msg = format!("{} has left the chat", username);
+    // state.broadcast(addr, &msg);
+
+    Ok(result?)
+}
+
+/// Check who a client that sent `hello` after being given `challenge` is.
+/// Returns whether it proved it holds the account's identity key; clients
+/// without one are guests, and may only use names no key is bound to.
+fn sign_in(
+    state: &Shared,
+    hello: &frame::Hello,
+    challenge: Option<&[u8; CHALLENGE_LEN]>,
+) -> Result<bool, &'static str> {
+    let identity = match hello.proof {
+        Some(proof) => Some(accounts::verify(
+            challenge,
+            hello.account,
+            hello.room,
+            proof,
+        )?),
+        None => None,
+    };
+    state.keys.sign_in(hello.account, identity.as_ref())
+}
+
+/// Account and room names are short and printable.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && !name.chars().any(char::is_control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_stream::StreamExt;
+    use tokio_util::codec::LengthDelimitedCodec;
+
+    /// Accept connections on an ephemeral port and run `process` for each.
+    async fn serve(state: Arc<Shared>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = listener.accept().await.unwrap();
+                let state = Arc::clone(&state);
+                tokio::spawn(async move {
+                    let _ = process(state, stream, addr.into()).await;
+                });
+            }
+        });
+        addr
+    }
+
+    /// Connect to `server` and join the default room as `account`.
+    async fn join(server: SocketAddr, account: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        stream
+            .write_all(&frame::encode_hello(account, DEFAULT_ROOM, &[]))
+            .await
+            .unwrap();
+        stream
+    }
+
+    /// Connect to `server` and join `room` as `account`, signed in with
+    /// `key` or as a guest.
+    async fn sign_in(
+        server: SocketAddr,
+        account: &str,
+        room: &str,
+        key: Option<&p256::ecdsa::SigningKey>,
+    ) -> FramedRead<TcpStream, LengthDelimitedCodec> {
+        let mut client = FramedRead::new(TcpStream::connect(server).await.unwrap(), frame::codec());
+        let proof = match key {
+            Some(key) => {
+                client
+                    .get_mut()
+                    .write_all(&frame::encode(Kind::Challenge, b""))
+                    .await
+                    .unwrap();
+                let challenge = client.next().await.unwrap().unwrap();
+                assert_eq!(frame::kind(&challenge), Some(Kind::Challenge));
+                accounts::prove(key, &challenge[frame::HEADER_LEN + 1..], account, room)
+            }
+            None => Vec::new(),
+        };
+        client
+            .get_mut()
+            .write_all(&frame::encode_hello(account, room, &proof))
+            .await
+            .unwrap();
+        client
+    }
+
+    /// A client that never reads must not make the server buffer more than
+    /// `queue_capacity` messages for it.
+    #[tokio::test]
+    async fn stalled_client_is_bounded() {
+        const CAPACITY: usize = 16;
+
+        let state = Arc::new(Shared::new(CAPACITY, Policy::DropOldest));
+        let server = serve(state.clone()).await;
+
+        let stalled = join(server, "stalled").await;
+        let stalled_addr = PeerAddr::from(stalled.local_addr().unwrap());
+        let mut sender = join(server, "sender").await;
+        while state.peers.len() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        let chunk = frame::encode(Kind::Data, &[0u8; 8 * 1024]);
+        for _ in 0..2048 {
+            sender.write_all(&chunk).await.unwrap();
+        }
+
+        let stats = tokio::time::timeout(Duration::from_secs(30), async {
+            loop {
+                let stats = state
+                    .peers
+                    .with(&stalled_addr, |member| member.tx.stats())
+                    .unwrap();
+                if stats.dropped > 0 {
+                    return stats;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the stalled client's queue never overflowed");
+
+        assert!(stats.depth <= CAPACITY);
+        assert!(stats.high_water <= CAPACITY);
+        drop(stalled);
+    }
+
+    #[tokio::test]
+    async fn stalled_client_is_disconnected() {
+        let state = Arc::new(Shared::new(4, Policy::Disconnect));
+        let server = serve(state.clone()).await;
+
+        let stalled = join(server, "stalled").await;
+        let stalled_addr = PeerAddr::from(stalled.local_addr().unwrap());
+        let mut sender = join(server, "sender").await;
+        while state.peers.len() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        let chunk = frame::encode(Kind::Data, &[0u8; 8 * 1024]);
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while state.peers.contains_key(&stalled_addr) {
+                sender.write_all(&chunk).await.unwrap();
+            }
+        })
+        .await
+        .expect("the stalled client was never disconnected");
+        drop(stalled);
+    }
+
+    /// Shutting down delivers what is already queued, then a `Shutdown`
+    /// frame, then closes the connection.
+    #[tokio::test]
+    async fn shutdown_flushes_and_notifies() {
+        const QUEUED: usize = 8;
+
+        let state = Arc::new(Shared::new(16, Policy::DropOldest));
+        let server = serve(state.clone()).await;
+
+        let mut reader = FramedRead::new(join(server, "reader").await, frame::codec());
+        let reader_addr = PeerAddr::from(reader.get_ref().local_addr().unwrap());
+        while state.peer_count() < 1 {
+            tokio::task::yield_now().await;
+        }
+
+        // The test runtime has one thread and nothing here yields, so the
+        // reader's connection can not write any of these before the
+        // shutdown.
+        let sender = PeerAddr::from(SocketAddr::from(([127, 0, 0, 1], 1)));
+        for i in 0..QUEUED {
+            let msg = frame::encode(Kind::Data, format!("queued {}", i).as_bytes());
+            state.broadcast(sender, DEFAULT_ROOM, &msg);
+        }
+        let depth = state
+            .peers
+            .with(&reader_addr, |member| member.tx.stats().depth);
+        assert_eq!(depth, Some(QUEUED));
+        state.shutdown();
+
+        for i in 0..QUEUED {
+            let msg = reader.next().await.unwrap().unwrap();
+            assert_eq!(frame::kind(&msg), Some(Kind::Data));
+            assert_eq!(
+                &msg[frame::HEADER_LEN + 1..],
+                format!("queued {}", i).as_bytes()
+            );
+        }
+        let msg = reader.next().await.unwrap().unwrap();
+        assert_eq!(frame::kind(&msg), Some(Kind::Shutdown));
+        assert!(reader.next().await.is_none());
+    }
+
+    /// A WebSocket client and a TCP client share the same routing table and
+    /// see each other's frames.
+    #[tokio::test]
+    async fn websocket_and_tcp_clients_meet() {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let state = Arc::new(Shared::new(16, Policy::DropOldest));
+        let tcp_server = serve(state.clone()).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ws_server = listener.local_addr().unwrap();
+        {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let (stream, addr) = listener.accept().await.unwrap();
+                let _ = process_websocket(state, stream, addr.into(), None).await;
+            });
+        }
+
+        let stream = TcpStream::connect(ws_server).await.unwrap();
+        let (mut browser, _) = tokio_tungstenite::client_async("ws://localhost/", stream)
+            .await
+            .unwrap();
+        let hello = frame::encode_hello("browser", DEFAULT_ROOM, &[]);
+        browser
+            .send(Message::Binary(hello.slice(frame::HEADER_LEN..)))
+            .await
+            .unwrap();
+        let mut terminal = FramedRead::new(join(tcp_server, "terminal").await, frame::codec());
+        while state.peer_count() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        // WebSocket to TCP: the length header is added back.
+        let data = frame::encode(Kind::Data, b"from the browser");
+        browser
+            .send(Message::Binary(data.slice(frame::HEADER_LEN..)))
+            .await
+            .unwrap();
+        let received = terminal.next().await.unwrap().unwrap();
+        assert_eq!(received, data);
+
+        // TCP to WebSocket: the length header is stripped.
+        let data = frame::encode(Kind::Data, b"from the terminal");
+        terminal.get_mut().write_all(&data).await.unwrap();
+        match browser.next().await.unwrap().unwrap() {
+            Message::Binary(received) => assert_eq!(received, data.slice(frame::HEADER_LEN..)),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    /// A peer in the default room, for tests that fill `peers` by hand.
+    fn member(tx: Tx) -> Member {
+        Member::new(tx, Arc::from("test"), watch::channel(None).0)
+    }
+
+    /// Broadcasts only visit the sender's room, which is forgotten once
+    /// its last peer leaves.
+    #[test]
+    fn rooms_are_indexed() {
+        let shared = Shared::new(16, Policy::DropOldest);
+        let addr = |port: u16| PeerAddr::from(SocketAddr::from(([127, 0, 0, 1], port)));
+        let mut receivers = Vec::new();
+        for (port, room) in [(1, "rust"), (2, "rust"), (3, "go")] {
+            let (tx, rx) = queue::channel(16, Policy::DropOldest);
+            let mut member = member(tx);
+            member.room = Arc::from(room);
+            shared.insert(addr(port), member);
+            receivers.push(rx);
+        }
+
+        shared.broadcast(addr(1), "rust", &Bytes::from_static(b"hi"));
+        let received: Vec<_> = receivers
+            .iter_mut()
+            .map(|rx| rx.try_recv().is_some())
+            .collect();
+        assert_eq!(received, [false, true, false]);
+
+        assert!(shared.remove(&addr(3)).is_some());
+        assert!(shared.rooms.with("go", |_| ()).is_none());
+        assert!(shared.remove(&addr(3)).is_none());
+        assert_eq!(shared.rooms.with("rust", |members| members.len()), Some(2));
+    }
+
+    /// Frames only reach peers in the sender's room.
+    #[tokio::test]
+    async fn rooms_are_separate() {
+        let state = Arc::new(Shared::new(16, Policy::DropOldest));
+        let server = serve(state.clone()).await;
+
+        let mut alice = FramedRead::new(TcpStream::connect(server).await.unwrap(), frame::codec());
+        let mut bob = FramedRead::new(TcpStream::connect(server).await.unwrap(), frame::codec());
+        let mut carol = FramedRead::new(TcpStream::connect(server).await.unwrap(), frame::codec());
+        for (client, name, room) in [
+            (&mut alice, "alice", "rust"),
+            (&mut bob, "bob", "rust"),
+            (&mut carol, "carol", "go"),
+        ] {
+            client
+                .get_mut()
+                .write_all(&frame::encode_hello(name, room, &[]))
+                .await
+                .unwrap();
+        }
+        // Wait until everyone has left the default room.
+        let addrs: Vec<PeerAddr> = [&alice, &bob, &carol]
+            .iter()
+            .map(|client| client.get_ref().local_addr().unwrap().into())
+            .collect();
+        while addrs
+            .iter()
+            .any(|addr| state.peers.with(addr, |m| &*m.room != DEFAULT_ROOM) != Some(true))
+        {
+            tokio::task::yield_now().await;
+        }
+
+        let data = frame::encode(Kind::Data, b"to rust");
+        carol
+            .get_mut()
+            .write_all(&frame::encode(Kind::Data, b"to go"))
+            .await
+            .unwrap();
+        alice.get_mut().write_all(&data).await.unwrap();
+        assert_eq!(bob.next().await.unwrap().unwrap(), data);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), carol.next())
+                .await
+                .is_err()
+        );
+    }
+
+    /// A flooding client is told it is throttled and, once it has used up
+    /// what is forgiven, disconnected.
+    #[tokio::test]
+    async fn flooding_is_throttled() {
+        use crate::ratelimit::Rate;
+
+        let state = Arc::new(Shared::new(16, Policy::DropOldest));
+        let mut rate = RateLimits::UNLIMITED;
+        rate.connection.messages = Rate {
+            per_second: 0.001,
+            burst: 2.0,
+        };
+        rate.abuse = Rate {
+            per_second: 0.001,
+            burst: 2.0,
+        };
+        let mut rooms = HashMap::new();
+        rooms.insert(
+            "slow".to_owned(),
+            Room {
+                rate: Some(rate),
+                ..Room::default()
+            },
+        );
+        state.reconfigure(Settings {
+            queue_capacity: 16,
+            queue_policy: Policy::DropOldest,
+            motd: None,
+            rate: RateLimits::UNLIMITED,
+            rooms,
+            admission: admission::Limits::UNLIMITED,
+            handshake_timeout: None,
+        });
+        let server = serve(state.clone()).await;
+
+        let mut flood = FramedRead::new(TcpStream::connect(server).await.unwrap(), frame::codec());
+        flood
+            .get_mut()
+            .write_all(&frame::encode_hello("flood", "slow", &[]))
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            let _ = flood
+                .get_mut()
+                .write_all(&frame::encode(Kind::Data, b"spam"))
+                .await;
+        }
+
+        let mut errors = Vec::new();
+        while let Some(Ok(frame)) = flood.next().await {
+            assert_eq!(frame::kind(&frame), Some(Kind::Error));
+            assert_eq!(frame[frame::HEADER_LEN + 1], ErrorCode::Throttled as u8);
+            errors.push(frame);
+        }
+        // Two messages pass, two are forgiven and the fifth disconnects.
+        assert_eq!(errors.len(), 3);
+    }
+
+    /// Muted peers are refused before their frames are relayed, banned
+    /// peers are disconnected and kept out.
+    #[tokio::test]
+    async fn moderation_is_enforced() {
+        use p256::ecdsa::SigningKey;
+
+        let state = Arc::new(Shared::new(16, Policy::DropOldest));
+        let server = serve(state.clone()).await;
+        let alice_key = SigningKey::random(&mut rand_core::OsRng);
+        let bob_key = SigningKey::random(&mut rand_core::OsRng);
+        let error_code = |frame: bytes::BytesMut| {
+            assert_eq!(frame::kind(&frame), Some(Kind::Error));
+            frame[frame::HEADER_LEN + 1]
+        };
+
+        // The first to sign in owns the room.
+        let mut alice = sign_in(server, "alice", "moderated", Some(&alice_key)).await;
+        let alice_addr = PeerAddr::from(alice.get_ref().local_addr().unwrap());
+        while state.peers.with(&alice_addr, |m| &*m.room == "moderated") != Some(true) {
+            tokio::task::yield_now().await;
+        }
+        let mut bob = sign_in(server, "bob", "moderated", Some(&bob_key)).await;
+
+        bob.get_mut()
+            .write_all(&frame::encode(Kind::Command, b"kick alice"))
+            .await
+            .unwrap();
+        let reply = bob.next().await.unwrap().unwrap();
+        assert_eq!(error_code(reply), ErrorCode::Forbidden as u8);
+
+        alice
+            .get_mut()
+            .write_all(&frame::encode(Kind::Command, b"mute bob 1m"))
+            .await
+            .unwrap();
+        let reply = alice.next().await.unwrap().unwrap();
+        assert_eq!(frame::kind(&reply), Some(Kind::Notice));
+        bob.get_mut()
+            .write_all(&frame::encode(Kind::Data, b"hi"))
+            .await
+            .unwrap();
+        let reply = bob.next().await.unwrap().unwrap();
+        assert_eq!(error_code(reply), ErrorCode::Muted as u8);
+
+        alice
+            .get_mut()
+            .write_all(&frame::encode(Kind::Command, b"ban bob"))
+            .await
+            .unwrap();
+        let reply = bob.next().await.unwrap().unwrap();
+        assert_eq!(error_code(reply), ErrorCode::Forbidden as u8);
+        assert!(bob.next().await.is_none());
+
+        let mut bob = sign_in(server, "bob", "moderated", Some(&bob_key)).await;
+        let reply = bob.next().await.unwrap().unwrap();
+        assert_eq!(error_code(reply), ErrorCode::Forbidden as u8);
+        assert!(bob.next().await.is_none());
+    }
+
+    /// Nobody gets into an account bound to someone else's key, and guests
+    /// can not moderate.
+    #[tokio::test]
+    async fn accounts_can_not_be_taken_over() {
+        use p256::ecdsa::SigningKey;
+
+        let state = Arc::new(Shared::new(16, Policy::DropOldest));
+        let server = serve(state.clone()).await;
+        let alice_key = SigningKey::random(&mut rand_core::OsRng);
+        let mallory_key = SigningKey::random(&mut rand_core::OsRng);
+        let refused = |mut client: FramedRead<TcpStream, LengthDelimitedCodec>| async move {
+            let reply = client.next().await.unwrap().unwrap();
+            assert_eq!(frame::kind(&reply), Some(Kind::Error));
+            assert_eq!(reply[frame::HEADER_LEN + 1], ErrorCode::Forbidden as u8);
+            assert!(client.next().await.is_none());
+        };
+
+        let alice = sign_in(server, "alice", "rust", Some(&alice_key)).await;
+        let alice_addr = PeerAddr::from(alice.get_ref().local_addr().unwrap());
+        while !state.peers.contains_key(&alice_addr) {
+            tokio::task::yield_now().await;
+        }
+        refused(sign_in(server, "alice", "rust", None).await).await;
+        refused(sign_in(server, "alice", "rust", Some(&mallory_key)).await).await;
+
+        // A proof made for another challenge is refused too.
+        let mut replayed =
+            FramedRead::new(TcpStream::connect(server).await.unwrap(), frame::codec());
+        let proof = accounts::prove(&alice_key, &[0; CHALLENGE_LEN], "alice", "rust");
+        replayed
+            .get_mut()
+            .write_all(&frame::encode_hello("alice", "rust", &proof))
+            .await
+            .unwrap();
+        refused(replayed).await;
+
+        let mut guest = sign_in(server, "guest", "rust", None).await;
+        guest
+            .get_mut()
+            .write_all(&frame::encode(Kind::Command, b"kick alice"))
+            .await
+            .unwrap();
+        let reply = guest.next().await.unwrap().unwrap();
+        assert_eq!(reply[frame::HEADER_LEN + 1], ErrorCode::Forbidden as u8);
+        assert!(state.peers.contains_key(&alice_addr));
+    }
+
+    /// Everything logged while it is installed.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Events of a connection are logged as JSON with the peer, and once it
+    /// has joined, its account and room.
+    #[tokio::test]
+    async fn json_logs_carry_the_connection() {
+        use tracing::Instrument;
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        // The layer `main` installs for `log.format = "json"`.
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .json()
+                .with_current_span(true)
+                .with_span_list(false)
+                .with_max_level(tracing::Level::DEBUG)
+                .with_writer(move || writer.clone())
+                .finish(),
+        );
+
+        let state = Arc::new(Shared::new(16, Policy::DropOldest));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let connection = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let (stream, addr) = listener.accept().await.unwrap();
+                let addr = PeerAddr::from(addr);
+                let _ = process(state, stream, addr).instrument(span(&addr)).await;
+            })
+        };
+
+        let mut client = TcpStream::connect(server).await.unwrap();
+        let peer = client.local_addr().unwrap().to_string();
+        client
+            .write_all(&frame::encode_hello("alice", "rust", &[]))
+            .await
+            .unwrap();
+        client
+            .write_all(&frame::encode(Kind::Error, b""))
+            .await
+            .unwrap();
+        drop(client);
+        connection.await.unwrap();
+
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let spans: Vec<serde_json::Value> = logs
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter_map(|event| event.get("span").cloned())
+            .collect();
+        assert!(!spans.is_empty(), "{}", logs);
+        for span in &spans {
+            assert_eq!(span["name"], "connection");
+            assert_eq!(span["peer"], peer.as_str());
+        }
+        // The frame after `Hello` is logged with the account and room.
+        let last = spans.last().unwrap();
+        assert_eq!(last["account"], "alice", "{}", logs);
+        assert_eq!(last["room"], "rust", "{}", logs);
+    }
+
+    /// Nothing is routed to a client until it has joined a room.
+    #[tokio::test]
+    async fn strangers_hear_nothing() {
+        let state = Arc::new(Shared::new(16, Policy::DropOldest));
+        let server = serve(state.clone()).await;
+
+        let mut stranger =
+            FramedRead::new(TcpStream::connect(server).await.unwrap(), frame::codec());
+        let mut talker = join(server, "talker").await;
+        while state.peer_count() < 1 {
+            tokio::task::yield_now().await;
+        }
+        talker
+            .write_all(&frame::encode(Kind::Data, b"lobby gossip"))
+            .await
+            .unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), stranger.next())
+                .await
+                .is_err()
+        );
+        assert_eq!(state.peer_count(), 1);
+    }
+
+    /// A client that connects but never sends a frame is disconnected.
+    #[tokio::test]
+    async fn silent_client_times_out() {
+        let state = Arc::new(Shared::new(16, Policy::DropOldest));
+        let mut settings = state.settings.read().unwrap().clone();
+        settings.handshake_timeout = Some(Duration::from_millis(50));
+        state.reconfigure(settings);
+        let server = serve(state.clone()).await;
+
+        let mut silent = FramedRead::new(TcpStream::connect(server).await.unwrap(), frame::codec());
+        let mut talker = FramedRead::new(TcpStream::connect(server).await.unwrap(), frame::codec());
+        talker
+            .get_mut()
+            .write_all(&frame::encode_hello("talker", DEFAULT_ROOM, &[]))
+            .await
+            .unwrap();
+
+        let error = silent.next().await.unwrap().unwrap();
+        assert_eq!(frame::kind(&error), Some(Kind::Error));
+        assert_eq!(error[frame::HEADER_LEN + 1], ErrorCode::BadRequest as u8);
+        assert!(silent.next().await.is_none());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), talker.next())
+                .await
+                .is_err()
+        );
+    }
+
+    /// The WebSocket upgrade and the first frame share one deadline, so a
+    /// slow upgrade leaves less time to introduce oneself.
+    #[tokio::test]
+    async fn handshake_steps_share_a_deadline() {
+        use tokio_tungstenite::tungstenite::Message;
+
+        let state = Arc::new(Shared::new(16, Policy::DropOldest));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let start = tokio::time::Instant::now();
+        {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let (stream, addr) = listener.accept().await.unwrap();
+                let deadline = Some(start + Duration::from_millis(300));
+                let _ = process_websocket(state, stream, addr.into(), deadline).await;
+            });
+        }
+
+        let stream = TcpStream::connect(server).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let (mut client, _) = tokio_tungstenite::client_async("ws://localhost/", stream)
+            .await
+            .unwrap();
+        match client.next().await {
+            Some(Ok(Message::Binary(error))) => assert_eq!(error[0], Kind::Error as u8),
+            other => panic!("unexpected message {:?}", other),
+        }
+        // Separate timeouts would have allowed until 550ms.
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    /// The routing table this module used before `Shared` was sharded: one
+    /// map behind one async mutex, held for the whole fan-out.
+    struct MutexRouter {
+        peers: tokio::sync::Mutex<std::collections::HashMap<PeerAddr, (Arc<str>, Tx)>>,
+    }
+
+    /// Run `SENDERS` tasks that each broadcast `MESSAGES` messages through
+    /// `broadcast` and return the total time together with the latency of
+    /// every single broadcast, sorted.
+    async fn drive<F, Fut>(senders: &[PeerAddr], broadcast: F) -> (Duration, Vec<Duration>)
+    where
+        F: Fn(PeerAddr) -> Fut + Clone + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
     {
-        let mut state = state.lock().await;
-        state.peers.remove(&addr);
+        const MESSAGES: usize = 200;
 
-        // let msg = format!("{} has left the chat", username);
-        // state.broadcast(addr, &msg).await;
+        let start = std::time::Instant::now();
+        let tasks: Vec<_> = senders
+            .iter()
+            .map(|&sender| {
+                let broadcast = broadcast.clone();
+                tokio::spawn(async move {
+                    let mut latencies = Vec::with_capacity(MESSAGES);
+                    for _ in 0..MESSAGES {
+                        let start = std::time::Instant::now();
+                        broadcast(sender).await;
+                        latencies.push(start.elapsed());
+                    }
+                    latencies
+                })
+            })
+            .collect();
+
+        let mut latencies = Vec::new();
+        for task in tasks {
+            latencies.extend(task.await.unwrap());
+        }
+        let elapsed = start.elapsed();
+        latencies.sort();
+        (elapsed, latencies)
+    }
+
+    fn report(name: &str, (elapsed, latencies): (Duration, Vec<Duration>)) {
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        println!(
+            "{:>7}: {:>9.0} broadcasts/s, p50 = {:?}, p99 = {:?}, max = {:?}",
+            name,
+            latencies.len() as f64 / elapsed.as_secs_f64(),
+            percentile(50),
+            percentile(99),
+            latencies[latencies.len() - 1],
+        );
     }
 
-    Ok(())
+    /// Compare broadcast throughput and latency of the sharded `Shared`
+    /// against a single `Mutex<HashMap>` that is walked for every message,
+    /// with everyone in one room and spread over many.
+    ///
+    /// Run with `cargo test --release -p server -- --ignored --nocapture bench_router`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_router() {
+        const PEERS: u16 = 2000;
+        const SENDERS: usize = 32;
+
+        let addrs: Vec<PeerAddr> = (0..PEERS)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port + 10000)).into())
+            .collect();
+        let senders = &addrs[..SENDERS];
+        let message = Bytes::from(vec![0u8; 256]);
+
+        // Each receiver is kept alive but never drained, so every push beyond
+        // the first few exercises the overflow path like a busy server would.
+        let mut receivers = Vec::new();
+
+        for rooms in [1, 200] {
+            println!("{} peers in {} rooms", PEERS, rooms);
+            let room_of = |i: usize| -> Arc<str> { Arc::from(format!("room{}", i % rooms)) };
+
+            let mutex = Arc::new(MutexRouter {
+                peers: tokio::sync::Mutex::new(std::collections::HashMap::new()),
+            });
+            for (i, addr) in addrs.iter().enumerate() {
+                let (tx, rx) = queue::channel(16, Policy::DropOldest);
+                mutex.peers.lock().await.insert(*addr, (room_of(i), tx));
+                receivers.push(rx);
+            }
+            let result = drive(senders, {
+                let message = message.clone();
+                move |sender| {
+                    let mutex = mutex.clone();
+                    let message = message.clone();
+                    async move {
+                        let peers = mutex.peers.lock().await;
+                        let room = peers[&sender].0.clone();
+                        for (addr, (to, tx)) in peers.iter() {
+                            if *addr != sender && *to == room {
+                                let _ = tx.push(message.clone());
+                            }
+                        }
+                    }
+                }
+            })
+            .await;
+            report("mutex", result);
+
+            let shared = Arc::new(Shared::new(16, Policy::DropOldest));
+            for (i, addr) in addrs.iter().enumerate() {
+                let (tx, rx) = queue::channel(16, Policy::DropOldest);
+                let mut member = member(tx);
+                member.room = room_of(i);
+                shared.insert(*addr, member);
+                receivers.push(rx);
+            }
+            let senders_rooms: std::collections::HashMap<PeerAddr, Arc<str>> = senders
+                .iter()
+                .enumerate()
+                .map(|(i, addr)| (*addr, room_of(i)))
+                .collect();
+            let result = drive(senders, {
+                let message = message.clone();
+                let senders_rooms = Arc::new(senders_rooms);
+                move |sender| {
+                    let shared = shared.clone();
+                    let message = message.clone();
+                    let room = senders_rooms[&sender].clone();
+                    async move { shared.broadcast(sender, &room, &message) }
+                }
+            })
+            .await;
+            report("sharded", result);
+        }
+    }
 }
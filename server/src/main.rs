@@ -1,55 +1,543 @@
-use tokio::{net::TcpListener, sync::Mutex};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener},
+    signal::unix::{signal, SignalKind},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-use std::{error::Error, net::SocketAddr, sync::Arc};
+use std::{
+    error::Error,
+    future::Future,
+    io,
+    process::{ExitCode, Termination},
+    sync::Arc,
+    time::Duration,
+};
 
+use clap::Parser;
 use dotenvy::dotenv;
-use tracing::Level;
 #[allow(unused_imports)]
-use tracing::{debug, error, info, trace, warn};
+use tracing::{debug, error, info, trace, warn, Instrument};
+use tracing_appender::{non_blocking::WorkerGuard, rolling};
+use tracing_subscriber::{
+    fmt::{self, writer::BoxMakeWriter},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
 
+use admission::Permit;
+use config::{Config, LogFormat};
+use handle_connection::PeerAddr;
+use reload::{LogHandle, Reloader};
+
+mod accounts;
+mod admin;
+mod admission;
+mod config;
+mod frame;
 mod handle_connection;
+mod metrics;
+mod moderation;
+mod queue;
+mod ratelimit;
+mod reload;
+mod shards;
+mod tls;
+mod transport;
+mod unix;
+
+/// The status the server exits with.
+#[repr(u8)]
+pub enum ExitStatus {
+    /// Shut down on request and every connection was drained in time.
+    Clean = 0,
+    /// A fatal error stopped the server.
+    Error = 1,
+    /// Shut down on request, but some connections were still being drained
+    /// when the shutdown timeout ran out.
+    DrainTimeout = 2,
+    /// The configuration is invalid, as in `EX_CONFIG` from sysexits.h.
+    Config = 78,
+}
+
+impl Termination for ExitStatus {
+    fn report(self) -> ExitCode {
+        ExitCode::from(self as u8)
+    }
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    dotenv().expect(".env file not found");
-
-    let tracing_level = &std::env::var("DEBUG_LEVEL").unwrap_or("INFO".to_string());
-    tracing_subscriber::fmt()
-        .with_max_level(
-            <Level as std::str::FromStr>::from_str(tracing_level).unwrap_or(Level::INFO),
-        )
-        .init();
+async fn main() -> ExitStatus {
+    // The environment is one layer of the configuration; `.env` is optional.
+    let _ = dotenv();
+    let args = config::Args::parse();
+    let config = match Config::load(&args, |var| std::env::var(var).ok()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            return ExitStatus::Config;
+        }
+    };
+
+    let (log, _log_guard) = match init_logging(&config) {
+        Ok(logging) => logging,
+        Err(e) => {
+            eprintln!("Failed to open the log file: {}", e);
+            return ExitStatus::Config;
+        }
+    };
+
+    if let Err(e) = std::fs::create_dir_all(&config.storage.path) {
+        error!(
+            "Failed to create the storage directory {}: {}",
+            config.storage.path.display(),
+            e
+        );
+        return ExitStatus::Error;
+    }
 
     // Create the shared state. This is how all the peers communicate.
     //
     // The server task will hold a handle to this. For every new client, the
     // `state` handle is cloned and passed into the task that processes the
     // client connection.
-    let state = Arc::new(Mutex::new(handle_connection::Shared::new()));
+    let moderation = match moderation::Moderation::load(&config.storage.path) {
+        Ok(moderation) => moderation,
+        Err(e) => {
+            error!("Failed to load the moderation state: {}", e);
+            return ExitStatus::Error;
+        }
+    };
+    let accounts = match accounts::Accounts::load(&config.storage.path) {
+        Ok(accounts) => accounts,
+        Err(e) => {
+            error!("Failed to load the account keys: {}", e);
+            return ExitStatus::Error;
+        }
+    };
+    let state = Arc::new(
+        handle_connection::Shared::new(config.limits.queue_capacity, config.limits.queue_policy)
+            .with_accounts(accounts)
+            .with_moderation(moderation),
+    );
 
     // Bind a TCP listener to the socket address.
     //
     // Note that this is the Tokio TcpListener, which is fully async.
-    let addr = &std::env::var("ADDRESS").expect("ADDRESS must be set.");
-    let addr = addr.parse::<SocketAddr>().unwrap();
-    let listener = TcpListener::bind(&addr).await?;
+    let addr = config.listen.address;
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind {}: {}", addr, e);
+            return ExitStatus::Error;
+        }
+    };
+
+    // Optionally accept WebSocket clients, such as browsers, as well.
+    let ws_listener = match config.listen.websocket {
+        Some(ws_addr) => match TcpListener::bind(&ws_addr).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                error!("Failed to bind {}: {}", ws_addr, e);
+                return ExitStatus::Error;
+            }
+        },
+        None => None,
+    };
+
+    // Optionally accept local clients on a Unix domain socket. Who may
+    // connect is decided by the socket file's permissions.
+    let unix_socket = config.listen.unix_socket.clone();
+    let unix_listener = match &unix_socket {
+        Some(path) => match unix::bind(path, config.listen.unix_socket_mode) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                error!("Failed to bind {}: {}", path.display(), e);
+                return ExitStatus::Error;
+            }
+        },
+        None => None,
+    };
+
+    // Serve TLS instead of plain TCP when a certificate and key are given.
+    let tls = match (&config.tls.cert, &config.tls.key) {
+        (Some(cert), Some(key)) => match tls::acceptor(cert, key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                error!("Failed to load the TLS certificate: {}", e);
+                return ExitStatus::Error;
+            }
+        },
+        _ => None,
+    };
+
+    let with_tls = if tls.is_some() { " with TLS" } else { "" };
+    info!("Server is running on {}{}", addr, with_tls);
+    if let Some(ws_listener) = &ws_listener {
+        info!(
+            "Accepting WebSocket clients on {}{}",
+            ws_listener.local_addr().unwrap(),
+            with_tls
+        );
+    }
+    if let Some(path) = &unix_socket {
+        info!("Accepting local clients on {}", path.display());
+    }
+
+    // From here on the configuration can change at runtime. SIGHUP reads
+    // it again and applies what it can without dropping anyone.
+    let reloader = Arc::new(Reloader::new(args, config, Arc::clone(&state), log));
+    {
+        let reloader = Arc::clone(&reloader);
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Failed to install the SIGHUP handler: {}", e);
+                return ExitStatus::Error;
+            }
+        };
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match reloader.reload() {
+                    Ok(_) => info!("Received SIGHUP, configuration reloaded"),
+                    Err(e) => error!("Received SIGHUP, keeping the current configuration: {}", e),
+                }
+            }
+        });
+    }
+
+    // The administrator can inspect and control the server through a local
+    // socket, for example with `chat-admin`.
+    let stop = CancellationToken::new();
+    let admin_socket = reloader.with_config(Config::admin_socket);
+    if let Some(path) = &admin_socket {
+        let listener = match admin::bind(path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind {}: {}", path.display(), e);
+                return ExitStatus::Error;
+            }
+        };
+        info!("Accepting administration requests on {}", path.display());
+        let admin = Arc::new(admin::Admin {
+            state: Arc::clone(&state),
+            reloader: Arc::clone(&reloader),
+            stop: stop.clone(),
+        });
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(listener, admin).await {
+                error!("Failed to accept administration connections: {}", e);
+            }
+        });
+    }
+
+    // Optionally let Prometheus scrape the server.
+    let metrics_address = reloader.with_config(|config| config.metrics.address);
+    if let Some(address) = metrics_address {
+        let listener = match TcpListener::bind(address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind {}: {}", address, e);
+                return ExitStatus::Error;
+            }
+        };
+        info!("Serving metrics on http://{}/metrics", address);
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listener, state).await {
+                error!("Failed to accept metrics connections: {}", e);
+            }
+        });
+    }
+
+    // Periodically report how full every peer's outbound queue is.
+    {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(10));
+            loop {
+                interval.tick().await;
+                debug!(
+                    "{} peers connected, {} connections open",
+                    state.peer_count(),
+                    state.connection_count()
+                );
+                for (addr, stats) in state.queue_stats() {
+                    debug!(
+                        "queue of {}: depth = {}/{}, high water = {}, dropped = {}",
+                        addr, stats.depth, stats.capacity, stats.high_water, stats.dropped
+                    );
+                }
+            }
+        });
+    }
+
+    // Every connection task is tracked so the shutdown can wait for them.
+    let tracker = TaskTracker::new();
+
+    let accept_websocket = async {
+        match &ws_listener {
+            Some(ws_listener) => {
+                accept(
+                    ws_listener,
+                    Protocol::WebSocket,
+                    tls.clone(),
+                    &state,
+                    &tracker,
+                )
+                .await
+            }
+            None => std::future::pending().await,
+        }
+    };
 
-    info!("Server is running on {}", addr);
+    let accept_unix = async {
+        match &unix_listener {
+            Some(unix_listener) => accept_unix(unix_listener, &state, &tracker).await,
+            None => std::future::pending().await,
+        }
+    };
 
+    let mut status = tokio::select! {
+        Err(e) = accept(&listener, Protocol::Framed, tls.clone(), &state, &tracker) => {
+            error!("Failed to accept connections: {}", e);
+            ExitStatus::Error
+        }
+        Err(e) = accept_websocket => {
+            error!("Failed to accept WebSocket connections: {}", e);
+            ExitStatus::Error
+        }
+        Err(e) = accept_unix => {
+            error!("Failed to accept Unix socket connections: {}", e);
+            ExitStatus::Error
+        }
+        signal = shutdown_signal() => {
+            info!("Received {}, shutting down", signal);
+            ExitStatus::Clean
+        }
+        _ = stop.cancelled() => {
+            info!("Shutting down on the administrator's request");
+            ExitStatus::Clean
+        }
+    };
+
+    // Stop accepting new connections and let the existing ones drain.
+    drop(listener);
+    drop(ws_listener);
+    drop(unix_listener);
+    for path in unix_socket.iter().chain(&admin_socket) {
+        let _ = std::fs::remove_file(path);
+    }
+    tracker.close();
+    state.shutdown();
+
+    // How long connections get to flush their queues once a shutdown has
+    // been requested.
+    let shutdown_timeout = reloader.with_config(|config| config.timeouts.shutdown());
+
+    if tokio::time::timeout(shutdown_timeout, tracker.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            "{} connections were still open after {:?}",
+            tracker.len(),
+            shutdown_timeout
+        );
+        if let ExitStatus::Clean = status {
+            status = ExitStatus::DrainTimeout;
+        }
+    }
+
+    info!("Server stopped");
+    status
+}
+
+/// Install the log subscriber. The returned handle swaps its filter; the
+/// guard flushes the log file, if there is one, when it is dropped.
+fn init_logging(config: &Config) -> Result<(LogHandle, Option<WorkerGuard>), rolling::InitError> {
+    // `Config::load` has validated the directives.
+    let filter = config
+        .log
+        .env_filter()
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter, handle) = tracing_subscriber::reload::Layer::new(filter);
+
+    let (writer, guard) = match &config.log.file {
+        Some(file) => {
+            let rotation = match file.rotation {
+                config::Rotation::Minutely => rolling::Rotation::MINUTELY,
+                config::Rotation::Hourly => rolling::Rotation::HOURLY,
+                config::Rotation::Daily => rolling::Rotation::DAILY,
+                config::Rotation::Never => rolling::Rotation::NEVER,
+            };
+            let appender = rolling::RollingFileAppender::builder()
+                .rotation(rotation)
+                .filename_prefix(&file.prefix)
+                .build(&file.directory)?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+    let layer = fmt::layer()
+        .with_writer(writer)
+        .with_ansi(config.log.file.is_none());
+    let layer = match config.log.format {
+        LogFormat::Full => layer.boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Json => layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .init();
+    Ok((handle, guard))
+}
+
+/// What clients speak on a listener.
+#[derive(Debug, Clone, Copy)]
+enum Protocol {
+    /// Length-delimited frames straight on the socket.
+    Framed,
+    /// One frame per binary WebSocket message.
+    WebSocket,
+}
+
+/// Accept connections and spawn a task to process each one until accepting
+/// fails.
+async fn accept(
+    listener: &TcpListener,
+    protocol: Protocol,
+    tls: Option<TlsAcceptor>,
+    state: &Arc<handle_connection::Shared>,
+    tracker: &TaskTracker,
+) -> io::Result<()> {
     loop {
         // Asynchronously wait for an inbound TcpStream.
         let (stream, addr) = listener.accept().await?;
 
+        // Turn the client away before spending anything on it if the server
+        // is full or the address is not allowed.
+        let addr = PeerAddr::from(addr);
+        let Some(permit) = admit(state, &addr) else {
+            continue;
+        };
+
         // Clone a handle to the `Shared` state for the new connection.
-        let state = Arc::clone(&state);
+        let state = Arc::clone(state);
 
-        // Spawn our handler to be run asynchronously.
-        tokio::spawn(async move {
+        // Spawn our handler to be run asynchronously. The TLS handshake
+        // happens in the task as well so a slow client can not hold up the
+        // accept loop.
+        //
+        // The handshake timeout covers everything from here to the first
+        // frame.
+        let tls = tls.clone();
+        let deadline = state.handshake_deadline();
+        spawn_connection(tracker, addr, permit, async move {
+            match tls {
+                Some(tls) => {
+                    let stream =
+                        handle_connection::handshake(&state, deadline, tls.accept(stream)).await?;
+                    serve(protocol, state, stream, addr, deadline).await
+                }
+                None => serve(protocol, state, stream, addr, deadline).await,
+            }
+        });
+    }
+}
+
+/// Accept connections on a Unix domain socket and spawn a task to process
+/// each one until accepting fails.
+async fn accept_unix(
+    listener: &UnixListener,
+    state: &Arc<handle_connection::Shared>,
+    tracker: &TaskTracker,
+) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let addr = unix::peer_addr(&stream);
+        let Some(permit) = admit(state, &addr) else {
+            continue;
+        };
+        let state = Arc::clone(state);
+        spawn_connection(
+            tracker,
+            addr,
+            permit,
+            handle_connection::process(state, stream, addr),
+        );
+    }
+}
+
+/// Take a connection slot for `addr`, or log why it was refused. The
+/// refused connection is closed when its stream is dropped.
+fn admit(state: &handle_connection::Shared, addr: &PeerAddr) -> Option<Permit> {
+    match state.admit(addr) {
+        Ok(permit) => Some(permit),
+        Err(reason) => {
+            info!("Refused connection from {}: {}", addr, reason);
+            None
+        }
+    }
+}
+
+/// Run a connection's handler on `tracker`, logging when it opens and
+/// closes and why it failed. The connection's slot is given back when the
+/// handler returns.
+fn spawn_connection<F>(tracker: &TaskTracker, addr: PeerAddr, permit: Permit, handler: F)
+where
+    F: Future<Output = Result<(), Box<dyn Error>>> + Send + 'static,
+{
+    let span = handle_connection::span(&addr);
+    tracker.spawn(
+        async move {
             info!("Accepted connection from: {}", addr);
-            if let Err(e) = handle_connection::process(state, stream, addr).await {
+            if let Err(e) = handler.await {
                 error!("an error occurred; error = {:?}", e);
             }
+            drop(permit);
             info!("Connection closed: {}", &addr);
-        });
+        }
+        .instrument(span),
+    );
+}
+
+/// Hand an accepted connection to the handler for its protocol. The client
+/// has until `deadline` to introduce itself.
+async fn serve<S>(
+    protocol: Protocol,
+    state: Arc<handle_connection::Shared>,
+    stream: S,
+    addr: PeerAddr,
+    deadline: Option<tokio::time::Instant>,
+) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match protocol {
+        Protocol::Framed => handle_connection::process_until(state, stream, addr, deadline).await,
+        Protocol::WebSocket => {
+            handle_connection::process_websocket(state, stream, addr, deadline).await
+        }
+    }
+}
+
+/// Wait for SIGINT or SIGTERM and return the name of the signal.
+async fn shutdown_signal() -> &'static str {
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("failed to install the SIGTERM handler");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
     }
 }
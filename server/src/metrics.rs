@@ -0,0 +1,305 @@
+//! Counters and gauges in the Prometheus text format.
+//!
+//! Counters are plain atomics bumped on the routing path. Gauges such as the
+//! number of peers are read from `Shared` when the endpoint is scraped, so
+//! they cost nothing in between.
+//!
+//! The endpoint is a deliberately small HTTP/1.1 responder: it answers
+//! `GET /metrics` and nothing else, one request per connection.
+
+use std::{
+    fmt::Write as _,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use crate::handle_connection::Shared;
+
+/// Upper bounds of the routing latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.000_01, 0.000_025, 0.000_05, 0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.005, 0.01,
+];
+
+/// Why a frame from a client was not relayed, or a frame for one was not
+/// delivered.
+#[derive(Debug, Clone, Copy)]
+pub enum Dropped {
+    /// A recipient's queue was full.
+    QueueFull,
+    /// The sender exceeded its rate limit.
+    Throttled,
+    /// The sender is muted.
+    Muted,
+}
+
+/// Everything counted since the server started.
+#[derive(Default)]
+pub struct Metrics {
+    messages_relayed: AtomicU64,
+    bytes_relayed: AtomicU64,
+    handshake_failures: AtomicU64,
+    dropped: [AtomicU64; 3],
+    routing_latency: Histogram,
+}
+
+impl Metrics {
+    /// A message of `len` bytes was routed to its room in `latency`.
+    pub fn relayed(&self, len: usize, latency: Duration) {
+        self.messages_relayed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_relayed.fetch_add(len as u64, Ordering::Relaxed);
+        self.routing_latency.observe(latency);
+    }
+
+    /// A client failed the TLS handshake or WebSocket upgrade, timed out
+    /// before introducing itself or sent an invalid `Hello`.
+    pub fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self, reason: Dropped, count: u64) {
+        self.dropped[reason as usize].fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Render the counters and the gauges read from `state`.
+    pub fn render(&self, state: &Shared) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, value: &AtomicU64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        };
+        let gauge = |out: &mut String, name: &str, help: &str, value: usize| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+
+        let connections = state.connections();
+        gauge(
+            &mut out,
+            "chat_peers",
+            "Connected peers.",
+            connections.len(),
+        );
+        gauge(
+            &mut out,
+            "chat_connections",
+            "Open connections, including those still handshaking.",
+            state.connection_count(),
+        );
+        gauge(
+            &mut out,
+            "chat_rooms",
+            "Rooms with at least one peer.",
+            state.rooms().len(),
+        );
+        gauge(
+            &mut out,
+            "chat_queue_depth",
+            "Messages waiting in all outbound queues together.",
+            connections.iter().map(|c| c.queue.depth).sum(),
+        );
+        gauge(
+            &mut out,
+            "chat_queue_depth_max",
+            "Messages waiting in the fullest outbound queue.",
+            connections.iter().map(|c| c.queue.depth).max().unwrap_or(0),
+        );
+
+        counter(
+            &mut out,
+            "chat_messages_relayed_total",
+            "Messages routed to a room.",
+            &self.messages_relayed,
+        );
+        counter(
+            &mut out,
+            "chat_bytes_relayed_total",
+            "Bytes of messages routed to a room, frame headers included.",
+            &self.bytes_relayed,
+        );
+        counter(
+            &mut out,
+            "chat_handshake_failures_total",
+            "Clients that failed to connect or introduce themselves.",
+            &self.handshake_failures,
+        );
+
+        // A peer's queue drops are added to the counter when it disconnects;
+        // until then they are read from the queue itself.
+        let queued: u64 = connections.iter().map(|c| c.queue.dropped).sum();
+        let _ = writeln!(
+            out,
+            "# HELP chat_frames_dropped_total Frames that were not delivered."
+        );
+        let _ = writeln!(out, "# TYPE chat_frames_dropped_total counter");
+        for (reason, label) in [
+            (Dropped::QueueFull, "queue_full"),
+            (Dropped::Throttled, "throttled"),
+            (Dropped::Muted, "muted"),
+        ] {
+            let mut value = self.dropped[reason as usize].load(Ordering::Relaxed);
+            if let Dropped::QueueFull = reason {
+                value += queued;
+            }
+            let _ = writeln!(
+                out,
+                "chat_frames_dropped_total{{reason=\"{}\"}} {}",
+                label, value
+            );
+        }
+
+        self.routing_latency.render(
+            &mut out,
+            "chat_routing_latency_seconds",
+            "Time from receiving a message to queueing it for its whole room.",
+        );
+        out
+    }
+}
+
+/// A histogram with fixed `LATENCY_BUCKETS`.
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(value.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        // Buckets are stored on their own and reported cumulatively.
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}_sum {}", name, sum.as_secs_f64());
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Answer scrapes on `listener` until accepting fails.
+pub async fn serve(listener: TcpListener, state: Arc<Shared>) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let result =
+                tokio::time::timeout(Duration::from_secs(10), respond(stream, &state)).await;
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("Metrics request from {} failed: {}", addr, e),
+                Err(_) => debug!("Metrics request from {} timed out", addr),
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, state: &Shared) -> io::Result<()> {
+    // Only the request line matters; the rest of the head is read so the
+    // client is not reset while still sending it.
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || head.len() > 8192 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+    let request_line = head.split(|b| *b == b'\r').next().unwrap_or_default();
+
+    let (status, body) = match request_line.split(|b| *b == b' ').collect::<Vec<_>>()[..] {
+        [b"GET", b"/metrics", _] => ("200 OK", state.metrics().render(state)),
+        [b"GET", ..] => ("404 Not Found", "Not found, try /metrics\n".to_owned()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::Policy;
+
+    #[tokio::test]
+    async fn scrape() {
+        let state = Arc::new(Shared::new(16, Policy::DropOldest));
+        state.metrics().relayed(100, Duration::from_micros(30));
+        state.metrics().relayed(50, Duration::from_secs(1));
+        state.metrics().dropped(Dropped::Throttled, 2);
+        state.metrics().handshake_failed();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, state.clone()));
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        for line in [
+            "chat_peers 0",
+            "chat_messages_relayed_total 2",
+            "chat_bytes_relayed_total 150",
+            "chat_handshake_failures_total 1",
+            "chat_frames_dropped_total{reason=\"throttled\"} 2",
+            "chat_routing_latency_seconds_bucket{le=\"0.00005\"} 1",
+            "chat_routing_latency_seconds_bucket{le=\"0.01\"} 1",
+            "chat_routing_latency_seconds_bucket{le=\"+Inf\"} 2",
+            "chat_routing_latency_seconds_count 2",
+        ] {
+            assert!(
+                response.lines().any(|l| l == line),
+                "no `{}` in\n{}",
+                line,
+                response
+            );
+        }
+
+        assert!(get("/").await.starts_with("HTTP/1.1 404 "));
+    }
+}
@@ -0,0 +1,680 @@
+//! Room roles, bans and mutes.
+//!
+//! Every room has at most one owner, who may appoint operators. Operators
+//! kick, ban and mute members; the owner may also do so to operators. An
+//! address ban can hit anyone behind that address, so only the owner may
+//! ban or unban addresses, and the owner is never kept out by one. The
+//! owner is either set in the config (`rooms.<name>.owner`) or whoever
+//! first joined the room. The default room has no owner unless configured.
+//!
+//! Everything but the configured owners is kept in `moderation.toml` in the
+//! storage directory, so bans survive a restart.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use crate::handle_connection::DEFAULT_ROOM;
+
+/// The name of the file moderation state is kept in.
+pub const FILE: &str = "moderation.toml";
+
+/// What an account may do in a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Member,
+    Operator,
+    Owner,
+}
+
+/// Whom a ban is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Account(String),
+    /// An address or a CIDR range.
+    Address(IpNet),
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(net) = s.parse::<IpNet>() {
+            return Ok(Target::Address(canonical(net)));
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(Target::Address(IpNet::from(ip.to_canonical())));
+        }
+        Ok(Target::Account(s.to_owned()))
+    }
+}
+
+/// `net` with IPv4 ranges written as IPv4-mapped IPv6 (`::ffff:a.b.c.d/n`)
+/// as plain IPv4, the way `IpAddr::to_canonical` treats addresses.
+fn canonical(net: IpNet) -> IpNet {
+    match net {
+        IpNet::V6(v6) if v6.prefix_len() >= 96 => match v6.addr().to_ipv4_mapped() {
+            Some(v4) => IpNet::new(IpAddr::V4(v4), v6.prefix_len() - 96).unwrap_or(net),
+            None => net,
+        },
+        net => net,
+    }
+}
+
+/// Whether an address ban on `net` covers a peer connected from `ip`.
+pub fn covers(net: &IpNet, ip: IpAddr) -> bool {
+    net.contains(&ip.to_canonical())
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Account(account) => f.write_str(account),
+            Target::Address(net) => net.fmt(f),
+        }
+    }
+}
+
+/// A moderation command, as sent in a `Command` frame.
+///
+/// The text form is the command name and its arguments separated by
+/// spaces, for example `mute alice 10m`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Disconnect an account from the room.
+    Kick(String),
+    /// Disconnect an account or address and keep it out.
+    Ban(Target),
+    Unban(Target),
+    /// Drop an account's messages for a while.
+    Mute(String, Duration),
+    Unmute(String),
+    /// Make an account an operator. Only the owner may.
+    Op(String),
+    Deop(String),
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let mut arg = |what: &str| {
+            words
+                .next()
+                .map(str::to_owned)
+                .ok_or_else(|| format!("usage: {} <{}>", name, what))
+        };
+
+        let command = match name {
+            "kick" => Command::Kick(arg("account")?),
+            "ban" => Command::Ban(arg("account or address")?.parse()?),
+            "unban" => Command::Unban(arg("account or address")?.parse()?),
+            "mute" => {
+                let account = arg("account> <duration")?;
+                let duration = arg("account> <duration")?;
+                Command::Mute(account, parse_duration(&duration)?)
+            }
+            "unmute" => Command::Unmute(arg("account")?),
+            "op" => Command::Op(arg("account")?),
+            "deop" => Command::Deop(arg("account")?),
+            _ => return Err(format!("unknown command `{}`", name)),
+        };
+        match words.next() {
+            Some(extra) => Err(format!("unexpected `{}`", extra)),
+            None => Ok(command),
+        }
+    }
+}
+
+/// Parse `90`, `90s`, `15m`, `2h` or `1d`.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("`{}` is not a duration like 90s, 15m or 2h", s)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("`{}` is not a duration like 90s, 15m or 2h", s))
+}
+
+/// What the server has to do to connected peers after a command.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Outcome {
+    /// Text for whoever gave the command.
+    pub message: String,
+    /// Peers in the room matching this are to be disconnected.
+    pub eject: Option<Target>,
+}
+
+/// The persisted state of one room.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RoomState {
+    owner: Option<String>,
+    operators: BTreeSet<String>,
+    banned: BTreeSet<String>,
+    banned_addresses: BTreeSet<IpNet>,
+    /// Muted accounts and when, in seconds since the Unix epoch, the mute
+    /// ends.
+    muted: BTreeMap<String, u64>,
+}
+
+impl RoomState {
+    fn is_empty(&self) -> bool {
+        *self == RoomState::default()
+    }
+}
+
+/// Roles, bans and mutes of every room.
+pub struct Moderation {
+    /// Where the state is saved; nowhere if `None`.
+    path: Option<PathBuf>,
+    rooms: RwLock<BTreeMap<String, RoomState>>,
+}
+
+impl Default for Moderation {
+    fn default() -> Self {
+        Moderation {
+            path: None,
+            rooms: RwLock::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Moderation {
+    /// Load the state saved in `dir`, if there is any, and save changes
+    /// there.
+    pub fn load(dir: &Path) -> io::Result<Moderation> {
+        let path = dir.join(FILE);
+        Ok(Moderation {
+            rooms: RwLock::new(read(&path)?),
+            path: Some(path),
+        })
+    }
+
+    /// Read the saved state again, for edits made to the file while the
+    /// server runs. On error the state is left as it is.
+    pub fn reload(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let rooms = read(path)?;
+        *self.rooms.write().unwrap() = rooms;
+        Ok(())
+    }
+
+    /// Write the state to a temporary file and move it in place, so a crash
+    /// never leaves half a file behind.
+    fn save(&self, rooms: &BTreeMap<String, RoomState>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = toml::to_string(rooms)
+            .map_err(io::Error::other)
+            .and_then(|text| {
+                let temp = path.with_extension("toml.tmp");
+                fs::write(&temp, text)?;
+                fs::rename(&temp, path)
+            });
+        if let Err(e) = result {
+            error!("Failed to save {}: {}", path.display(), e);
+        }
+    }
+
+    /// Let `account`, connected from `ip`, into `room` unless it is banned
+    /// there, and return its role. Only `authenticated` accounts hold
+    /// roles: the first of them in a room without an owner becomes its
+    /// owner, and guests are members whatever their name.
+    pub fn join(
+        &self,
+        room: &str,
+        account: &str,
+        authenticated: bool,
+        ip: Option<IpAddr>,
+        configured_owner: Option<&str>,
+    ) -> Result<Role, &'static str> {
+        let role_in = |state: &RoomState| match authenticated {
+            true => role(state, account, configured_owner),
+            false => Role::Member,
+        };
+        {
+            let rooms = self.rooms.read().unwrap();
+            if let Some(state) = rooms.get(room) {
+                let role = role_in(state);
+                if banned(state, account, role, ip) {
+                    return Err("banned from this room");
+                }
+                if configured_owner.is_some() || state.owner.is_some() || !authenticated {
+                    return Ok(role);
+                }
+            }
+            if configured_owner.is_some() || room == DEFAULT_ROOM || !authenticated {
+                return Ok(role_in(&RoomState::default()));
+            }
+        }
+
+        let mut rooms = self.rooms.write().unwrap();
+        let state = rooms.entry(room.to_owned()).or_default();
+        // Someone else may have claimed it since the read lock was dropped.
+        if state.owner.is_none() {
+            info!("{} is the owner of room {}", account, room);
+            state.owner = Some(account.to_owned());
+            self.save(&rooms);
+            return Ok(Role::Owner);
+        }
+        Ok(role(state, account, None))
+    }
+
+    /// Whether `account`, connected from `ip`, is banned from `room`.
+    pub fn is_banned(
+        &self,
+        room: &str,
+        account: &str,
+        authenticated: bool,
+        ip: Option<IpAddr>,
+        configured_owner: Option<&str>,
+    ) -> bool {
+        let rooms = self.rooms.read().unwrap();
+        rooms.get(room).is_some_and(|state| {
+            let role = match authenticated {
+                true => role(state, account, configured_owner),
+                false => Role::Member,
+            };
+            banned(state, account, role, ip)
+        })
+    }
+
+    /// The role of `account` in `room`.
+    pub fn role(&self, room: &str, account: &str, configured_owner: Option<&str>) -> Role {
+        let rooms = self.rooms.read().unwrap();
+        match rooms.get(room) {
+            Some(state) => role(state, account, configured_owner),
+            None if configured_owner == Some(account) => Role::Owner,
+            None => Role::Member,
+        }
+    }
+
+    /// How much longer `account` is muted in `room`, if it is.
+    pub fn muted(&self, room: &str, account: &str, now: SystemTime) -> Option<Duration> {
+        let rooms = self.rooms.read().unwrap();
+        let until = *rooms.get(room)?.muted.get(account)?;
+        let until = UNIX_EPOCH + Duration::from_secs(until);
+        until
+            .duration_since(now)
+            .ok()
+            .filter(|left| !left.is_zero())
+    }
+
+    /// Carry out `command` in `room` for `actor`, or for the server's
+    /// administrator if `actor` is `None`.
+    pub fn apply(
+        &self,
+        room: &str,
+        actor: Option<&str>,
+        configured_owner: Option<&str>,
+        command: Command,
+        now: SystemTime,
+    ) -> Result<Outcome, String> {
+        let mut rooms = self.rooms.write().unwrap();
+        // Rooms nobody moderated yet are only added once a command is
+        // allowed, so refused ones leave nothing behind.
+        let empty = RoomState::default();
+        let current = rooms.get(room).unwrap_or(&empty);
+
+        let required = match command {
+            Command::Op(_)
+            | Command::Deop(_)
+            | Command::Ban(Target::Address(_))
+            | Command::Unban(Target::Address(_)) => Role::Owner,
+            _ => Role::Operator,
+        };
+        if let Some(actor) = actor {
+            let actor_role = role(current, actor, configured_owner);
+            if actor_role < required {
+                let who = match required {
+                    Role::Owner => "the owner",
+                    _ => "operators",
+                };
+                return Err(format!("only {} of {} may do that", who, room));
+            }
+            // Nobody may act on someone of their own rank or above.
+            let subject = match &command {
+                Command::Ban(Target::Account(account))
+                | Command::Kick(account)
+                | Command::Mute(account, _)
+                | Command::Op(account)
+                | Command::Deop(account) => Some(account),
+                _ => None,
+            };
+            if let Some(subject) = subject {
+                if role(current, subject, configured_owner) >= actor_role {
+                    return Err(format!("{} can not be moderated by {}", subject, actor));
+                }
+            }
+        }
+
+        let state = rooms.entry(room.to_owned()).or_default();
+        let outcome = carry_out(state, room, command, now);
+
+        // Expired mutes are forgotten whenever something changes.
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        state.muted.retain(|_, until| *until > now);
+        // Nor is a room left with nothing to remember.
+        if state.is_empty() {
+            rooms.remove(room);
+        }
+        if outcome.is_ok() {
+            self.save(&rooms);
+        }
+        outcome
+    }
+}
+
+/// Change `state`, the state of `room`, as `command` says.
+fn carry_out(
+    state: &mut RoomState,
+    room: &str,
+    command: Command,
+    now: SystemTime,
+) -> Result<Outcome, String> {
+    Ok(match command {
+        Command::Kick(account) => Outcome {
+            message: format!("kicked {} from {}", account, room),
+            eject: Some(Target::Account(account)),
+        },
+        Command::Ban(target) => {
+            match &target {
+                Target::Account(account) => {
+                    state.banned.insert(account.clone());
+                    state.operators.remove(account);
+                }
+                Target::Address(net) => {
+                    state.banned_addresses.insert(*net);
+                }
+            }
+            Outcome {
+                message: format!("banned {} from {}", target, room),
+                eject: Some(target),
+            }
+        }
+        Command::Unban(target) => {
+            let removed = match &target {
+                Target::Account(account) => state.banned.remove(account),
+                Target::Address(net) => state.banned_addresses.remove(net),
+            };
+            if !removed {
+                return Err(format!("{} is not banned from {}", target, room));
+            }
+            Outcome {
+                message: format!("unbanned {} from {}", target, room),
+                eject: None,
+            }
+        }
+        Command::Mute(account, duration) => {
+            let until = (now + duration)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            state.muted.insert(account.clone(), until);
+            Outcome {
+                message: format!("muted {} in {} for {:?}", account, room, duration),
+                eject: None,
+            }
+        }
+        Command::Unmute(account) => {
+            if state.muted.remove(&account).is_none() {
+                return Err(format!("{} is not muted in {}", account, room));
+            }
+            Outcome {
+                message: format!("unmuted {} in {}", account, room),
+                eject: None,
+            }
+        }
+        Command::Op(account) => {
+            state.operators.insert(account.clone());
+            Outcome {
+                message: format!("{} is now an operator of {}", account, room),
+                eject: None,
+            }
+        }
+        Command::Deop(account) => {
+            if !state.operators.remove(&account) {
+                return Err(format!("{} is not an operator of {}", account, room));
+            }
+            Outcome {
+                message: format!("{} is no longer an operator of {}", account, room),
+                eject: None,
+            }
+        }
+    })
+}
+
+fn role(state: &RoomState, account: &str, configured_owner: Option<&str>) -> Role {
+    if configured_owner.or(state.owner.as_deref()) == Some(account) {
+        Role::Owner
+    } else if state.operators.contains(account) {
+        Role::Operator
+    } else {
+        Role::Member
+    }
+}
+
+/// Whether `account`, holding `role` and connected from `ip`, is banned
+/// from the room `state` belongs to. The owner can not be locked out of
+/// their own room by an address ban.
+fn banned(state: &RoomState, account: &str, role: Role, ip: Option<IpAddr>) -> bool {
+    let address_banned = ip.is_some_and(|ip| {
+        role < Role::Owner && state.banned_addresses.iter().any(|net| covers(net, ip))
+    });
+    state.banned.contains(account) || address_banned
+}
+
+/// The rooms saved at `path`, none if there is no such file.
+fn read(path: &Path) -> io::Result<BTreeMap<String, RoomState>> {
+    match fs::read_to_string(path) {
+        Ok(text) => toml::from_str(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(text: &str) -> Command {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(command("kick bob"), Command::Kick("bob".into()));
+        assert_eq!(
+            command("ban 10.0.0.0/8"),
+            Command::Ban(Target::Address("10.0.0.0/8".parse().unwrap()))
+        );
+        assert_eq!(
+            command("ban ::ffff:10.0.0.1"),
+            Command::Ban(Target::Address("10.0.0.1/32".parse().unwrap()))
+        );
+        assert_eq!(
+            command("ban ::ffff:10.0.0.0/104"),
+            Command::Ban(Target::Address("10.0.0.0/8".parse().unwrap()))
+        );
+        assert_eq!(
+            command("mute bob 15m"),
+            Command::Mute("bob".into(), Duration::from_secs(900))
+        );
+        assert!("mute bob".parse::<Command>().is_err());
+        assert!("mute bob soon".parse::<Command>().is_err());
+        assert!("kick bob carol".parse::<Command>().is_err());
+        assert!("topic hi".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn roles_and_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+        let moderation = Moderation::load(dir.path()).unwrap();
+
+        // Guests never claim a room, whatever they call themselves.
+        assert_eq!(
+            moderation.join("rust", "alice", false, None, None),
+            Ok(Role::Member)
+        );
+        assert_eq!(
+            moderation.join("rust", "alice", true, None, None),
+            Ok(Role::Owner)
+        );
+        assert_eq!(
+            moderation.join("rust", "alice", false, None, None),
+            Ok(Role::Member)
+        );
+        assert_eq!(
+            moderation.join("rust", "bob", true, None, None),
+            Ok(Role::Member)
+        );
+        assert_eq!(
+            moderation.join(DEFAULT_ROOM, "alice", true, None, None),
+            Ok(Role::Member)
+        );
+
+        // Members can not moderate, operators can not touch each other.
+        assert!(moderation
+            .apply("rust", Some("bob"), None, command("kick alice"), now)
+            .is_err());
+        // Refused commands, or ones with nothing to undo, leave no trace.
+        for text in ["ban alice", "unmute alice"] {
+            assert!(moderation
+                .apply("quiet", Some("bob"), None, command(text), now)
+                .is_err());
+        }
+        assert!(moderation
+            .apply("quiet", None, None, command("unban alice"), now)
+            .is_err());
+        assert!(!moderation.rooms.read().unwrap().contains_key("quiet"));
+        moderation
+            .apply("rust", Some("alice"), None, command("op bob"), now)
+            .unwrap();
+        moderation
+            .apply("rust", Some("alice"), None, command("op carol"), now)
+            .unwrap();
+        assert!(moderation
+            .apply("rust", Some("bob"), None, command("mute carol 1m"), now)
+            .is_err());
+        assert!(moderation
+            .apply("rust", Some("bob"), None, command("op dave"), now)
+            .is_err());
+
+        // Only the owner may ban addresses, which could be anyone's.
+        assert!(moderation
+            .apply("rust", Some("bob"), None, command("ban 0.0.0.0/0"), now)
+            .is_err());
+        let outcome = moderation
+            .apply(
+                "rust",
+                Some("alice"),
+                None,
+                command("ban 192.0.2.0/24"),
+                now,
+            )
+            .unwrap();
+        assert_eq!(
+            outcome.eject,
+            Some(Target::Address("192.0.2.0/24".parse().unwrap()))
+        );
+        moderation
+            .apply("rust", Some("bob"), None, command("mute dave 1m"), now)
+            .unwrap();
+        moderation
+            .apply("rust", None, None, command("ban alice"), now)
+            .unwrap();
+
+        // Everything but the mute's remaining time is read back as it was.
+        let moderation = Moderation::load(dir.path()).unwrap();
+        assert_eq!(
+            moderation.join("rust", "alice", true, None, None),
+            Err("banned from this room")
+        );
+        assert_eq!(
+            moderation.join(
+                "rust",
+                "eve",
+                true,
+                Some("192.0.2.7".parse().unwrap()),
+                None
+            ),
+            Err("banned from this room")
+        );
+        assert_eq!(
+            moderation.join("rust", "bob", true, None, None),
+            Ok(Role::Operator)
+        );
+        assert!(moderation.muted("rust", "dave", now).is_some());
+        assert!(moderation
+            .muted("rust", "dave", now + Duration::from_secs(61))
+            .is_none());
+
+        // A configured owner outranks the one who claimed the room.
+        assert_eq!(
+            moderation.join("rust", "carol", true, None, Some("carol")),
+            Ok(Role::Owner)
+        );
+        assert_eq!(
+            moderation.join("rust", "carol", false, None, Some("carol")),
+            Ok(Role::Member)
+        );
+    }
+
+    #[test]
+    fn owners_are_not_locked_out() {
+        let now = SystemTime::now();
+        let moderation = Moderation::default();
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+
+        assert_eq!(
+            moderation.join("rust", "alice", true, ip("192.0.2.1"), None),
+            Ok(Role::Owner)
+        );
+        moderation
+            .apply("rust", Some("alice"), None, command("ban 0.0.0.0/0"), now)
+            .unwrap();
+        assert_eq!(
+            moderation.join("rust", "alice", true, ip("192.0.2.1"), None),
+            Ok(Role::Owner)
+        );
+        // IPv4 clients of a dual stack listener are banned all the same.
+        assert_eq!(
+            moderation.join("rust", "eve", true, ip("::ffff:192.0.2.7"), None),
+            Err("banned from this room")
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+
+/// What to do when a peer's outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Discard the oldest queued message to make room for the new one.
+    DropOldest,
+    /// Discard the message that is being pushed.
+    DropNewest,
+    /// Close the queue, which disconnects the slow consumer.
+    Disconnect,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(Policy::DropOldest),
+            "drop-newest" => Ok(Policy::DropNewest),
+            "disconnect" => Ok(Policy::Disconnect),
+            _ => Err(format!(
+                "unknown queue policy `{}`, expected one of: drop-oldest, drop-newest, disconnect",
+                s
+            )),
+        }
+    }
+}
+
+/// The queue has been closed, either because the receiver went away or
+/// because it overflowed under `Policy::Disconnect`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("outbound queue is closed")
+    }
+}
+
+impl std::error::Error for Closed {}
+
+/// A snapshot of a queue's counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of messages currently waiting to be written.
+    pub depth: usize,
+    /// The highest `depth` observed since the queue was created.
+    pub high_water: usize,
+    /// Maximum number of messages the queue will hold.
+    pub capacity: usize,
+    /// Number of messages discarded by the overflow policy.
+    pub dropped: u64,
+}
+
+struct State {
+    items: VecDeque<Bytes>,
+    high_water: usize,
+    dropped: u64,
+    closed: bool,
+    capacity: usize,
+    policy: Policy,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+/// Transmit half of a bounded outbound queue.
+///
+/// Pushing never waits: when the queue is full the configured `Policy`
+/// decides which message is lost, so a slow reader can not make the
+/// server's memory grow without limit.
+///
+/// Messages are reference-counted `Bytes`, so a message broadcast to many
+/// peers is stored once no matter how many queues hold it.
+#[derive(Clone)]
+pub struct Tx {
+    inner: Arc<Inner>,
+}
+
+/// Receive half of a bounded outbound queue.
+pub struct Rx {
+    inner: Arc<Inner>,
+}
+
+/// Create a bounded queue holding at most `capacity` messages.
+pub fn channel(capacity: usize, policy: Policy) -> (Tx, Rx) {
+    let inner = Arc::new(Inner {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            high_water: 0,
+            dropped: 0,
+            closed: false,
+            capacity,
+            policy,
+        }),
+        notify: Notify::new(),
+    });
+
+    (
+        Tx {
+            inner: inner.clone(),
+        },
+        Rx { inner },
+    )
+}
+
+impl Tx {
+    /// Queue a message for the receiver, applying the overflow policy if the
+    /// queue is full.
+    pub fn push(&self, message: Bytes) -> Result<(), Closed> {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.closed {
+            return Err(Closed);
+        }
+
+        if state.items.len() >= state.capacity {
+            state.dropped += 1;
+            match state.policy {
+                Policy::DropOldest => {
+                    // The capacity may have been lowered below the depth.
+                    while state.items.len() >= state.capacity {
+                        state.items.pop_front();
+                    }
+                }
+                Policy::DropNewest => return Ok(()),
+                Policy::Disconnect => {
+                    state.closed = true;
+                    state.items.clear();
+                    drop(state);
+                    self.inner.notify.notify_one();
+                    return Err(Closed);
+                }
+            }
+        }
+
+        state.items.push_back(message);
+        state.high_water = state.high_water.max(state.items.len());
+        drop(state);
+        self.inner.notify.notify_one();
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Stats {
+        let state = self.inner.state.lock().unwrap();
+        Stats {
+            depth: state.items.len(),
+            high_water: state.high_water,
+            capacity: state.capacity,
+            dropped: state.dropped,
+        }
+    }
+
+    /// Change the capacity and overflow policy of a live queue. Messages
+    /// already queued are kept; the new limits apply from the next push.
+    pub fn set_limits(&self, capacity: usize, policy: Policy) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.capacity = capacity;
+        state.policy = policy;
+    }
+}
+
+impl Rx {
+    /// Wait for the next message.
+    ///
+    /// Returns `None` once the queue has been closed.
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        loop {
+            {
+                let mut state = self.inner.state.lock().unwrap();
+                if state.closed {
+                    return None;
+                }
+                if let Some(message) = state.items.pop_front() {
+                    return Some(message);
+                }
+            }
+            self.inner.notify.notified().await;
+        }
+    }
+
+    /// Take the next message if one is already waiting.
+    pub fn try_recv(&mut self) -> Option<Bytes> {
+        self.inner.state.lock().unwrap().items.pop_front()
+    }
+}
+
+impl Drop for Rx {
+    fn drop(&mut self) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.closed = true;
+        state.items.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(tx: &Tx, count: u8) {
+        for i in 0..count {
+            let _ = tx.push(Bytes::from(vec![i]));
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let (tx, mut rx) = channel(2, Policy::DropOldest);
+        fill(&tx, 4);
+
+        assert_eq!(tx.stats().dropped, 2);
+        assert_eq!(rx.recv().await, Some(Bytes::from_static(&[2])));
+        assert_eq!(rx.recv().await, Some(Bytes::from_static(&[3])));
+    }
+
+    #[tokio::test]
+    async fn drop_newest() {
+        let (tx, mut rx) = channel(2, Policy::DropNewest);
+        fill(&tx, 4);
+
+        assert_eq!(tx.stats().dropped, 2);
+        assert_eq!(rx.recv().await, Some(Bytes::from_static(&[0])));
+        assert_eq!(rx.try_recv(), Some(Bytes::from_static(&[1])));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[tokio::test]
+    async fn disconnect() {
+        let (tx, mut rx) = channel(2, Policy::Disconnect);
+        assert!(tx.push(Bytes::from_static(&[0])).is_ok());
+        assert!(tx.push(Bytes::from_static(&[1])).is_ok());
+        assert_eq!(tx.push(Bytes::from_static(&[2])), Err(Closed));
+
+        assert_eq!(rx.recv().await, None);
+        assert_eq!(tx.push(Bytes::from_static(&[3])), Err(Closed));
+    }
+
+    #[tokio::test]
+    async fn set_limits() {
+        let (tx, mut rx) = channel(4, Policy::DropNewest);
+        fill(&tx, 4);
+
+        tx.set_limits(2, Policy::DropOldest);
+        assert_eq!(tx.stats().capacity, 2);
+        tx.push(Bytes::from_static(&[4])).unwrap();
+        assert_eq!(rx.recv().await, Some(Bytes::from_static(&[3])));
+        assert_eq!(rx.try_recv(), Some(Bytes::from_static(&[4])));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn policy_from_str() {
+        assert_eq!("drop-oldest".parse(), Ok(Policy::DropOldest));
+        assert_eq!("drop-newest".parse(), Ok(Policy::DropNewest));
+        assert_eq!("disconnect".parse(), Ok(Policy::Disconnect));
+        assert!("drop-everything".parse::<Policy>().is_err());
+    }
+}
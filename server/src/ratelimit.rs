@@ -0,0 +1,263 @@
+//! Token buckets that keep a single connection or account from flooding a
+//! room.
+//!
+//! Buckets only remember how many tokens they hold. The `Rate` is passed in
+//! on every check, so a reloaded configuration applies to connected peers
+//! straight away.
+
+use std::time::Instant;
+
+use serde::Deserialize;
+
+/// How fast a bucket refills and how many tokens it holds when full.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl Rate {
+    pub const UNLIMITED: Rate = Rate {
+        per_second: f64::INFINITY,
+        burst: f64::INFINITY,
+    };
+
+    fn is_unlimited(&self) -> bool {
+        self.burst.is_infinite()
+    }
+}
+
+/// Messages and bytes a sender may relay.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    pub messages: Rate,
+    pub bytes: Rate,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            messages: Rate {
+                per_second: 10.0,
+                burst: 50.0,
+            },
+            bytes: Rate {
+                per_second: 256.0 * 1024.0,
+                burst: 1024.0 * 1024.0,
+            },
+        }
+    }
+}
+
+impl RateLimit {
+    pub const UNLIMITED: RateLimit = RateLimit {
+        messages: Rate::UNLIMITED,
+        bytes: Rate::UNLIMITED,
+    };
+}
+
+/// Every limit that applies in a room.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// What a single connection may send.
+    pub connection: RateLimit,
+    /// What all connections of one account may send together.
+    pub account: RateLimit,
+    /// How many throttled frames are forgiven. A connection that runs out
+    /// is disconnected.
+    pub abuse: Rate,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            connection: RateLimit::default(),
+            account: RateLimit {
+                messages: Rate {
+                    per_second: 20.0,
+                    burst: 100.0,
+                },
+                bytes: Rate {
+                    per_second: 512.0 * 1024.0,
+                    burst: 2.0 * 1024.0 * 1024.0,
+                },
+            },
+            abuse: Rate {
+                per_second: 1.0,
+                burst: 20.0,
+            },
+        }
+    }
+}
+
+impl RateLimits {
+    pub const UNLIMITED: RateLimits = RateLimits {
+        connection: RateLimit::UNLIMITED,
+        account: RateLimit::UNLIMITED,
+        abuse: Rate::UNLIMITED,
+    };
+}
+
+/// A token bucket, created full.
+#[derive(Debug)]
+pub struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    pub fn new(now: Instant) -> Bucket {
+        Bucket {
+            tokens: f64::INFINITY,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, rate: &Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(rate.burst);
+        self.last = now;
+    }
+
+    fn has(&self, rate: &Rate, n: f64) -> bool {
+        rate.is_unlimited() || self.tokens >= n
+    }
+
+    fn take(&mut self, rate: &Rate, n: f64) {
+        if !rate.is_unlimited() {
+            self.tokens -= n;
+        }
+    }
+
+    /// Take `n` tokens if there are enough.
+    pub fn try_take(&mut self, rate: &Rate, n: f64, now: Instant) -> bool {
+        self.refill(rate, now);
+        let ok = self.has(rate, n);
+        if ok {
+            self.take(rate, n);
+        }
+        ok
+    }
+}
+
+/// The message and byte buckets of one sender.
+#[derive(Debug)]
+pub struct Limiter {
+    messages: Bucket,
+    bytes: Bucket,
+}
+
+impl Limiter {
+    pub fn new(now: Instant) -> Limiter {
+        Limiter {
+            messages: Bucket::new(now),
+            bytes: Bucket::new(now),
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        self.messages.refill(&limit.messages, now);
+        self.bytes.refill(&limit.bytes, now);
+    }
+
+    fn has(&self, limit: &RateLimit, len: usize) -> bool {
+        self.messages.has(&limit.messages, 1.0) && self.bytes.has(&limit.bytes, len as f64)
+    }
+
+    fn take(&mut self, limit: &RateLimit, len: usize) {
+        self.messages.take(&limit.messages, 1.0);
+        self.bytes.take(&limit.bytes, len as f64);
+    }
+}
+
+/// Let a message of `len` bytes through if both the connection and the
+/// account have room for it. Nothing is taken from either when it is
+/// throttled.
+pub fn admit(
+    limits: &RateLimits,
+    connection: &mut Limiter,
+    account: &mut Limiter,
+    len: usize,
+    now: Instant,
+) -> bool {
+    connection.refill(&limits.connection, now);
+    account.refill(&limits.account, now);
+    let ok = connection.has(&limits.connection, len) && account.has(&limits.account, len);
+    if ok {
+        connection.take(&limits.connection, len);
+        account.take(&limits.account, len);
+    }
+    ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_refills() {
+        let rate = Rate {
+            per_second: 2.0,
+            burst: 4.0,
+        };
+        let start = Instant::now();
+        let mut bucket = Bucket::new(start);
+
+        for _ in 0..4 {
+            assert!(bucket.try_take(&rate, 1.0, start));
+        }
+        assert!(!bucket.try_take(&rate, 1.0, start));
+        assert!(bucket.try_take(&rate, 1.0, start + Duration::from_millis(500)));
+        // Never more than a burst, however long it was idle.
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.try_take(&rate, 4.0, later));
+        assert!(!bucket.try_take(&rate, 1.0, later));
+    }
+
+    #[test]
+    fn account_is_shared() {
+        let mut limits = RateLimits::UNLIMITED;
+        limits.account.messages = Rate {
+            per_second: 1.0,
+            burst: 3.0,
+        };
+        limits.connection.bytes = Rate {
+            per_second: 1.0,
+            burst: 10.0,
+        };
+        let now = Instant::now();
+        let mut account = Limiter::new(now);
+        let mut first = Limiter::new(now);
+        let mut second = Limiter::new(now);
+
+        assert!(admit(&limits, &mut first, &mut account, 10, now));
+        // Too large for the connection; the account is not charged.
+        assert!(!admit(&limits, &mut first, &mut account, 1, now));
+        assert!(admit(&limits, &mut second, &mut account, 1, now));
+        assert!(admit(&limits, &mut second, &mut account, 1, now));
+        assert!(!admit(&limits, &mut second, &mut account, 1, now));
+
+        assert!(admit(
+            &RateLimits::UNLIMITED,
+            &mut first,
+            &mut account,
+            usize::MAX,
+            now
+        ));
+
+        // The largest frame a client may send passes the default limits.
+        let (mut connection, mut account) = (Limiter::new(now), Limiter::new(now));
+        let len = crate::frame::MAX_FRAME_LEN;
+        assert!(admit(
+            &RateLimits::default(),
+            &mut connection,
+            &mut account,
+            len,
+            now
+        ));
+    }
+}
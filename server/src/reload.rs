@@ -0,0 +1,188 @@
+//! Applying a changed configuration without a restart.
+//!
+//! The log filter and the settings in `handle_connection::Settings`, such as
+//! rate limits and room settings, are swapped in place, so connected peers
+//! stay connected. Bans, mutes and operators are read again from
+//! `moderation.toml`, and peers it now bans are disconnected. Listeners, TLS
+//! and storage are only read at startup; a change to them is logged on
+//! every reload until the next restart, and until then the running
+//! configuration keeps the settings in effect.
+
+use std::sync::{Arc, Mutex};
+
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::config::{Args, Config, ConfigError};
+use crate::handle_connection::Shared;
+
+/// Swaps the filter of the installed log subscriber.
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Reads the configuration again from the same layers it was loaded from.
+pub struct Reloader {
+    args: Args,
+    state: Arc<Shared>,
+    log: LogHandle,
+    current: Mutex<Config>,
+}
+
+impl Reloader {
+    /// Apply `config`, as loaded from `args`, and remember both for later
+    /// reloads.
+    pub fn new(args: Args, config: Config, state: Arc<Shared>, log: LogHandle) -> Reloader {
+        let reloader = Reloader {
+            args,
+            state,
+            log,
+            current: Mutex::new(Config::default()),
+        };
+        reloader.apply(config);
+        reloader
+    }
+
+    /// Load the configuration again and apply it. On error the running
+    /// configuration is left as it is. Returns the changed settings that
+    /// wait for a restart.
+    pub fn reload(&self) -> Result<Vec<&'static str>, ConfigError> {
+        let mut config = Config::load(&self.args, |var| std::env::var(var).ok())?;
+
+        let changed = {
+            let mut current = self.current.lock().unwrap();
+            let changed = current.restart_required(&config);
+            config.keep_restart_only(&mut current);
+            changed
+        };
+        if !changed.is_empty() {
+            warn!(
+                "Changes to {} take effect after a restart",
+                changed.join(", ")
+            );
+        }
+        self.apply(config);
+        Ok(changed)
+    }
+
+    fn apply(&self, config: Config) {
+        // Loading validated the directives already.
+        match config.log.env_filter() {
+            Ok(filter) => {
+                if let Err(e) = self.log.reload(filter) {
+                    error!("Failed to change the log filter: {}", e);
+                }
+            }
+            Err(e) => error!("Failed to change the log filter: {}", e),
+        }
+        self.state.reconfigure(config.settings());
+        if let Err(e) = self.state.reload_moderation() {
+            error!("Failed to reload the moderation state: {}", e);
+        }
+        *self.current.lock().unwrap() = config;
+    }
+
+    /// Run `f` with the configuration currently in effect.
+    pub fn with_config<R>(&self, f: impl FnOnce(&Config) -> R) -> R {
+        f(&self.current.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::Policy;
+    use std::fs;
+    use tokio::io::AsyncWriteExt;
+    use tracing::level_filters::LevelFilter;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[tokio::test]
+    async fn applies_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        fs::write(&path, "[limits]\nqueue_capacity = 8\n").unwrap();
+        let args = Args {
+            config: Some(path.clone()),
+            ..Args::default()
+        };
+
+        let (filter, log) = reload::Layer::new(EnvFilter::new("info"));
+        let _subscriber = Registry::default().with(filter);
+        let moderation = crate::moderation::Moderation::load(dir.path()).unwrap();
+        let state = Arc::new(Shared::new(1, Policy::DropOldest).with_moderation(moderation));
+        let config = Config::load(&args, |_| None).unwrap();
+        let reloader = Reloader::new(args, config, state.clone(), log.clone());
+
+        // A connected peer, so its queue has to be resized in place.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let (stream, addr) = listener.accept().await.unwrap();
+                let _ = crate::handle_connection::process(state, stream, addr.into()).await;
+            });
+        }
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(&crate::frame::encode_hello("client", "lobby", &[]))
+            .await
+            .unwrap();
+        while state.peer_count() < 1 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(state.queue_stats()[0].1.capacity, 8);
+
+        fs::write(
+            &path,
+            "motd = \"welcome\"\n[limits]\nqueue_capacity = 32\n[log]\nlevel = \"warn\"\nfilter = \"server::admin=trace\"\n",
+        )
+        .unwrap();
+        assert!(reloader.reload().unwrap().is_empty());
+        assert_eq!(state.queue_stats()[0].1.capacity, 32);
+        assert_eq!(
+            log.with_current(|filter| filter.max_level_hint()).unwrap(),
+            Some(LevelFilter::TRACE)
+        );
+        assert!(log
+            .with_current(|filter| filter.to_string().contains("server::admin=trace"))
+            .unwrap());
+        reloader.with_config(|config| assert_eq!(config.motd.as_deref(), Some("welcome")));
+
+        // A broken file keeps the running configuration.
+        fs::write(&path, "[limits]\nqueue_capacity = \"many\"\n").unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(state.queue_stats()[0].1.capacity, 32);
+
+        // Moving a listener waits for a restart, and is reported again on
+        // every reload until then, while the rest still applies.
+        fs::write(
+            &path,
+            "motd = \"moved\"\n[listen]\naddress = \"127.0.0.1:7000\"\n[limits]\nqueue_capacity = 16\n",
+        )
+        .unwrap();
+        for _ in 0..2 {
+            assert_eq!(reloader.reload().unwrap(), ["listen"]);
+            assert_eq!(state.queue_stats()[0].1.capacity, 16);
+            reloader.with_config(|config| {
+                assert_eq!(config.listen.address.port(), 6142);
+                assert_eq!(config.motd.as_deref(), Some("moved"));
+            });
+        }
+
+        // Ban lists edited on disk apply too, to peers already connected.
+        fs::write(
+            dir.path().join(crate::moderation::FILE),
+            "[lobby]\nbanned = [\"client\"]\n",
+        )
+        .unwrap();
+        reloader.reload().unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while state.peer_count() > 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    borrow::Borrow,
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hash},
+    sync::RwLock,
+};
+
+/// Number of shards a `ShardedMap` is split into.
+const SHARDS: usize = 64;
+
+/// A hash map split into independently locked shards.
+///
+/// Writers only lock the shard their key hashes to, and readers that walk the
+/// whole map hold one shard's read lock at a time, so concurrent connections
+/// rarely wait on each other. The locks are synchronous and never held across
+/// an `.await`.
+pub struct ShardedMap<K, V> {
+    shards: Box<[RwLock<HashMap<K, V>>]>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V> ShardedMap<K, V> {
+    pub fn new() -> Self {
+        ShardedMap {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard<Q>(&self, key: &Q) -> &RwLock<HashMap<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).write().unwrap().insert(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).write().unwrap().remove(key)
+    }
+
+    /// Run `f` on the value stored for `key`, if any.
+    pub fn with<Q, R>(&self, key: &Q, f: impl FnOnce(&V) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).read().unwrap().get(key).map(f)
+    }
+
+    /// Run `f` on the value stored for `key`, inserting the default first
+    /// if there is none.
+    pub fn with_default<R>(&self, key: K, f: impl FnOnce(&mut V) -> R) -> R
+    where
+        V: Default,
+    {
+        let mut shard = self.shard(&key).write().unwrap();
+        f(shard.entry(key).or_default())
+    }
+
+    /// Run `f` on the value stored for `key`, if any, and remove it if `f`
+    /// returns true.
+    pub fn remove_if(&self, key: &K, f: impl FnOnce(&mut V) -> bool) {
+        let mut shard = self.shard(key).write().unwrap();
+        if shard.get_mut(key).is_some_and(f) {
+            shard.remove(key);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.shard(key).read().unwrap().contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    /// Call `f` for every entry, one shard at a time.
+    pub fn for_each(&self, mut f: impl FnMut(&K, &V)) {
+        for shard in self.shards.iter() {
+            for (key, value) in shard.read().unwrap().iter() {
+                f(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_remove() {
+        let map = ShardedMap::new();
+        for i in 0..1000 {
+            map.insert(i, i * 2);
+        }
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.with(&21, |v| *v), Some(42));
+
+        let mut sum = 0;
+        map.for_each(|_, v| sum += v);
+        assert_eq!(sum, (0..1000).map(|i| i * 2).sum::<i32>());
+
+        assert_eq!(map.remove(&21), Some(42));
+        assert!(!map.contains_key(&21));
+        assert_eq!(map.len(), 999);
+
+        map.with_default(21, |v| *v += 1);
+        assert_eq!(map.with(&21, |v| *v), Some(1));
+        map.remove_if(&21, |v| *v > 1);
+        assert!(map.contains_key(&21));
+        map.remove_if(&21, |v| *v == 1);
+        assert!(!map.contains_key(&21));
+    }
+}
@@ -0,0 +1,129 @@
+//! Optional TLS for client connections.
+//!
+//! Payloads are already end-to-end encrypted, but without TLS anyone on the
+//! network can still see who is talking to whom and when.
+
+use std::{io, path::Path, sync::Arc};
+
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+/// Build an acceptor from a PEM encoded certificate chain and private key.
+pub fn acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let invalid = |path: &Path, e: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    };
+
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(cert, &e))?;
+    let key_der = PrivateKeyDer::from_pem_file(key).map_err(|e| invalid(key, &e))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key_der)
+        .map_err(|e| invalid(cert, &e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{self, Kind};
+    use crate::handle_connection::{process, Shared};
+    use crate::queue::Policy;
+    use futures::SinkExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::{
+        client::TlsStream,
+        rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+        TlsConnector,
+    };
+    use tokio_stream::StreamExt;
+    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+    /// Write a fresh self-signed certificate for `localhost` and its key to
+    /// `dir`, returning the certificate so clients can trust it.
+    fn self_signed(dir: &Path) -> CertificateDer<'static> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.signing_key.serialize_pem()).unwrap();
+        cert.cert.der().clone()
+    }
+
+    async fn connect(
+        server: std::net::SocketAddr,
+        connector: &TlsConnector,
+    ) -> Framed<TlsStream<TcpStream>, LengthDelimitedCodec> {
+        let stream = TcpStream::connect(server).await.unwrap();
+        let stream = connector
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        Framed::new(stream, LengthDelimitedCodec::new())
+    }
+
+    #[tokio::test]
+    async fn relays_over_tls() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = self_signed(dir.path());
+        let acceptor = acceptor(&dir.path().join("cert.pem"), &dir.path().join("key.pem")).unwrap();
+
+        let state = Arc::new(Shared::new(16, Policy::DropOldest));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        {
+            let state = state.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, addr) = listener.accept().await.unwrap();
+                    let stream = acceptor.accept(stream).await.unwrap();
+                    let state = Arc::clone(&state);
+                    tokio::spawn(async move {
+                        let _ = process(state, stream, addr.into()).await;
+                    });
+                }
+            });
+        }
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let mut alice = connect(server, &connector).await;
+        let mut bob = connect(server, &connector).await;
+        for (client, name) in [(&mut alice, "alice"), (&mut bob, "bob")] {
+            let hello = frame::encode_hello(name, "lobby", &[]);
+            client.send(hello.slice(frame::HEADER_LEN..)).await.unwrap();
+        }
+        while state.peer_count() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        let data = frame::encode(Kind::Data, b"hello");
+        alice.send(data.slice(frame::HEADER_LEN..)).await.unwrap();
+        let received = bob.next().await.unwrap().unwrap();
+        assert_eq!(&received[..], &data[frame::HEADER_LEN..]);
+    }
+
+    #[test]
+    fn missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = acceptor(&dir.path().join("cert.pem"), &dir.path().join("key.pem"))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("cert.pem"));
+    }
+}
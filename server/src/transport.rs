@@ -0,0 +1,97 @@
+//! The ways a client can be connected to the server.
+//!
+//! Every transport reads and writes complete `frame`s, length header
+//! included, so `handle_connection` does not care whether a peer is on a raw
+//! socket or behind a WebSocket.
+
+use std::io;
+
+use bytes::{Bytes, BytesMut};
+use futures::SinkExt;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_stream::StreamExt;
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
+
+use crate::frame;
+
+/// A connection that frames can be read from and written to.
+pub(crate) trait Transport {
+    /// Read the next frame. Returns `None` once the client has gone away.
+    ///
+    /// This must be cancel safe, it is raced against the peer's queue.
+    async fn read(&mut self) -> Option<io::Result<Bytes>>;
+
+    /// Write a complete frame.
+    async fn write(&mut self, frame: Bytes) -> io::Result<()>;
+
+    /// Close the connection after everything written has been flushed.
+    async fn close(&mut self) -> io::Result<()>;
+}
+
+/// A byte stream (TCP, TLS, ...) split into frames by their length header.
+///
+/// Frames are written straight to the underlying stream, so a shared buffer
+/// is never copied, and flushed so none linger in a TLS session's buffer.
+impl<S> Transport for FramedRead<S, LengthDelimitedCodec>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn read(&mut self) -> Option<io::Result<Bytes>> {
+        self.next().await.map(|frame| frame.map(BytesMut::freeze))
+    }
+
+    async fn write(&mut self, mut frame: Bytes) -> io::Result<()> {
+        let stream = self.get_mut();
+        stream.write_all_buf(&mut frame).await?;
+        // TLS streams hold on to ciphertext until flushed.
+        stream.flush().await
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        self.get_mut().shutdown().await
+    }
+}
+
+/// A WebSocket carrying one frame per binary message.
+///
+/// WebSocket messages are already delimited, so frames travel without their
+/// length header. It is added back when a frame is read so it can be relayed
+/// to peers on other transports.
+impl<S> Transport for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn read(&mut self) -> Option<io::Result<Bytes>> {
+        loop {
+            match self.next().await? {
+                Ok(Message::Binary(frame)) => {
+                    if frame::HEADER_LEN + frame.len() > frame::MAX_FRAME_LEN {
+                        let e = io::Error::new(io::ErrorKind::InvalidData, "frame size too big");
+                        return Some(Err(e));
+                    }
+                    let mut framed = BytesMut::with_capacity(frame::HEADER_LEN + frame.len());
+                    framed.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+                    framed.extend_from_slice(&frame);
+                    return Some(Ok(framed.freeze()));
+                }
+                Ok(Message::Close(_)) => return None,
+                // Pings are answered by tungstenite itself.
+                Ok(_) => continue,
+                Err(e) => return Some(Err(io::Error::other(e))),
+            }
+        }
+    }
+
+    async fn write(&mut self, frame: Bytes) -> io::Result<()> {
+        self.send(Message::Binary(frame.slice(frame::HEADER_LEN..)))
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        WebSocketStream::close(self, None)
+            .await
+            .map_err(io::Error::other)
+    }
+}
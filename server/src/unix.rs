@@ -0,0 +1,163 @@
+//! A Unix domain socket listener for clients on the same host, such as bots
+//! and tooling.
+//!
+//! Access is controlled by the permissions of the socket file: only users
+//! who may write to it can connect.
+
+use std::{
+    fs, io,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::handle_connection::PeerAddr;
+
+/// Bind a listener at `path` and restrict the socket file to `mode`.
+///
+/// A socket file left behind by a previous run is removed first. Anything
+/// else at `path` is left alone and binding fails.
+pub fn bind(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if is_socket(&metadata) => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(_) => {}
+    }
+
+    // Bound under the process umask, the socket would be open to anyone
+    // until it is restricted. It is made in a directory only we can enter
+    // instead, and moved in place once it has its mode.
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?;
+    let mut private = name.to_owned();
+    private.push(format!(".{}.tmp", std::process::id()));
+    let private = path.with_file_name(private);
+    if private.exists() {
+        fs::remove_dir_all(&private)?;
+    }
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let result = bind_private(&private.join(name), path, mode);
+    let _ = fs::remove_dir_all(&private);
+    result
+}
+
+/// Bind a listener at `temp`, give it `mode` and move it to `path`.
+fn bind_private(temp: &Path, path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let listener = UnixListener::bind(temp)?;
+    fs::set_permissions(temp, fs::Permissions::from_mode(mode))?;
+    fs::rename(temp, path)?;
+    Ok(listener)
+}
+
+fn is_socket(metadata: &fs::Metadata) -> bool {
+    metadata.file_type().is_socket()
+}
+
+/// Give an accepted connection an address that is unique for the lifetime
+/// of the server.
+pub fn peer_addr(stream: &UnixStream) -> PeerAddr {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+    PeerAddr::Unix {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        uid: stream
+            .peer_cred()
+            .map(|cred| cred.uid())
+            .unwrap_or(u32::MAX),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{self, Kind};
+    use crate::handle_connection::{process, Shared};
+    use crate::queue::Policy;
+    use std::sync::Arc;
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_stream::StreamExt;
+    use tokio_util::codec::FramedRead;
+
+    #[tokio::test]
+    async fn socket_file_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.sock");
+
+        let listener = bind(&path, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Nothing is left of the directory it was bound in, and it still
+        // accepts connections after the move.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        UnixStream::connect(&path).await.unwrap();
+        drop(listener);
+
+        // A stale socket is replaced, a regular file is not.
+        bind(&path, 0o660).unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, b"").unwrap();
+        assert!(bind(&file, 0o660).is_err());
+    }
+
+    /// A TCP client and a Unix socket client are in the same room.
+    #[tokio::test]
+    async fn tcp_and_unix_clients_meet() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.sock");
+        let state = Arc::new(Shared::new(16, Policy::DropOldest));
+
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let unix = bind(&path, 0o600).unwrap();
+        {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let (stream, addr) = tcp.accept().await.unwrap();
+                let _ = process(state, stream, addr.into()).await;
+            });
+        }
+        {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let (stream, _) = unix.accept().await.unwrap();
+                let addr = peer_addr(&stream);
+                let _ = process(state, stream, addr).await;
+            });
+        }
+
+        let mut terminal =
+            FramedRead::new(TcpStream::connect(tcp_addr).await.unwrap(), frame::codec());
+        let mut bot = FramedRead::new(UnixStream::connect(&path).await.unwrap(), frame::codec());
+        terminal
+            .get_mut()
+            .write_all(&frame::encode_hello("terminal", "lobby", &[]))
+            .await
+            .unwrap();
+        bot.get_mut()
+            .write_all(&frame::encode_hello("bot", "lobby", &[]))
+            .await
+            .unwrap();
+        while state.peer_count() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        let data = frame::encode(Kind::Data, b"beep");
+        bot.get_mut().write_all(&data).await.unwrap();
+        assert_eq!(terminal.next().await.unwrap().unwrap(), data);
+
+        let data = frame::encode(Kind::Data, b"hello bot");
+        terminal.get_mut().write_all(&data).await.unwrap();
+        assert_eq!(bot.next().await.unwrap().unwrap(), data);
+    }
+}